@@ -1,11 +1,10 @@
+use squirrel_rs::compiler::compile_str;
 use squirrel_rs::io::read_closure;
 
 use squirrel_rs::object;
 use squirrel_rs::vm::Executor;
-use squirrel_rs::Object;
 use std::env;
 use std::fs::File;
-use std::io::prelude::*;
 use std::rc::Rc;
 
 fn main() {
@@ -18,9 +17,14 @@ fn main() {
             std::mem::size_of::<squirrel_rs::Object>(),
             std::mem::size_of::<Box<str>>(),
         );
-        let mut file = File::open(filename).unwrap();
-        //        let mut bc = &include_bytes!("out.cnut")[..];
-        let closure = read_closure(&mut file).unwrap();
+        let closure = if filename.ends_with(".nut") {
+            let source = std::fs::read_to_string(&filename).unwrap();
+            compile_str(&source, &filename).unwrap()
+        } else {
+            let mut file = File::open(filename).unwrap();
+            //        let mut bc = &include_bytes!("out.cnut")[..];
+            read_closure(&mut file).unwrap()
+        };
 
         closure
             .closure()
@@ -36,12 +40,11 @@ fn main() {
         exec.add_native_func(
             "print",
             squirrel_rs::native_closure(
-                Box::new(|stack| {
-                    // println!("print '{}'\n", stack.up(0));
-                    print!("{}", stack.up(0));
-                    // stack.print_compact("print");
+                Box::new(|ctx| {
+                    print!("{}", ctx.arg(0)?);
+                    Ok(squirrel_rs::Object::Null)
                 }),
-                1,
+                2,
             ),
         )
         .unwrap();
@@ -51,19 +54,14 @@ fn main() {
             exec.trace_call_return = false;
         }
 
-        exec.stack().push(closure);
-        exec.push_roottable();
-        let mut num_args = 1;
+        exec.stack().push(closure).unwrap();
+        exec.push_roottable().unwrap();
+        let num_args = 1;
 
         exec.stack().print_compact("initial");
 
         exec.call(num_args, false).unwrap();
-        let ret = exec.execute();
-        if ret.is_err() {
-            exec.print_state();
-        }
-
-        let retval = ret.unwrap();
+        let retval = exec.execute().unwrap();
         //let ret = exec.stack.pop();
         println!("{:?}", retval);
         //assert_eq!(retval.integer().unwrap(), 111)
@@ -0,0 +1,4 @@
+local a = 0;
+local b = 5;
+local c = 7;
+return "" + (a && b) + (b && c);
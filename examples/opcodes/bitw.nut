@@ -0,0 +1,3 @@
+local a = 0x1ff;
+local b = -16;
+return (a & 0xff) + " " + (a | 0x200) + " " + (a ^ 1) + " " + (a << 1) + " " + (b >> 2) + " " + ((b >>> 1) > 0);
@@ -0,0 +1,2 @@
+local a = 0;
+return ~a;
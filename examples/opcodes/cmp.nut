@@ -0,0 +1,4 @@
+local a = 1;
+local b = 2;
+local r = a < b;
+return r;
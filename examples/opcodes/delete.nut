@@ -0,0 +1,3 @@
+local t = {a = 1, b = 2};
+local v = delete t.a;
+return v * 10 + t.len();
@@ -0,0 +1,4 @@
+local t = {k = 1};
+local k = "k";
+local x = "x";
+return "" + (k in t) + (x in t);
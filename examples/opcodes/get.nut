@@ -0,0 +1,3 @@
+local t = {k = 3};
+local k = "k";
+return t[k];
@@ -0,0 +1,2 @@
+local a = 5;
+return -a;
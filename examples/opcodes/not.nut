@@ -0,0 +1,3 @@
+local a = null;
+local b = 1;
+return "" + !a + !b;
@@ -0,0 +1,3 @@
+local a = 0;
+local b = 5;
+return "" + (a || b) + (b || a);
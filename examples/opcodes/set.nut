@@ -0,0 +1,3 @@
+local t = {k = 1};
+t.k = 2;
+return t.k;
@@ -0,0 +1,269 @@
+use crate::convert::IntoObject;
+use crate::vm::{DefaultDelegates, Executor, Thread};
+use crate::{gc, object, Error, Object, Result};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+type BuiltinFn = fn(&mut Executor, Vec<Object>) -> Result<Object>;
+
+fn builtin(func: BuiltinFn, nargs: crate::types::Integer) -> Object {
+    Object::NativeClosure(Rc::new(object::NativeClosure::builtin(func, nargs)))
+}
+
+fn delegate(funcs: &[(&str, BuiltinFn, crate::types::Integer)]) -> Object {
+    let mut table = object::Table::new();
+    for (name, func, nargs) in funcs {
+        table
+            .map
+            .insert(Object::new_string(name), builtin(*func, *nargs));
+    }
+    gc::track(Object::Table(Rc::new(RefCell::new(table))))
+}
+
+pub(crate) fn default_delegates() -> DefaultDelegates {
+    DefaultDelegates {
+        table: delegate(&[
+            ("setdelegate", table_setdelegate, 2),
+            ("getdelegate", table_getdelegate, 1),
+            ("rawget", table_rawget, 2),
+            ("rawset", table_rawset, 3),
+            ("rawin", table_rawin, 2),
+            ("rawdelete", table_rawdelete, 2),
+            ("len", table_len, 1),
+            ("weakref", weakref, 1),
+        ]),
+        array: delegate(&[
+            ("len", array_len, 1),
+            ("append", array_append, 2),
+            ("push", array_append, 2),
+            ("pop", array_pop, 1),
+            ("top", array_top, 1),
+            ("weakref", weakref, 1),
+        ]),
+        closure: delegate(&[("weakref", weakref, 1)]),
+        class: delegate(&[("weakref", weakref, 1)]),
+        instance: delegate(&[("weakref", weakref, 1)]),
+        generator: delegate(&[("weakref", weakref, 1)]),
+        thread: delegate(&[
+            ("call", thread_call, -1),
+            ("wakeup", thread_wakeup, -1),
+            ("getstatus", thread_getstatus, 1),
+            ("weakref", weakref, 1),
+        ]),
+        weakref: delegate(&[("ref", weakref_ref, 1), ("weakref", weakref, 1)]),
+    }
+}
+
+// registers the base library functions in the root table
+pub(crate) fn register(exec: &mut Executor) {
+    exec.add_native_func("newthread", builtin(newthread, 2))
+        .expect("root table is a table");
+    exec.add_native_func("suspend", builtin(suspend, -1))
+        .expect("root table is a table");
+    exec.add_native_func("collectgarbage", builtin(collectgarbage, 1))
+        .expect("root table is a table");
+    exec.add_native_func("resurrectunreachable", builtin(resurrectunreachable, 1))
+        .expect("root table is a table");
+}
+
+fn thread(obj: &Object) -> Result<Rc<RefCell<Thread>>> {
+    match obj {
+        Object::Thread(thread) => Ok(thread.clone()),
+        _ => Err(Error::RuntimeError(format!(
+            "expected thread. found {}",
+            obj.type_name()
+        ))),
+    }
+}
+
+fn table(obj: &Object) -> Result<Rc<RefCell<object::Table>>> {
+    match obj {
+        Object::Table(table) => Ok(table.clone()),
+        _ => Err(Error::RuntimeError(format!(
+            "expected table. found {}",
+            obj.type_name()
+        ))),
+    }
+}
+
+fn table_setdelegate(_exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    let this = table(&args[0])?;
+    let delegate = match &args[1] {
+        Object::Null => None,
+        Object::Table(delegate) => {
+            // walk the new delegate chain to make sure it does not lead back to this table
+            let mut next = Some(delegate.clone());
+            while let Some(table) = next {
+                if Rc::ptr_eq(&table, &this) {
+                    return Err(Error::RuntimeError("delegate cycle".to_string()));
+                }
+                next = match &table.borrow().delegate {
+                    Some(delegate) => Some(self::table(delegate)?),
+                    None => None,
+                };
+            }
+            Some(args[1].clone())
+        }
+        other => {
+            return Err(Error::RuntimeError(format!(
+                "the delegate must be a table or null. found {}",
+                other.type_name()
+            )))
+        }
+    };
+    this.borrow_mut().delegate = delegate;
+    Ok(args[0].clone())
+}
+
+fn table_getdelegate(_exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    Ok(table(&args[0])?
+        .borrow()
+        .delegate
+        .clone()
+        .unwrap_or(Object::Null))
+}
+
+fn table_rawget(_exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    table(&args[0])?
+        .borrow()
+        .map
+        .get(&args[1])
+        .cloned()
+        .ok_or_else(|| Error::RuntimeError(format!("the index '{}' does not exist", args[1])))
+}
+
+fn table_rawset(exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    exec.table_insert(&table(&args[0])?, args[1].clone(), args[2].clone())?;
+    Ok(args[0].clone())
+}
+
+fn table_rawin(_exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    Ok(Object::Bool(
+        table(&args[0])?.borrow().map.contains_key(&args[1]),
+    ))
+}
+
+fn table_rawdelete(exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    Ok(exec
+        .table_remove(&table(&args[0])?, &args[1])
+        .unwrap_or(Object::Null))
+}
+
+fn table_len(_exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    Ok(Object::Integer(
+        table(&args[0])?.borrow().map.len() as crate::types::Integer
+    ))
+}
+
+fn array(obj: &Object) -> Result<Rc<RefCell<object::Array>>> {
+    match obj {
+        Object::Array(array) => Ok(array.clone()),
+        _ => Err(Error::RuntimeError(format!(
+            "expected array. found {}",
+            obj.type_name()
+        ))),
+    }
+}
+
+fn array_len(_exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    Ok(Object::Integer(
+        array(&args[0])?.borrow().array.len() as crate::types::Integer
+    ))
+}
+
+fn array_append(exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    exec.array_push(&array(&args[0])?, args[1].clone())?;
+    Ok(args[0].clone())
+}
+
+fn array_pop(exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    exec.array_pop(&array(&args[0])?)
+        .ok_or_else(|| Error::RuntimeError("pop on a empty array".to_string()))
+}
+
+fn array_top(_exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    array(&args[0])?
+        .borrow()
+        .array
+        .last()
+        .cloned()
+        .ok_or_else(|| Error::RuntimeError("top() on a empty array".to_string()))
+}
+
+// values that are not reference counted are returned as they are
+fn weakref(_exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    Ok(match object::WeakRef::new(&args[0]) {
+        Some(weakref) => Object::WeakRef(Rc::new(weakref)),
+        None => args[0].clone(),
+    })
+}
+
+fn weakref_ref(_exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    match &args[0] {
+        Object::WeakRef(weakref) => Ok(weakref.get()),
+        other => Err(Error::RuntimeError(format!(
+            "expected weakref. found {}",
+            other.type_name()
+        ))),
+    }
+}
+
+fn newthread(exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    match &args[1] {
+        Object::Closure(_) => Ok(Object::Thread(Rc::new(RefCell::new(Thread::new(
+            args[1].clone(),
+            exec.stack().limit(),
+        ))))),
+        other => Err(Error::RuntimeError(format!(
+            "newthread requires a closure. found {}",
+            other.type_name()
+        ))),
+    }
+}
+
+fn suspend(exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    exec.stack()
+        .suspend(args.get(1).cloned().unwrap_or(Object::Null));
+    Ok(Object::Null)
+}
+
+fn collectgarbage(exec: &mut Executor, _args: Vec<Object>) -> Result<Object> {
+    Ok(Object::Integer(
+        exec.collect_garbage() as crate::types::Integer
+    ))
+}
+
+// returns the unreachable objects in an array, or null if there are none
+fn resurrectunreachable(exec: &mut Executor, _args: Vec<Object>) -> Result<Object> {
+    let unreachable = exec.resurrect_unreachable();
+    if unreachable.is_empty() {
+        return Ok(Object::Null);
+    }
+    Ok(unreachable.into_object())
+}
+
+fn thread_call(exec: &mut Executor, mut args: Vec<Object>) -> Result<Object> {
+    let thread = thread(&args[0])?;
+    if thread.borrow().status() != crate::vm::ThreadStatus::Idle {
+        return Err(Error::RuntimeError("wrong status".to_string()));
+    }
+    args.remove(0);
+    exec.run_thread(&thread, args)
+}
+
+fn thread_wakeup(exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    let thread = thread(&args[0])?;
+    let status = thread.borrow().status();
+    if status != crate::vm::ThreadStatus::Suspended {
+        return Err(Error::RuntimeError(format!(
+            "cannot wakeup a {} thread",
+            status.name()
+        )));
+    }
+    exec.run_thread(&thread, args.into_iter().skip(1).take(1).collect())
+}
+
+fn thread_getstatus(_exec: &mut Executor, args: Vec<Object>) -> Result<Object> {
+    let status = thread(&args[0])?.borrow().status();
+    Ok(Object::new_string(status.name()))
+}
@@ -0,0 +1,289 @@
+use crate::convert::{FromObject, IntoObject, ANY_TYPE};
+use crate::object::{self, NativeClosure, NativeFn};
+use crate::{gc, raw_type, types, Error, Object, Result};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// a rust type exposed to scripts as a class, registered with Executor::register_class.
+// Instances of the class hold the rust value created by the constructor as userdata.
+pub trait ClassBinding: Any + Sized {
+    // the name of the class in the root table
+    const NAME: &'static str;
+    fn bind(class: &mut ClassBuilder<Self>);
+}
+
+type Getter<T> = Box<dyn Fn(&T) -> Object>;
+type Setter<T> = Box<dyn Fn(&mut T, &Object) -> Result<()>>;
+
+pub struct ClassBuilder<T> {
+    methods: Vec<(String, Object)>,
+    getters: HashMap<String, Getter<T>>,
+    setters: HashMap<String, Setter<T>>,
+}
+
+impl<T: Any> ClassBuilder<T> {
+    pub fn constructor<Args>(&mut self, func: impl IntoConstructor<T, Args>) -> &mut Self {
+        self.methods
+            .push(("constructor".to_string(), func.into_constructor()));
+        self
+    }
+
+    pub fn method<Args>(&mut self, name: &str, func: impl IntoMethod<T, Args>) -> &mut Self {
+        self.methods.push((name.to_string(), func.into_method()));
+        self
+    }
+
+    // a property read and written through the _get and _set metamethods of the class
+    pub fn property<V: for<'a> FromObject<'a> + IntoObject>(
+        &mut self,
+        name: &str,
+        get: impl Fn(&T) -> V + 'static,
+        set: impl Fn(&mut T, V) + 'static,
+    ) -> &mut Self {
+        self.getter(name, get);
+        self.setters.insert(
+            name.to_string(),
+            Box::new(move |value, obj| {
+                set(value, V::from_object(obj)?);
+                Ok(())
+            }),
+        );
+        self
+    }
+
+    // a read only property
+    pub fn getter<V: IntoObject>(
+        &mut self,
+        name: &str,
+        get: impl Fn(&T) -> V + 'static,
+    ) -> &mut Self {
+        self.getters.insert(
+            name.to_string(),
+            Box::new(move |value| get(value).into_object()),
+        );
+        self
+    }
+}
+
+// builds the class of T
+pub(crate) fn class_object<T: ClassBinding>() -> Result<Object> {
+    let mut builder = ClassBuilder {
+        methods: Vec::new(),
+        getters: HashMap::new(),
+        setters: HashMap::new(),
+    };
+    T::bind(&mut builder);
+
+    let mut class = object::Class::new(None)?;
+    for (name, method) in builder.methods {
+        class.new_slot(Object::new_string(&name), method, false)?;
+    }
+    let getters = builder.getters;
+    if !getters.is_empty() {
+        let get: NativeFn = Box::new(move |ctx| match property(&getters, ctx.arg(0)?) {
+            Some(getter) => with_value(ctx.this(), |value: &mut T| Ok(getter(value))),
+            // not a property, the lookup fails as usual
+            None => Err(Error::Exception(Object::Null)),
+        });
+        class.new_slot(
+            Object::new_string("_get"),
+            native_method(vec![raw_type::INSTANCE, raw_type::STRING], get),
+            false,
+        )?;
+    }
+    let setters = builder.setters;
+    if !setters.is_empty() {
+        let set: NativeFn = Box::new(move |ctx| match property(&setters, ctx.arg(0)?) {
+            Some(setter) => {
+                let obj = ctx.arg(1)?;
+                with_value(ctx.this(), |value: &mut T| setter(value, obj))?;
+                Ok(Object::Null)
+            }
+            None => Err(Error::Exception(Object::Null)),
+        });
+        class.new_slot(
+            Object::new_string("_set"),
+            native_method(vec![raw_type::INSTANCE, raw_type::STRING, ANY_TYPE], set),
+            false,
+        )?;
+    }
+    Ok(gc::track(Object::Class(Rc::new(RefCell::new(class)))))
+}
+
+fn property<'a, P>(properties: &'a HashMap<String, P>, key: &Object) -> Option<&'a P> {
+    match key {
+        Object::String(key) => properties.get(&key[..]),
+        _ => None,
+    }
+}
+
+fn native_method(typemask: Vec<isize>, func: NativeFn) -> Object {
+    let mut closure = NativeClosure::new(func, typemask.len() as types::Integer);
+    closure.typemask = typemask;
+    Object::NativeClosure(Rc::new(closure))
+}
+
+// runs f with the rust value of an instance of a bound class
+fn with_value<T: Any, R>(this: &Object, f: impl FnOnce(&mut T) -> Result<R>) -> Result<R> {
+    let userdata = match this {
+        Object::Instance(instance) => instance.borrow().userdata.clone(),
+        _ => None,
+    };
+    match userdata {
+        Some(userdata) => f(userdata.userdata_mut()?.get_mut::<T>()?),
+        None => Err(Error::RuntimeError(format!(
+            "expected an instance of {}",
+            std::any::type_name::<T>()
+        ))),
+    }
+}
+
+// methods of bound classes. They get the rust value of `this` and the converted arguments.
+pub trait IntoMethod<T, Args> {
+    fn into_method(self) -> Object;
+}
+
+// constructors of bound classes. The returned value is stored in the new instance.
+pub trait IntoConstructor<T, Args> {
+    fn into_constructor(self) -> Object;
+}
+
+macro_rules! impl_bound_fn {
+    ($($arg:ident),*) => {
+        impl<T, F, R, $($arg,)*> IntoMethod<T, ($($arg,)*)> for F
+        where
+            T: Any,
+            F: Fn(&mut T, $($arg),*) -> Result<R> + 'static,
+            R: IntoObject,
+            $($arg: for<'a> FromObject<'a>,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables, unused_assignments)]
+            fn into_method(self) -> Object {
+                let typemask = vec![raw_type::INSTANCE, $($arg::TYPE_MASK,)*];
+                native_method(typemask, Box::new(move |ctx| {
+                    let mut n = 0;
+                    $(
+                        let $arg = $arg::from_object(ctx.arg(n)?)?;
+                        n += 1;
+                    )*
+                    with_value(ctx.this(), |value| self(value, $($arg),*)).map(R::into_object)
+                }))
+            }
+        }
+
+        impl<T, F, $($arg,)*> IntoConstructor<T, ($($arg,)*)> for F
+        where
+            T: Any,
+            F: Fn($($arg),*) -> Result<T> + 'static,
+            $($arg: for<'a> FromObject<'a>,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables, unused_assignments)]
+            fn into_constructor(self) -> Object {
+                let typemask = vec![raw_type::INSTANCE, $($arg::TYPE_MASK,)*];
+                native_method(typemask, Box::new(move |ctx| {
+                    let mut n = 0;
+                    $(
+                        let $arg = $arg::from_object(ctx.arg(n)?)?;
+                        n += 1;
+                    )*
+                    let value = self($($arg),*)?;
+                    match ctx.this() {
+                        Object::Instance(instance) => {
+                            instance.borrow_mut().userdata =
+                                Some(Object::new_userdata(object::UserData::new(value)));
+                            Ok(Object::Null)
+                        }
+                        other => Err(Error::RuntimeError(format!(
+                            "expected instance. found {}",
+                            other.type_name()
+                        ))),
+                    }
+                }))
+            }
+        }
+    };
+}
+
+impl_bound_fn!();
+impl_bound_fn!(A1);
+impl_bound_fn!(A1, A2);
+impl_bound_fn!(A1, A2, A3);
+impl_bound_fn!(A1, A2, A3, A4);
+impl_bound_fn!(A1, A2, A3, A4, A5);
+impl_bound_fn!(A1, A2, A3, A4, A5, A6);
+
+#[cfg(test)]
+mod tests {
+    use super::{ClassBinding, ClassBuilder};
+    use crate::vm::tests::{run, strings};
+    use crate::vm::Executor;
+
+    struct Vec3 {
+        x: f64,
+        y: f64,
+        z: f64,
+    }
+
+    impl Vec3 {
+        fn length(&self) -> f64 {
+            (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+        }
+    }
+
+    impl ClassBinding for Vec3 {
+        const NAME: &'static str = "Vec3";
+        fn bind(class: &mut ClassBuilder<Self>) {
+            class
+                .constructor(|x: f64, y: f64, z: f64| Ok(Vec3 { x, y, z }))
+                .method("length", |v: &mut Vec3| Ok(v.length()))
+                .method("normalize", |v: &mut Vec3| {
+                    let length = v.length();
+                    v.x /= length;
+                    v.y /= length;
+                    v.z /= length;
+                    Ok(())
+                })
+                .property("x", |v| v.x, |v, x| v.x = x)
+                .property("y", |v| v.y, |v, y| v.y = y)
+                .property("z", |v| v.z, |v, z| v.z = z)
+                .getter("name", |_| "vec3");
+        }
+    }
+
+    #[test]
+    fn bind_class() {
+        let source = "
+            local v = Vec3(-1, 4, 0);
+            v.x += 4;
+            local length = v.length();
+            v.normalize();
+            local errors = [];
+            try { v.w; } catch (e) { errors.append(e); }
+            try { v.name = 1; } catch (e) { errors.append(e); }
+            try { Vec3(1, 2); } catch (e) { errors.append(e); }
+            local copy = clone v;
+            try { copy.x = 7; } catch (e) { errors.append(e); }
+            return [length, v.x, v.y, v.z, v.name, v instanceof Vec3, errors[0], errors[1], errors[2],
+                errors[3]];
+        ";
+        let mut exec = Executor::new();
+        exec.register_class::<Vec3>().unwrap();
+        assert_eq!(
+            strings(run(&mut exec, source)),
+            [
+                "5",
+                "0.6",
+                "0.8",
+                "0",
+                "vec3",
+                "true",
+                "the index 'w' does not exist",
+                "the index 'name' does not exist",
+                "wrong number of parameters",
+                "expected an instance of squirrel_rs::binding::tests::Vec3"
+            ]
+        );
+    }
+}
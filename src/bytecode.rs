@@ -1,12 +1,12 @@
 use super::Result;
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use num_derive::{FromPrimitive, ToPrimitive};
-use num_traits::{FromPrimitive, ToPrimitive};
+use num_traits::FromPrimitive;
 
 use std::fmt::Formatter;
-use std::io::Read;
+use std::io::{Read, Write};
 
-#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Eq, Hash)]
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Opcode {
     LINE = 0x00,
     LOAD = 0x01,
@@ -80,6 +80,16 @@ pub enum CompOp {
     _3W = 5,
 }
 
+#[derive(FromPrimitive, ToPrimitive, Debug)]
+pub enum BitwOp {
+    AND = 0,
+    OR = 2,
+    XOR = 3,
+    SHIFTL = 4,
+    SHIFTR = 5,
+    USHIFTR = 6,
+}
+
 #[derive(FromPrimitive, ToPrimitive, Debug)]
 pub enum NewObjectType {
     TABLE = 0,
@@ -93,10 +103,16 @@ pub enum AppendArrayType {
     LITERAL = 1,
     INT = 2,
     FLOAT = 3,
-    BOOL = 4
+    BOOL = 4,
 }
 
-// #[derive(Debug)]
+pub const NEW_SLOT_ATTRIBUTES_FLAG: u8 = 0x01;
+pub const NEW_SLOT_STATIC_FLAG: u8 = 0x02;
+
+pub const OUTER_TYPE_LOCAL: super::types::Integer = 0;
+pub const OUTER_TYPE_OUTER: super::types::Integer = 1;
+
+#[derive(Clone)]
 pub struct Instruction {
     pub arg1: i32,
     pub opcode: u8,
@@ -109,16 +125,22 @@ impl Instruction {
     pub fn read(rdr: &mut dyn Read) -> Result<Instruction> {
         let arg1 = rdr.read_i32::<LittleEndian>()?;
         let mut buf = [0u8; 4];
-        rdr.read(&mut buf)?;
+        rdr.read_exact(&mut buf)?;
 
         Ok(Instruction {
-            arg1: arg1,
+            arg1,
             opcode: buf[0],
             arg0: buf[1],
             arg2: buf[2],
             arg3: buf[3],
         })
     }
+
+    pub fn write(&self, wtr: &mut dyn Write) -> Result<()> {
+        wtr.write_i32::<LittleEndian>(self.arg1)?;
+        wtr.write_all(&[self.opcode, self.arg0, self.arg2, self.arg3])?;
+        Ok(())
+    }
 }
 
 impl std::fmt::Debug for Instruction {
@@ -0,0 +1,1908 @@
+//! Squirrel source compiler. Produces the same function prototypes as the reference compiler,
+//! so its output can be executed directly or saved as .cnut.
+
+mod func_state;
+mod lexer;
+
+use self::func_state::{new_func_proto, FuncState, MAX_FUNC_STACKSIZE};
+use self::lexer::{Lexer, Token};
+use crate::bytecode::{
+    BitwOp, CompOp, NewObjectType, Opcode, NEW_SLOT_ATTRIBUTES_FLAG, NEW_SLOT_STATIC_FLAG,
+};
+use crate::{object, types, Error, Object, Result};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ExpType {
+    Expr,
+    Object,
+    Base,
+    Local,
+    Outer,
+}
+
+#[derive(Clone, Copy)]
+struct ExpState {
+    etype: ExpType,
+    // stack position for Expr/Local, outer index for Outer
+    epos: isize,
+    // signals not to deref the next value
+    donot_get: bool,
+}
+
+impl ExpState {
+    fn new() -> ExpState {
+        ExpState {
+            etype: ExpType::Expr,
+            epos: -1,
+            donot_get: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Scope {
+    stacksize: isize,
+}
+
+struct Compiler<'a> {
+    lex: Lexer<'a>,
+    token: Token,
+    source_name: Object,
+    fs: FuncState,
+    // enclosing functions of `fs`, innermost last
+    parents: Vec<FuncState>,
+    es: ExpState,
+    scope: Scope,
+    consts: HashMap<Object, Object>,
+}
+
+/// Compiles squirrel source code into a closure of the main function.
+pub fn compile_str(source: &str, source_name: &str) -> Result<Object> {
+    let mut compiler = Compiler::new(source, source_name);
+    let func_proto = compiler.compile()?;
+    Ok(Object::Closure(Rc::new(object::Closure::new(func_proto))))
+}
+
+type ExpFn<'a> = fn(&mut Compiler<'a>) -> Result<()>;
+
+impl<'a> Compiler<'a> {
+    fn new(source: &'a str, source_name: &str) -> Compiler<'a> {
+        let source_name = Object::new_string(source_name);
+        Compiler {
+            lex: Lexer::new(source.as_bytes()),
+            token: Token::Eof,
+            fs: FuncState::new(source_name.clone(), false),
+            source_name,
+            parents: Vec::new(),
+            es: ExpState::new(),
+            scope: Scope { stacksize: 0 },
+            consts: HashMap::new(),
+        }
+    }
+
+    fn error<T>(&self, msg: impl Into<String>) -> Result<T> {
+        Err(Error::CompileError(format!(
+            "{}:{}:{}: {}",
+            self.source_name,
+            self.lex.current_line,
+            self.lex.current_column,
+            msg.into()
+        )))
+    }
+
+    fn lex(&mut self) -> Result<()> {
+        match self.lex.lex() {
+            Ok(tok) => {
+                self.token = tok;
+                Ok(())
+            }
+            Err(Error::CompileError(msg)) => self.error(msg),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn expect(&mut self, tok: Token) -> Result<Object> {
+        if self.token != tok && !(self.token == Token::Constructor && tok == Token::Identifier) {
+            return self.error(format!("expected '{}'", tok.name()));
+        }
+        let ret = match tok {
+            Token::Identifier | Token::StringLiteral => Object::new_string(&self.lex.svalue),
+            Token::Integer => Object::Integer(self.lex.nvalue),
+            Token::Float => Object::Float(self.lex.fvalue),
+            _ => Object::Null,
+        };
+        self.lex()?;
+        Ok(ret)
+    }
+
+    fn is_end_of_statement(&self) -> bool {
+        self.lex.prev_token == Token::Char(b'\n')
+            || self.token == Token::Eof
+            || self.token == Token::Char(b'}')
+            || self.token == Token::Char(b';')
+    }
+
+    fn optional_semicolon(&mut self) -> Result<()> {
+        if self.token == Token::Char(b';') {
+            return self.lex();
+        }
+        if !self.is_end_of_statement() {
+            return self.error("end of statement expected (; or lf)");
+        }
+        Ok(())
+    }
+
+    fn optional_semicolon_after_statement(&mut self) -> Result<()> {
+        if self.lex.prev_token != Token::Char(b'}') && self.lex.prev_token != Token::Char(b';') {
+            self.optional_semicolon()?;
+        }
+        Ok(())
+    }
+
+    fn move_if_current_target_is_local(&mut self) {
+        let trg = self.fs.top_target();
+        if self.fs.is_local(trg as usize) {
+            let trg = self.fs.pop_target();
+            let dst = self.fs.push_target();
+            self.fs.add_instruction(Opcode::MOVE, dst, trg, 0, 0);
+        }
+    }
+
+    fn begin_scope(&mut self) -> Scope {
+        let old = self.scope;
+        self.scope = Scope {
+            stacksize: self.fs.get_stack_size(),
+        };
+        old
+    }
+
+    fn resolve_outers(&mut self) {
+        if self.fs.get_stack_size() != self.scope.stacksize
+            && self.fs.count_outers(self.scope.stacksize) > 0
+        {
+            self.fs
+                .add_instruction(Opcode::CLOSE, 0, self.scope.stacksize, 0, 0);
+        }
+    }
+
+    fn end_scope_no_close(&mut self, old: Scope) {
+        if self.fs.get_stack_size() != self.scope.stacksize {
+            self.fs.set_stack_size(self.scope.stacksize);
+        }
+        self.scope = old;
+    }
+
+    fn end_scope(&mut self, old: Scope) {
+        let oldouters = self.fs.outers;
+        if self.fs.get_stack_size() != self.scope.stacksize {
+            self.fs.set_stack_size(self.scope.stacksize);
+            if oldouters != self.fs.outers {
+                self.fs
+                    .add_instruction(Opcode::CLOSE, 0, self.scope.stacksize, 0, 0);
+            }
+        }
+        self.scope = old;
+    }
+
+    // returns the number of pending breaks and continues to resolve in end_breakable_block
+    fn begin_breakable_block(&mut self) -> (usize, usize) {
+        let state = (
+            self.fs.unresolved_breaks.len(),
+            self.fs.unresolved_continues.len(),
+        );
+        self.fs.break_targets.push(0);
+        self.fs.continue_targets.push(0);
+        state
+    }
+
+    fn end_breakable_block(
+        &mut self,
+        (nbreaks, ncontinues): (usize, usize),
+        continue_target: isize,
+    ) {
+        let nbreaks = self.fs.unresolved_breaks.len() - nbreaks;
+        let ncontinues = self.fs.unresolved_continues.len() - ncontinues;
+        if ncontinues > 0 {
+            self.resolve_continues(ncontinues, continue_target);
+        }
+        if nbreaks > 0 {
+            self.resolve_breaks(nbreaks);
+        }
+        self.fs.break_targets.pop();
+        self.fs.continue_targets.pop();
+    }
+
+    fn resolve_breaks(&mut self, ntoresolve: usize) {
+        for _ in 0..ntoresolve {
+            let pos = self.fs.unresolved_breaks.pop().unwrap();
+            let offset = self.fs.get_current_pos() - pos;
+            self.fs.set_instruction_params(pos, 0, offset, 0, 0);
+        }
+    }
+
+    fn resolve_continues(&mut self, ntoresolve: usize, targetpos: isize) {
+        for _ in 0..ntoresolve {
+            let pos = self.fs.unresolved_continues.pop().unwrap();
+            self.fs
+                .set_instruction_params(pos, 0, targetpos - pos, 0, 0);
+        }
+    }
+
+    fn compile(&mut self) -> Result<Object> {
+        self.fs.name = Object::new_string("main");
+        self.fs.add_parameter(Object::new_string("this"));
+        self.fs.add_parameter(Object::new_string("vargv"));
+        self.fs.varparams = true;
+        let stacksize = self.fs.get_stack_size();
+        self.lex()?;
+        while self.token != Token::Eof {
+            self.statement(true)?;
+            self.optional_semicolon_after_statement()?;
+        }
+        self.fs.set_stack_size(stacksize);
+        self.fs.add_line_infos(self.lex.current_line as isize, true);
+        self.fs.add_instruction(Opcode::RETURN, 0xFF, 0, 0, 0);
+        self.fs.set_stack_size(0);
+        let fs = std::mem::replace(&mut self.fs, FuncState::new(Object::Null, false));
+        Ok(new_func_proto(fs))
+    }
+
+    fn statements(&mut self) -> Result<()> {
+        while self.token != Token::Char(b'}')
+            && self.token != Token::Default
+            && self.token != Token::Case
+        {
+            self.statement(true)?;
+            self.optional_semicolon_after_statement()?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, closeframe: bool) -> Result<()> {
+        self.fs
+            .add_line_infos(self.lex.current_line as isize, false);
+        match self.token {
+            Token::Char(b';') => self.lex()?,
+            Token::If => self.if_statement()?,
+            Token::While => self.while_statement()?,
+            Token::Do => self.do_while_statement()?,
+            Token::For => self.for_statement()?,
+            Token::Foreach => self.foreach_statement()?,
+            Token::Switch => self.switch_statement()?,
+            Token::Local => self.local_decl_statement()?,
+            Token::Return | Token::Yield => {
+                let op = if self.token == Token::Return {
+                    Opcode::RETURN
+                } else {
+                    self.fs.bgenerator = true;
+                    Opcode::YIELD
+                };
+                self.lex()?;
+                if !self.is_end_of_statement() {
+                    let retexp = self.fs.get_current_pos() + 1;
+                    self.comma_expr()?;
+                    if op == Opcode::RETURN && self.fs.traps > 0 {
+                        self.fs
+                            .add_instruction(Opcode::POPTRAP, self.fs.traps, 0, 0, 0);
+                    }
+                    self.fs.returnexp = retexp;
+                    let stacksize = self.fs.get_stack_size();
+                    let trg = self.fs.pop_target();
+                    self.fs.add_instruction(op, 1, trg, stacksize, 0);
+                } else {
+                    if op == Opcode::RETURN && self.fs.traps > 0 {
+                        self.fs
+                            .add_instruction(Opcode::POPTRAP, self.fs.traps, 0, 0, 0);
+                    }
+                    self.fs.returnexp = -1;
+                    let stacksize = self.fs.get_stack_size();
+                    self.fs.add_instruction(op, 0xFF, 0, stacksize, 0);
+                }
+            }
+            Token::Break => {
+                let ntraps = match self.fs.break_targets.last() {
+                    Some(ntraps) => *ntraps,
+                    None => return self.error("'break' has to be in a loop block"),
+                };
+                if ntraps > 0 {
+                    self.fs.add_instruction(Opcode::POPTRAP, ntraps, 0, 0, 0);
+                }
+                self.resolve_outers();
+                self.fs.add_instruction(Opcode::JMP, 0, -1234, 0, 0);
+                let pos = self.fs.get_current_pos();
+                self.fs.unresolved_breaks.push(pos);
+                self.lex()?;
+            }
+            Token::Continue => {
+                let ntraps = match self.fs.continue_targets.last() {
+                    Some(ntraps) => *ntraps,
+                    None => return self.error("'continue' has to be in a loop block"),
+                };
+                if ntraps > 0 {
+                    self.fs.add_instruction(Opcode::POPTRAP, ntraps, 0, 0, 0);
+                }
+                self.resolve_outers();
+                self.fs.add_instruction(Opcode::JMP, 0, -1234, 0, 0);
+                let pos = self.fs.get_current_pos();
+                self.fs.unresolved_continues.push(pos);
+                self.lex()?;
+            }
+            Token::Function => self.function_statement()?,
+            Token::Class => self.class_statement()?,
+            Token::Enum => self.enum_statement()?,
+            Token::Char(b'{') => {
+                let old = self.begin_scope();
+                self.lex()?;
+                self.statements()?;
+                self.expect(Token::Char(b'}'))?;
+                if closeframe {
+                    self.end_scope(old);
+                } else {
+                    self.end_scope_no_close(old);
+                }
+            }
+            Token::Try => self.try_catch_statement()?,
+            Token::Throw => {
+                self.lex()?;
+                self.comma_expr()?;
+                let trg = self.fs.pop_target();
+                self.fs.add_instruction(Opcode::THROW, trg, 0, 0, 0);
+            }
+            Token::Const => {
+                self.lex()?;
+                let id = self.expect(Token::Identifier)?;
+                self.expect(Token::Char(b'='))?;
+                let val = self.expect_scalar()?;
+                self.optional_semicolon()?;
+                self.consts.insert(id, val);
+            }
+            _ => {
+                self.comma_expr()?;
+                self.fs.discard_target();
+            }
+        }
+        self.fs.snooze_opt();
+        Ok(())
+    }
+
+    fn emit_deref_op(&mut self, op: Opcode) {
+        let val = self.fs.pop_target();
+        let key = self.fs.pop_target();
+        let src = self.fs.pop_target();
+        let trg = self.fs.push_target();
+        self.fs.add_instruction(op, trg, src, key, val);
+    }
+
+    fn emit_2args_op(&mut self, op: Opcode, p3: isize) {
+        let p2 = self.fs.pop_target(); // src in GET
+        let p1 = self.fs.pop_target(); // key in GET
+        let trg = self.fs.push_target();
+        self.fs.add_instruction(op, trg, p1, p2, p3);
+    }
+
+    fn emit_compound_arith(&mut self, tok: Token, etype: ExpType, pos: isize) {
+        match etype {
+            ExpType::Local => {
+                let p2 = self.fs.pop_target();
+                let p1 = self.fs.pop_target();
+                self.fs.push_target_at(p1);
+                self.fs
+                    .add_instruction(arith_op_by_token(tok), p1, p2, p1, 0);
+                self.fs.snooze_opt();
+            }
+            ExpType::Object | ExpType::Base => {
+                let val = self.fs.pop_target();
+                let key = self.fs.pop_target();
+                let src = self.fs.pop_target();
+                let trg = self.fs.push_target();
+                // COMPARITH mixes dest obj and source val in arg1
+                self.fs.add_instruction(
+                    Opcode::COMPARITH,
+                    trg,
+                    (src << 16) | val,
+                    key,
+                    comp_arith_char_by_token(tok) as isize,
+                );
+            }
+            ExpType::Outer => {
+                let val = self.fs.top_target();
+                let tmp = self.fs.push_target();
+                self.fs.add_instruction(Opcode::GETOUTER, tmp, pos, 0, 0);
+                self.fs
+                    .add_instruction(arith_op_by_token(tok), tmp, val, tmp, 0);
+                self.fs.pop_target();
+                self.fs.pop_target();
+                let trg = self.fs.push_target();
+                self.fs.add_instruction(Opcode::SETOUTER, trg, pos, tmp, 0);
+            }
+            ExpType::Expr => unreachable!(),
+        }
+    }
+
+    fn comma_expr(&mut self) -> Result<()> {
+        self.expression()?;
+        while self.token == Token::Char(b',') {
+            self.fs.pop_target();
+            self.lex()?;
+            self.expression()?;
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self) -> Result<()> {
+        let es = self.es;
+        self.es = ExpState::new();
+        self.logical_or_exp()?;
+        match self.token {
+            Token::Char(b'=')
+            | Token::NewSlot
+            | Token::MinusEq
+            | Token::PlusEq
+            | Token::MulEq
+            | Token::DivEq
+            | Token::ModEq => {
+                let op = self.token;
+                let ds = self.es.etype;
+                let pos = self.es.epos;
+                match ds {
+                    ExpType::Expr => return self.error("can't assign expression"),
+                    ExpType::Base => return self.error("'base' cannot be modified"),
+                    _ => (),
+                }
+                self.lex()?;
+                self.expression()?;
+
+                match op {
+                    Token::NewSlot => {
+                        if ds == ExpType::Object || ds == ExpType::Base {
+                            self.emit_deref_op(Opcode::NEWSLOT);
+                        } else {
+                            return self.error("can't 'create' a local slot");
+                        }
+                    }
+                    Token::Char(b'=') => match ds {
+                        ExpType::Local => {
+                            let src = self.fs.pop_target();
+                            let dst = self.fs.top_target();
+                            self.fs.add_instruction(Opcode::MOVE, dst, src, 0, 0);
+                        }
+                        ExpType::Object | ExpType::Base => self.emit_deref_op(Opcode::SET),
+                        ExpType::Outer => {
+                            let src = self.fs.pop_target();
+                            let dst = self.fs.push_target();
+                            self.fs.add_instruction(Opcode::SETOUTER, dst, pos, src, 0);
+                        }
+                        ExpType::Expr => unreachable!(),
+                    },
+                    _ => self.emit_compound_arith(op, ds, pos),
+                }
+            }
+            Token::Char(b'?') => {
+                self.lex()?;
+                let cond = self.fs.pop_target();
+                self.fs.add_instruction(Opcode::JZ, cond, 0, 0, 0);
+                let jzpos = self.fs.get_current_pos();
+                let trg = self.fs.push_target();
+                self.expression()?;
+                let first_exp = self.fs.pop_target();
+                if trg != first_exp {
+                    self.fs.add_instruction(Opcode::MOVE, trg, first_exp, 0, 0);
+                }
+                let endfirstexp = self.fs.get_current_pos();
+                self.fs.add_instruction(Opcode::JMP, 0, 0, 0, 0);
+                self.expect(Token::Char(b':'))?;
+                let jmppos = self.fs.get_current_pos();
+                self.expression()?;
+                let second_exp = self.fs.pop_target();
+                if trg != second_exp {
+                    self.fs.add_instruction(Opcode::MOVE, trg, second_exp, 0, 0);
+                }
+                let pos = self.fs.get_current_pos();
+                self.fs.set_instruction_param(jmppos, 1, pos - jmppos);
+                self.fs
+                    .set_instruction_param(jzpos, 1, endfirstexp - jzpos + 1);
+                self.fs.snooze_opt();
+            }
+            _ => (),
+        }
+        self.es = es;
+        Ok(())
+    }
+
+    fn invoke_exp(&mut self, f: ExpFn<'a>) -> Result<()> {
+        let es = self.es;
+        self.es = ExpState::new();
+        f(self)?;
+        self.es = es;
+        Ok(())
+    }
+
+    fn bin_exp(&mut self, op: Opcode, f: ExpFn<'a>, op3: isize) -> Result<()> {
+        self.lex()?;
+        self.invoke_exp(f)?;
+        let op1 = self.fs.pop_target();
+        let op2 = self.fs.pop_target();
+        let trg = self.fs.push_target();
+        self.fs.add_instruction(op, trg, op1, op2, op3);
+        self.es.etype = ExpType::Expr;
+        Ok(())
+    }
+
+    // shared by && and ||: evaluates the right hand side only if `op` does not short-circuit
+    fn logical_exp(&mut self, op: Opcode, f: ExpFn<'a>) -> Result<()> {
+        let first_exp = self.fs.pop_target();
+        let trg = self.fs.push_target();
+        self.fs.add_instruction(op, trg, 0, first_exp, 0);
+        let jpos = self.fs.get_current_pos();
+        if trg != first_exp {
+            self.fs.add_instruction(Opcode::MOVE, trg, first_exp, 0, 0);
+        }
+        self.lex()?;
+        self.invoke_exp(f)?;
+        self.fs.snooze_opt();
+        let second_exp = self.fs.pop_target();
+        if trg != second_exp {
+            self.fs.add_instruction(Opcode::MOVE, trg, second_exp, 0, 0);
+        }
+        self.fs.snooze_opt();
+        let pos = self.fs.get_current_pos();
+        self.fs.set_instruction_param(jpos, 1, pos - jpos);
+        self.es.etype = ExpType::Expr;
+        Ok(())
+    }
+
+    fn logical_or_exp(&mut self) -> Result<()> {
+        self.and_exp()?;
+        if self.token == Token::Or {
+            self.logical_exp(Opcode::OR, Compiler::logical_or_exp)?;
+        }
+        Ok(())
+    }
+
+    fn and_exp(&mut self) -> Result<()> {
+        self.bitwise_or_exp()?;
+        while self.token == Token::And {
+            self.logical_exp(Opcode::AND, Compiler::and_exp)?;
+        }
+        Ok(())
+    }
+
+    fn bitwise_or_exp(&mut self) -> Result<()> {
+        self.bitwise_xor_exp()?;
+        while self.token == Token::Char(b'|') {
+            self.bin_exp(Opcode::BITW, Compiler::bitwise_xor_exp, BitwOp::OR as isize)?;
+        }
+        Ok(())
+    }
+
+    fn bitwise_xor_exp(&mut self) -> Result<()> {
+        self.bitwise_and_exp()?;
+        while self.token == Token::Char(b'^') {
+            self.bin_exp(
+                Opcode::BITW,
+                Compiler::bitwise_and_exp,
+                BitwOp::XOR as isize,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn bitwise_and_exp(&mut self) -> Result<()> {
+        self.eq_exp()?;
+        while self.token == Token::Char(b'&') {
+            self.bin_exp(Opcode::BITW, Compiler::eq_exp, BitwOp::AND as isize)?;
+        }
+        Ok(())
+    }
+
+    fn eq_exp(&mut self) -> Result<()> {
+        self.comp_exp()?;
+        loop {
+            match self.token {
+                Token::Eq => self.bin_exp(Opcode::EQ, Compiler::comp_exp, 0)?,
+                Token::Ne => self.bin_exp(Opcode::NE, Compiler::comp_exp, 0)?,
+                Token::ThreeWayCmp => {
+                    self.bin_exp(Opcode::CMP, Compiler::comp_exp, CompOp::_3W as isize)?
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn comp_exp(&mut self) -> Result<()> {
+        self.shift_exp()?;
+        loop {
+            match self.token {
+                Token::Char(b'>') => {
+                    self.bin_exp(Opcode::CMP, Compiler::shift_exp, CompOp::G as isize)?
+                }
+                Token::Char(b'<') => {
+                    self.bin_exp(Opcode::CMP, Compiler::shift_exp, CompOp::L as isize)?
+                }
+                Token::Ge => self.bin_exp(Opcode::CMP, Compiler::shift_exp, CompOp::GE as isize)?,
+                Token::Le => self.bin_exp(Opcode::CMP, Compiler::shift_exp, CompOp::LE as isize)?,
+                Token::In => self.bin_exp(Opcode::EXISTS, Compiler::shift_exp, 0)?,
+                Token::InstanceOf => self.bin_exp(Opcode::INSTANCEOF, Compiler::shift_exp, 0)?,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn shift_exp(&mut self) -> Result<()> {
+        self.plus_exp()?;
+        loop {
+            let op = match self.token {
+                Token::UShiftR => BitwOp::USHIFTR,
+                Token::ShiftL => BitwOp::SHIFTL,
+                Token::ShiftR => BitwOp::SHIFTR,
+                _ => return Ok(()),
+            };
+            self.bin_exp(Opcode::BITW, Compiler::plus_exp, op as isize)?;
+        }
+    }
+
+    fn plus_exp(&mut self) -> Result<()> {
+        self.mult_exp()?;
+        while let Token::Char(b'+') | Token::Char(b'-') = self.token {
+            self.bin_exp(arith_op_by_token(self.token), Compiler::mult_exp, 0)?;
+        }
+        Ok(())
+    }
+
+    fn mult_exp(&mut self) -> Result<()> {
+        self.prefixed_expr()?;
+        while let Token::Char(b'*') | Token::Char(b'/') | Token::Char(b'%') = self.token {
+            self.bin_exp(arith_op_by_token(self.token), Compiler::prefixed_expr, 0)?;
+        }
+        Ok(())
+    }
+
+    // the key of an object access is on the stack: emit GET for rvalues, keep the
+    // (object, key) pair for assignments and calls
+    fn deref_member(&mut self) {
+        if self.es.etype == ExpType::Base {
+            self.emit_2args_op(Opcode::GET, 0);
+            self.es.etype = ExpType::Expr;
+            self.es.epos = self.fs.top_target();
+        } else {
+            if self.need_get() {
+                self.emit_2args_op(Opcode::GET, 0);
+            }
+            self.es.etype = ExpType::Object;
+        }
+    }
+
+    fn prefixed_expr(&mut self) -> Result<()> {
+        self.factor()?;
+        loop {
+            match self.token {
+                Token::Char(b'.') => {
+                    self.lex()?;
+                    let id = self.expect(Token::Identifier)?;
+                    let trg = self.fs.push_target();
+                    let constant = self.fs.get_constant(id);
+                    self.fs.add_instruction(Opcode::LOAD, trg, constant, 0, 0);
+                    self.deref_member();
+                }
+                Token::Char(b'[') => {
+                    if self.lex.prev_token == Token::Char(b'\n') {
+                        return self.error(
+                            "cannot brake deref/or comma needed after [exp]=exp slot declaration",
+                        );
+                    }
+                    self.lex()?;
+                    self.expression()?;
+                    self.expect(Token::Char(b']'))?;
+                    self.deref_member();
+                }
+                Token::MinusMinus | Token::PlusPlus => {
+                    if self.is_end_of_statement() {
+                        return Ok(());
+                    }
+                    let diff = if self.token == Token::MinusMinus {
+                        -1
+                    } else {
+                        1
+                    };
+                    self.lex()?;
+                    match self.es.etype {
+                        ExpType::Expr => return self.error("can't '++' or '--' an expression"),
+                        ExpType::Object | ExpType::Base => {
+                            if self.es.donot_get {
+                                return self.error("can't '++' or '--' an expression");
+                            }
+                            self.emit_2args_op(Opcode::PINC, diff);
+                        }
+                        ExpType::Local => {
+                            let src = self.fs.pop_target();
+                            let trg = self.fs.push_target();
+                            self.fs.add_instruction(Opcode::PINCL, trg, src, 0, diff);
+                        }
+                        ExpType::Outer => {
+                            let tmp1 = self.fs.push_target();
+                            let tmp2 = self.fs.push_target();
+                            let epos = self.es.epos;
+                            self.fs.add_instruction(Opcode::GETOUTER, tmp2, epos, 0, 0);
+                            self.fs.add_instruction(Opcode::PINCL, tmp1, tmp2, 0, diff);
+                            self.fs
+                                .add_instruction(Opcode::SETOUTER, tmp2, epos, tmp2, 0);
+                            self.fs.pop_target();
+                        }
+                    }
+                    return Ok(());
+                }
+                Token::Char(b'(') => {
+                    match self.es.etype {
+                        ExpType::Object => {
+                            let key = self.fs.pop_target();
+                            let table = self.fs.pop_target();
+                            let closure = self.fs.push_target();
+                            let ttarget = self.fs.push_target();
+                            self.fs
+                                .add_instruction(Opcode::PREPCALL, closure, key, table, ttarget);
+                        }
+                        ExpType::Outer => {
+                            let trg = self.fs.push_target();
+                            let epos = self.es.epos;
+                            self.fs.add_instruction(Opcode::GETOUTER, trg, epos, 0, 0);
+                            let trg = self.fs.push_target();
+                            self.fs.add_instruction(Opcode::MOVE, trg, 0, 0, 0);
+                        }
+                        _ => {
+                            let trg = self.fs.push_target();
+                            self.fs.add_instruction(Opcode::MOVE, trg, 0, 0, 0);
+                        }
+                    }
+                    self.es.etype = ExpType::Expr;
+                    self.lex()?;
+                    self.function_call_args()?;
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Result<()> {
+        match self.token {
+            Token::StringLiteral => {
+                let trg = self.fs.push_target();
+                let constant = self.fs.get_constant(Object::new_string(&self.lex.svalue));
+                self.fs.add_instruction(Opcode::LOAD, trg, constant, 0, 0);
+                self.lex()?;
+            }
+            Token::Base => {
+                self.lex()?;
+                let trg = self.fs.push_target();
+                self.fs.add_instruction(Opcode::GETBASE, trg, 0, 0, 0);
+                self.es.etype = ExpType::Base;
+                self.es.epos = trg;
+                return Ok(());
+            }
+            Token::Identifier | Token::Constructor | Token::This => {
+                let id = match self.token {
+                    Token::This => Object::new_string("this"),
+                    Token::Constructor => Object::new_string("constructor"),
+                    _ => Object::new_string(&self.lex.svalue),
+                };
+                self.lex()?;
+                if let Some(pos) = self.fs.get_local_variable(&id) {
+                    // a local variable (includes 'this')
+                    self.fs.push_target_at(pos);
+                    self.es.etype = ExpType::Local;
+                    self.es.epos = pos;
+                } else if let Some(pos) = self.fs.get_outer_variable(&mut self.parents, &id) {
+                    // a free variable
+                    if self.need_get() {
+                        self.es.epos = self.fs.push_target();
+                        self.fs
+                            .add_instruction(Opcode::GETOUTER, self.es.epos, pos, 0, 0);
+                    } else {
+                        self.es.etype = ExpType::Outer;
+                        self.es.epos = pos;
+                    }
+                } else if let Some(constant) = self.consts.get(&id).cloned() {
+                    // a named constant
+                    let constval = if let Object::Table(table) = &constant {
+                        self.expect(Token::Char(b'.'))?;
+                        let constid = self.expect(Token::Identifier)?;
+                        let constval = table.borrow().map.get(&constid).cloned();
+                        match constval {
+                            Some(constval) => constval,
+                            None => {
+                                return self.error(format!("invalid constant [{}.{}]", id, constid))
+                            }
+                        }
+                    } else {
+                        constant
+                    };
+                    self.es.epos = self.fs.push_target();
+                    let epos = self.es.epos;
+                    match constval {
+                        Object::Integer(i) => self.emit_load_const_int(i, epos),
+                        Object::Float(f) => self.emit_load_const_float(f, epos),
+                        Object::Bool(b) => {
+                            self.fs
+                                .add_instruction(Opcode::LOADBOOL, epos, b as isize, 0, 0)
+                        }
+                        _ => {
+                            let constant = self.fs.get_constant(constval);
+                            self.fs.add_instruction(Opcode::LOAD, epos, constant, 0, 0);
+                        }
+                    }
+                    self.es.etype = ExpType::Expr;
+                } else {
+                    // a non-local variable, aka a field of 'this'. 'this' is always at stack
+                    // position 0, so only the key needs to be loaded.
+                    self.fs.push_target_at(0);
+                    let trg = self.fs.push_target();
+                    let constant = self.fs.get_constant(id);
+                    self.fs.add_instruction(Opcode::LOAD, trg, constant, 0, 0);
+                    if self.need_get() {
+                        self.emit_2args_op(Opcode::GET, 0);
+                    }
+                    self.es.etype = ExpType::Object;
+                }
+                return Ok(());
+            }
+            Token::DoubleColon => {
+                let trg = self.fs.push_target();
+                self.fs.add_instruction(Opcode::LOADROOT, trg, 0, 0, 0);
+                self.es.etype = ExpType::Object;
+                // continue in prefixed_expr as if this was a '.'
+                self.token = Token::Char(b'.');
+                self.es.epos = -1;
+                return Ok(());
+            }
+            Token::Null => {
+                let trg = self.fs.push_target();
+                self.fs.add_instruction(Opcode::LOADNULLS, trg, 1, 0, 0);
+                self.lex()?;
+            }
+            Token::Integer => {
+                self.emit_load_const_int(self.lex.nvalue, -1);
+                self.lex()?;
+            }
+            Token::Float => {
+                self.emit_load_const_float(self.lex.fvalue, -1);
+                self.lex()?;
+            }
+            Token::True | Token::False => {
+                let trg = self.fs.push_target();
+                let value = (self.token == Token::True) as isize;
+                self.fs.add_instruction(Opcode::LOADBOOL, trg, value, 0, 0);
+                self.lex()?;
+            }
+            Token::Char(b'[') => {
+                let trg = self.fs.push_target();
+                self.fs
+                    .add_instruction(Opcode::NEWOBJ, trg, 0, 0, NewObjectType::ARRAY as isize);
+                let apos = self.fs.get_current_pos();
+                let mut key = 0;
+                self.lex()?;
+                while self.token != Token::Char(b']') {
+                    self.expression()?;
+                    if self.token == Token::Char(b',') {
+                        self.lex()?;
+                    }
+                    let val = self.fs.pop_target();
+                    let array = self.fs.top_target();
+                    self.fs
+                        .add_instruction(Opcode::APPENDARRAY, array, val, 0, 0);
+                    key += 1;
+                }
+                self.fs.set_instruction_param(apos, 1, key);
+                self.lex()?;
+            }
+            Token::Char(b'{') => {
+                let trg = self.fs.push_target();
+                self.fs
+                    .add_instruction(Opcode::NEWOBJ, trg, 0, 0, NewObjectType::TABLE as isize);
+                self.lex()?;
+                self.parse_table_or_class(Token::Char(b','), Token::Char(b'}'))?;
+            }
+            Token::Function => self.function_exp(false)?,
+            Token::Char(b'@') => self.function_exp(true)?,
+            Token::Class => {
+                self.lex()?;
+                self.class_exp()?;
+            }
+            Token::Char(b'-') => {
+                self.lex()?;
+                match self.token {
+                    Token::Integer => {
+                        self.emit_load_const_int(self.lex.nvalue.wrapping_neg(), -1);
+                        self.lex()?;
+                    }
+                    Token::Float => {
+                        self.emit_load_const_float(-self.lex.fvalue, -1);
+                        self.lex()?;
+                    }
+                    _ => self.unary_op(Opcode::NEG)?,
+                }
+            }
+            Token::Char(b'!') => {
+                self.lex()?;
+                self.unary_op(Opcode::NOT)?;
+            }
+            Token::Char(b'~') => {
+                self.lex()?;
+                if self.token == Token::Integer {
+                    self.emit_load_const_int(!self.lex.nvalue, -1);
+                    self.lex()?;
+                } else {
+                    self.unary_op(Opcode::BWNOT)?;
+                }
+            }
+            Token::Typeof => {
+                self.lex()?;
+                self.unary_op(Opcode::TYPEOF)?;
+            }
+            Token::Resume => {
+                self.lex()?;
+                self.unary_op(Opcode::RESUME)?;
+            }
+            Token::Clone => {
+                self.lex()?;
+                self.unary_op(Opcode::CLONE)?;
+            }
+            Token::MinusMinus | Token::PlusPlus => self.prefix_inc_dec(self.token)?,
+            Token::Delete => self.delete_expr()?,
+            Token::Char(b'(') => {
+                self.lex()?;
+                self.comma_expr()?;
+                self.expect(Token::Char(b')'))?;
+            }
+            Token::Line => {
+                self.emit_load_const_int(self.lex.current_line as types::Integer, -1);
+                self.lex()?;
+            }
+            Token::File => {
+                let trg = self.fs.push_target();
+                let constant = self.fs.get_constant(self.source_name.clone());
+                self.fs.add_instruction(Opcode::LOAD, trg, constant, 0, 0);
+                self.lex()?;
+            }
+            _ => return self.error("expression expected"),
+        }
+        self.es.etype = ExpType::Expr;
+        Ok(())
+    }
+
+    fn emit_load_const_int(&mut self, value: types::Integer, target: isize) {
+        let target = if target < 0 {
+            self.fs.push_target()
+        } else {
+            target
+        };
+        // values that do not fit into arg1 go to the literal table
+        if value <= i32::MAX as types::Integer && value >= i32::MIN as types::Integer {
+            self.fs
+                .add_instruction(Opcode::LOADINT, target, value as isize, 0, 0);
+        } else {
+            let constant = self.fs.get_constant(Object::Integer(value));
+            self.fs
+                .add_instruction(Opcode::LOAD, target, constant, 0, 0);
+        }
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    fn emit_load_const_float(&mut self, value: types::Float, target: isize) {
+        let target = if target < 0 {
+            self.fs.push_target()
+        } else {
+            target
+        };
+        // LOADFLOAT only fits single precision floats
+        if std::mem::size_of::<types::Float>() == std::mem::size_of::<i32>() {
+            self.fs.add_instruction(
+                Opcode::LOADFLOAT,
+                target,
+                (value as f32).to_bits() as i32 as isize,
+                0,
+                0,
+            );
+        } else {
+            let constant = self.fs.get_constant(Object::Float(value));
+            self.fs
+                .add_instruction(Opcode::LOAD, target, constant, 0, 0);
+        }
+    }
+
+    fn unary_op(&mut self, op: Opcode) -> Result<()> {
+        self.prefixed_expr()?;
+        let src = self.fs.pop_target();
+        let trg = self.fs.push_target();
+        self.fs.add_instruction(op, trg, src, 0, 0);
+        Ok(())
+    }
+
+    fn need_get(&self) -> bool {
+        match self.token {
+            Token::Char(b'=')
+            | Token::Char(b'(')
+            | Token::NewSlot
+            | Token::ModEq
+            | Token::MulEq
+            | Token::DivEq
+            | Token::MinusEq
+            | Token::PlusEq => return false,
+            Token::PlusPlus | Token::MinusMinus if !self.is_end_of_statement() => return false,
+            _ => (),
+        }
+        !self.es.donot_get || self.token == Token::Char(b'.') || self.token == Token::Char(b'[')
+    }
+
+    fn function_call_args(&mut self) -> Result<()> {
+        let mut nargs = 1; // this
+        while self.token != Token::Char(b')') {
+            self.expression()?;
+            self.move_if_current_target_is_local();
+            nargs += 1;
+            if self.token == Token::Char(b',') {
+                self.lex()?;
+                if self.token == Token::Char(b')') {
+                    return self.error("expression expected, found ')'");
+                }
+            }
+        }
+        self.lex()?;
+        for _ in 0..(nargs - 1) {
+            self.fs.pop_target();
+        }
+        let stackbase = self.fs.pop_target();
+        let closure = self.fs.pop_target();
+        let trg = self.fs.push_target();
+        self.fs
+            .add_instruction(Opcode::CALL, trg, closure, stackbase, nargs);
+        Ok(())
+    }
+
+    fn parse_table_or_class(&mut self, separator: Token, terminator: Token) -> Result<()> {
+        let tpos = self.fs.get_current_pos();
+        let mut nkeys = 0;
+        while self.token != terminator {
+            let mut hasattrs = false;
+            let mut isstatic = false;
+            // check if is an attribute
+            if separator == Token::Char(b';') {
+                if self.token == Token::AttrOpen {
+                    let trg = self.fs.push_target();
+                    self.fs.add_instruction(
+                        Opcode::NEWOBJ,
+                        trg,
+                        0,
+                        0,
+                        NewObjectType::TABLE as isize,
+                    );
+                    self.lex()?;
+                    self.parse_table_or_class(Token::Char(b','), Token::AttrClose)?;
+                    hasattrs = true;
+                }
+                if self.token == Token::Static {
+                    isstatic = true;
+                    self.lex()?;
+                }
+            }
+            match self.token {
+                Token::Function | Token::Constructor => {
+                    let tk = self.token;
+                    self.lex()?;
+                    let id = if tk == Token::Function {
+                        self.expect(Token::Identifier)?
+                    } else {
+                        Object::new_string("constructor")
+                    };
+                    self.expect(Token::Char(b'('))?;
+                    let trg = self.fs.push_target();
+                    let constant = self.fs.get_constant(id.clone());
+                    self.fs.add_instruction(Opcode::LOAD, trg, constant, 0, 0);
+                    self.create_function(id, false)?;
+                    let trg = self.fs.push_target();
+                    let nfunc = self.fs.functions.len() as isize - 1;
+                    self.fs.add_instruction(Opcode::CLOSURE, trg, nfunc, 0, 0);
+                }
+                Token::Char(b'[') => {
+                    self.lex()?;
+                    self.comma_expr()?;
+                    self.expect(Token::Char(b']'))?;
+                    self.expect(Token::Char(b'='))?;
+                    self.expression()?;
+                }
+                Token::StringLiteral if separator == Token::Char(b',') => {
+                    // JSON style keys, only for tables
+                    let key = self.expect(Token::StringLiteral)?;
+                    let trg = self.fs.push_target();
+                    let constant = self.fs.get_constant(key);
+                    self.fs.add_instruction(Opcode::LOAD, trg, constant, 0, 0);
+                    self.expect(Token::Char(b':'))?;
+                    self.expression()?;
+                }
+                _ => {
+                    let key = self.expect(Token::Identifier)?;
+                    let trg = self.fs.push_target();
+                    let constant = self.fs.get_constant(key);
+                    self.fs.add_instruction(Opcode::LOAD, trg, constant, 0, 0);
+                    self.expect(Token::Char(b'='))?;
+                    self.expression()?;
+                }
+            }
+            if self.token == separator {
+                // optional comma/semicolon
+                self.lex()?;
+            }
+            nkeys += 1;
+            let val = self.fs.pop_target();
+            let key = self.fs.pop_target();
+            if hasattrs {
+                self.fs.pop_target();
+            }
+            let mut flags = 0;
+            if hasattrs {
+                flags |= NEW_SLOT_ATTRIBUTES_FLAG;
+            }
+            if isstatic {
+                flags |= NEW_SLOT_STATIC_FLAG;
+            }
+            let table = self.fs.top_target();
+            if separator == Token::Char(b',') {
+                self.fs
+                    .add_instruction(Opcode::NEWSLOT, 0xFF, table, key, val);
+            } else {
+                // classes only, invokes _newmember
+                self.fs
+                    .add_instruction(Opcode::NEWSLOTA, flags as isize, table, key, val);
+            }
+        }
+        if separator == Token::Char(b',') {
+            self.fs.set_instruction_param(tpos, 1, nkeys);
+        }
+        self.lex()
+    }
+
+    fn local_decl_statement(&mut self) -> Result<()> {
+        self.lex()?;
+        if self.token == Token::Function {
+            self.lex()?;
+            let varname = self.expect(Token::Identifier)?;
+            self.expect(Token::Char(b'('))?;
+            self.create_function(varname.clone(), false)?;
+            let trg = self.fs.push_target();
+            let nfunc = self.fs.functions.len() as isize - 1;
+            self.fs.add_instruction(Opcode::CLOSURE, trg, nfunc, 0, 0);
+            self.fs.pop_target();
+            self.fs.push_local_variable(varname);
+            return Ok(());
+        }
+
+        loop {
+            let varname = self.expect(Token::Identifier)?;
+            if self.token == Token::Char(b'=') {
+                self.lex()?;
+                self.expression()?;
+                let src = self.fs.pop_target();
+                let dest = self.fs.push_target();
+                if dest != src {
+                    self.fs.add_instruction(Opcode::MOVE, dest, src, 0, 0);
+                }
+            } else {
+                let trg = self.fs.push_target();
+                self.fs.add_instruction(Opcode::LOADNULLS, trg, 1, 0, 0);
+            }
+            self.fs.pop_target();
+            self.fs.push_local_variable(varname);
+            if self.token == Token::Char(b',') {
+                self.lex()?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn if_block(&mut self) -> Result<()> {
+        if self.token == Token::Char(b'{') {
+            let old = self.begin_scope();
+            self.lex()?;
+            self.statements()?;
+            self.expect(Token::Char(b'}'))?;
+            self.end_scope(old);
+        } else {
+            self.statement(true)?;
+            self.optional_semicolon_after_statement()?;
+        }
+        Ok(())
+    }
+
+    fn if_statement(&mut self) -> Result<()> {
+        self.lex()?;
+        self.expect(Token::Char(b'('))?;
+        self.comma_expr()?;
+        self.expect(Token::Char(b')'))?;
+        let cond = self.fs.pop_target();
+        self.fs.add_instruction(Opcode::JZ, cond, 0, 0, 0);
+        let jnepos = self.fs.get_current_pos();
+
+        self.if_block()?;
+
+        let endifblock = self.fs.get_current_pos();
+        let mut haselse = false;
+        if self.token == Token::Else {
+            haselse = true;
+            self.fs.add_instruction(Opcode::JMP, 0, 0, 0, 0);
+            let jmppos = self.fs.get_current_pos();
+            self.lex()?;
+            self.if_block()?;
+            let pos = self.fs.get_current_pos();
+            self.fs.set_instruction_param(jmppos, 1, pos - jmppos);
+        }
+        self.fs
+            .set_instruction_param(jnepos, 1, endifblock - jnepos + haselse as isize);
+        Ok(())
+    }
+
+    fn while_statement(&mut self) -> Result<()> {
+        let jmppos = self.fs.get_current_pos();
+        self.lex()?;
+        self.expect(Token::Char(b'('))?;
+        self.comma_expr()?;
+        self.expect(Token::Char(b')'))?;
+
+        let block = self.begin_breakable_block();
+        let cond = self.fs.pop_target();
+        self.fs.add_instruction(Opcode::JZ, cond, 0, 0, 0);
+        let jzpos = self.fs.get_current_pos();
+        let old = self.begin_scope();
+
+        self.statement(true)?;
+
+        self.end_scope(old);
+        let pos = self.fs.get_current_pos();
+        self.fs
+            .add_instruction(Opcode::JMP, 0, jmppos - pos - 1, 0, 0);
+        let pos = self.fs.get_current_pos();
+        self.fs.set_instruction_param(jzpos, 1, pos - jzpos);
+
+        self.end_breakable_block(block, jmppos);
+        Ok(())
+    }
+
+    fn do_while_statement(&mut self) -> Result<()> {
+        self.lex()?;
+        let jmptrg = self.fs.get_current_pos();
+        let block = self.begin_breakable_block();
+        let old = self.begin_scope();
+        self.statement(true)?;
+        self.end_scope(old);
+        self.expect(Token::While)?;
+        let continuetrg = self.fs.get_current_pos();
+        self.expect(Token::Char(b'('))?;
+        self.comma_expr()?;
+        self.expect(Token::Char(b')'))?;
+        let cond = self.fs.pop_target();
+        self.fs.add_instruction(Opcode::JZ, cond, 1, 0, 0);
+        let pos = self.fs.get_current_pos();
+        self.fs
+            .add_instruction(Opcode::JMP, 0, jmptrg - pos - 1, 0, 0);
+        self.end_breakable_block(block, continuetrg);
+        Ok(())
+    }
+
+    fn for_statement(&mut self) -> Result<()> {
+        self.lex()?;
+        let old = self.begin_scope();
+        self.expect(Token::Char(b'('))?;
+        if self.token == Token::Local {
+            self.local_decl_statement()?;
+        } else if self.token != Token::Char(b';') {
+            self.comma_expr()?;
+            self.fs.pop_target();
+        }
+        self.expect(Token::Char(b';'))?;
+        self.fs.snooze_opt();
+        let jmppos = self.fs.get_current_pos();
+        let mut jzpos = -1;
+        if self.token != Token::Char(b';') {
+            self.comma_expr()?;
+            let cond = self.fs.pop_target();
+            self.fs.add_instruction(Opcode::JZ, cond, 0, 0, 0);
+            jzpos = self.fs.get_current_pos();
+        }
+        self.expect(Token::Char(b';'))?;
+        self.fs.snooze_opt();
+        let expstart = self.fs.get_current_pos() + 1;
+        if self.token != Token::Char(b')') {
+            self.comma_expr()?;
+            self.fs.pop_target();
+        }
+        self.expect(Token::Char(b')'))?;
+        self.fs.snooze_opt();
+        let expend = self.fs.get_current_pos();
+        let expsize = (expend - expstart) + 1;
+        // the increment expression is moved behind the loop body
+        let exp: Vec<_> = (0..expsize)
+            .map(|i| self.fs.get_instruction(expstart + i))
+            .collect();
+        if expsize > 0 {
+            self.fs.pop_instructions(expsize);
+        }
+        let block = self.begin_breakable_block();
+        self.statement(true)?;
+        let continuetrg = self.fs.get_current_pos();
+        for i in exp {
+            self.fs.add_instr(i);
+        }
+        let pos = self.fs.get_current_pos();
+        self.fs
+            .add_instruction(Opcode::JMP, 0, jmppos - pos - 1, 0, 0);
+        if jzpos > 0 {
+            let pos = self.fs.get_current_pos();
+            self.fs.set_instruction_param(jzpos, 1, pos - jzpos);
+        }
+        self.end_scope(old);
+
+        self.end_breakable_block(block, continuetrg);
+        Ok(())
+    }
+
+    fn foreach_statement(&mut self) -> Result<()> {
+        self.lex()?;
+        self.expect(Token::Char(b'('))?;
+        let mut valname = self.expect(Token::Identifier)?;
+        let idxname = if self.token == Token::Char(b',') {
+            self.lex()?;
+            std::mem::replace(&mut valname, self.expect(Token::Identifier)?)
+        } else {
+            Object::new_string("@INDEX@")
+        };
+        self.expect(Token::In)?;
+
+        // save the stack size
+        let old = self.begin_scope();
+        // put the container in the stack (evaluate the container expression)
+        self.expression()?;
+        self.expect(Token::Char(b')'))?;
+        let container = self.fs.top_target();
+        // push the index local var
+        let indexpos = self.fs.push_local_variable(idxname);
+        self.fs
+            .add_instruction(Opcode::LOADNULLS, indexpos, 1, 0, 0);
+        // push the value local var
+        let valuepos = self.fs.push_local_variable(valname);
+        self.fs
+            .add_instruction(Opcode::LOADNULLS, valuepos, 1, 0, 0);
+        // push reference index; uses an invalid id to make it inaccessible
+        let itrpos = self
+            .fs
+            .push_local_variable(Object::new_string("@ITERATOR@"));
+        self.fs.add_instruction(Opcode::LOADNULLS, itrpos, 1, 0, 0);
+        let jmppos = self.fs.get_current_pos();
+        self.fs
+            .add_instruction(Opcode::FOREACH, container, 0, indexpos, 0);
+        let foreachpos = self.fs.get_current_pos();
+        self.fs
+            .add_instruction(Opcode::POSTFOREACH, container, 0, indexpos, 0);
+        // generate the statement code
+        let block = self.begin_breakable_block();
+        self.statement(true)?;
+        let pos = self.fs.get_current_pos();
+        self.fs
+            .add_instruction(Opcode::JMP, 0, jmppos - pos - 1, 0, 0);
+        let pos = self.fs.get_current_pos();
+        self.fs
+            .set_instruction_param(foreachpos, 1, pos - foreachpos);
+        self.fs
+            .set_instruction_param(foreachpos + 1, 1, pos - foreachpos);
+        self.end_breakable_block(block, foreachpos - 1);
+        // restore the local variable stack (remove index, val and ref idx)
+        self.fs.pop_target();
+        self.end_scope(old);
+        Ok(())
+    }
+
+    fn switch_statement(&mut self) -> Result<()> {
+        self.lex()?;
+        self.expect(Token::Char(b'('))?;
+        self.comma_expr()?;
+        self.expect(Token::Char(b')'))?;
+        self.expect(Token::Char(b'{'))?;
+        let expr = self.fs.top_target();
+        let mut bfirst = true;
+        let mut tonextcondjmp = -1;
+        let mut skipcondjmp = -1;
+        let nbreaks = self.fs.unresolved_breaks.len();
+        self.fs.break_targets.push(0);
+        while self.token == Token::Case {
+            if !bfirst {
+                self.fs.add_instruction(Opcode::JMP, 0, 0, 0, 0);
+                skipcondjmp = self.fs.get_current_pos();
+                self.fs
+                    .set_instruction_param(tonextcondjmp, 1, skipcondjmp - tonextcondjmp);
+            }
+            // condition
+            self.lex()?;
+            self.expression()?;
+            self.expect(Token::Char(b':'))?;
+            let trg = self.fs.pop_target();
+            let mut eqtarget = trg;
+            let local = self.fs.is_local(trg as usize);
+            if local {
+                // we need to allocate an extra reg
+                eqtarget = self.fs.push_target();
+            }
+            self.fs.add_instruction(Opcode::EQ, eqtarget, trg, expr, 0);
+            self.fs.add_instruction(Opcode::JZ, eqtarget, 0, 0, 0);
+            if local {
+                self.fs.pop_target();
+            }
+
+            // end condition
+            if skipcondjmp != -1 {
+                let pos = self.fs.get_current_pos();
+                self.fs
+                    .set_instruction_param(skipcondjmp, 1, pos - skipcondjmp);
+            }
+            tonextcondjmp = self.fs.get_current_pos();
+            let old = self.begin_scope();
+            self.statements()?;
+            self.end_scope(old);
+            bfirst = false;
+        }
+        if tonextcondjmp != -1 {
+            let pos = self.fs.get_current_pos();
+            self.fs
+                .set_instruction_param(tonextcondjmp, 1, pos - tonextcondjmp);
+        }
+        if self.token == Token::Default {
+            self.lex()?;
+            self.expect(Token::Char(b':'))?;
+            let old = self.begin_scope();
+            self.statements()?;
+            self.end_scope(old);
+        }
+        self.expect(Token::Char(b'}'))?;
+        self.fs.pop_target();
+        let nbreaks = self.fs.unresolved_breaks.len() - nbreaks;
+        if nbreaks > 0 {
+            self.resolve_breaks(nbreaks);
+        }
+        self.fs.break_targets.pop();
+        Ok(())
+    }
+
+    fn function_statement(&mut self) -> Result<()> {
+        self.lex()?;
+        let mut id = self.expect(Token::Identifier)?;
+        self.fs.push_target_at(0);
+        let trg = self.fs.push_target();
+        let constant = self.fs.get_constant(id.clone());
+        self.fs.add_instruction(Opcode::LOAD, trg, constant, 0, 0);
+        if self.token == Token::DoubleColon {
+            self.emit_2args_op(Opcode::GET, 0);
+        }
+
+        while self.token == Token::DoubleColon {
+            self.lex()?;
+            id = self.expect(Token::Identifier)?;
+            let trg = self.fs.push_target();
+            let constant = self.fs.get_constant(id.clone());
+            self.fs.add_instruction(Opcode::LOAD, trg, constant, 0, 0);
+            if self.token == Token::DoubleColon {
+                self.emit_2args_op(Opcode::GET, 0);
+            }
+        }
+        self.expect(Token::Char(b'('))?;
+        self.create_function(id, false)?;
+        let trg = self.fs.push_target();
+        let nfunc = self.fs.functions.len() as isize - 1;
+        self.fs.add_instruction(Opcode::CLOSURE, trg, nfunc, 0, 0);
+        self.emit_deref_op(Opcode::NEWSLOT);
+        self.fs.pop_target();
+        Ok(())
+    }
+
+    fn class_statement(&mut self) -> Result<()> {
+        self.lex()?;
+        let es = self.es;
+        self.es.donot_get = true;
+        self.prefixed_expr()?;
+        match self.es.etype {
+            ExpType::Expr => return self.error("invalid class name"),
+            ExpType::Object | ExpType::Base => {
+                self.class_exp()?;
+                self.emit_deref_op(Opcode::NEWSLOT);
+                self.fs.pop_target();
+            }
+            _ => {
+                return self
+                    .error("cannot create a class in a local with the syntax(class <local>)")
+            }
+        }
+        self.es = es;
+        Ok(())
+    }
+
+    fn expect_scalar(&mut self) -> Result<Object> {
+        let val = match self.token {
+            Token::Integer => Object::Integer(self.lex.nvalue),
+            Token::Float => Object::Float(self.lex.fvalue),
+            Token::StringLiteral => Object::new_string(&self.lex.svalue),
+            Token::True | Token::False => Object::Bool(self.token == Token::True),
+            Token::Char(b'-') => {
+                self.lex()?;
+                match self.token {
+                    Token::Integer => Object::Integer(self.lex.nvalue.wrapping_neg()),
+                    Token::Float => Object::Float(-self.lex.fvalue),
+                    _ => return self.error("scalar expected : integer, float"),
+                }
+            }
+            _ => return self.error("scalar expected : integer, float or string"),
+        };
+        self.lex()?;
+        Ok(val)
+    }
+
+    fn enum_statement(&mut self) -> Result<()> {
+        self.lex()?;
+        let id = self.expect(Token::Identifier)?;
+        self.expect(Token::Char(b'{'))?;
+
+        let mut table = Object::new_table();
+        let mut nval = 0;
+        while self.token != Token::Char(b'}') {
+            let key = self.expect(Token::Identifier)?;
+            let val = if self.token == Token::Char(b'=') {
+                self.lex()?;
+                self.expect_scalar()?
+            } else {
+                nval += 1;
+                Object::Integer(nval - 1)
+            };
+            table.table_mut()?.map.insert(key, val);
+            if self.token == Token::Char(b',') {
+                self.lex()?;
+            }
+        }
+        self.consts.insert(id, table);
+        self.lex()
+    }
+
+    fn try_catch_statement(&mut self) -> Result<()> {
+        self.lex()?;
+        self.fs.add_instruction(Opcode::PUSHTRAP, 0, 0, 0, 0);
+        self.fs.traps += 1;
+        if let Some(n) = self.fs.break_targets.last_mut() {
+            *n += 1;
+        }
+        if let Some(n) = self.fs.continue_targets.last_mut() {
+            *n += 1;
+        }
+        let trappos = self.fs.get_current_pos();
+        {
+            let old = self.begin_scope();
+            self.statement(true)?;
+            self.end_scope(old);
+        }
+        self.fs.traps -= 1;
+        self.fs.add_instruction(Opcode::POPTRAP, 1, 0, 0, 0);
+        if let Some(n) = self.fs.break_targets.last_mut() {
+            *n -= 1;
+        }
+        if let Some(n) = self.fs.continue_targets.last_mut() {
+            *n -= 1;
+        }
+        self.fs.add_instruction(Opcode::JMP, 0, 0, 0, 0);
+        let jmppos = self.fs.get_current_pos();
+        self.fs.set_instruction_param(trappos, 1, jmppos - trappos);
+        self.expect(Token::Catch)?;
+        self.expect(Token::Char(b'('))?;
+        let exid = self.expect(Token::Identifier)?;
+        self.expect(Token::Char(b')'))?;
+        {
+            let old = self.begin_scope();
+            let ex_target = self.fs.push_local_variable(exid);
+            self.fs.set_instruction_param(trappos, 0, ex_target);
+            self.statement(true)?;
+            let pos = self.fs.get_current_pos();
+            self.fs
+                .set_instruction_params(jmppos, 0, pos - jmppos, 0, 0);
+            self.end_scope(old);
+        }
+        Ok(())
+    }
+
+    fn function_exp(&mut self, lambda: bool) -> Result<()> {
+        self.lex()?;
+        self.expect(Token::Char(b'('))?;
+        self.create_function(Object::Null, lambda)?;
+        let trg = self.fs.push_target();
+        let nfunc = self.fs.functions.len() as isize - 1;
+        self.fs
+            .add_instruction(Opcode::CLOSURE, trg, nfunc, lambda as isize, 0);
+        Ok(())
+    }
+
+    fn class_exp(&mut self) -> Result<()> {
+        let mut base = -1;
+        let mut attrs = -1;
+        if self.token == Token::Extends {
+            self.lex()?;
+            self.expression()?;
+            base = self.fs.top_target();
+        }
+        if self.token == Token::AttrOpen {
+            self.lex()?;
+            let trg = self.fs.push_target();
+            self.fs
+                .add_instruction(Opcode::NEWOBJ, trg, 0, 0, NewObjectType::TABLE as isize);
+            self.parse_table_or_class(Token::Char(b','), Token::AttrClose)?;
+            attrs = self.fs.top_target();
+        }
+        self.expect(Token::Char(b'{'))?;
+        if attrs != -1 {
+            self.fs.pop_target();
+        }
+        if base != -1 {
+            self.fs.pop_target();
+        }
+        let trg = self.fs.push_target();
+        self.fs.add_instruction(
+            Opcode::NEWOBJ,
+            trg,
+            base,
+            attrs,
+            NewObjectType::CLASS as isize,
+        );
+        self.parse_table_or_class(Token::Char(b';'), Token::Char(b'}'))
+    }
+
+    fn delete_expr(&mut self) -> Result<()> {
+        self.lex()?;
+        let es = self.es;
+        self.es.donot_get = true;
+        self.prefixed_expr()?;
+        match self.es.etype {
+            ExpType::Expr => return self.error("can't delete an expression"),
+            ExpType::Object | ExpType::Base => self.emit_2args_op(Opcode::DELETE, 0),
+            _ => return self.error("cannot delete an (outer) local"),
+        }
+        self.es = es;
+        Ok(())
+    }
+
+    fn prefix_inc_dec(&mut self, token: Token) -> Result<()> {
+        let diff = if token == Token::MinusMinus { -1 } else { 1 };
+        self.lex()?;
+        let es = self.es;
+        self.es.donot_get = true;
+        self.prefixed_expr()?;
+        match self.es.etype {
+            ExpType::Expr => return self.error("can't '++' or '--' an expression"),
+            ExpType::Object | ExpType::Base => self.emit_2args_op(Opcode::INC, diff),
+            ExpType::Local => {
+                let src = self.fs.top_target();
+                self.fs.add_instruction(Opcode::INCL, src, src, 0, diff);
+            }
+            ExpType::Outer => {
+                let tmp = self.fs.push_target();
+                let epos = self.es.epos;
+                self.fs.add_instruction(Opcode::GETOUTER, tmp, epos, 0, 0);
+                self.fs.add_instruction(Opcode::INCL, tmp, tmp, 0, diff);
+                self.fs.add_instruction(Opcode::SETOUTER, tmp, epos, tmp, 0);
+            }
+        }
+        self.es = es;
+        Ok(())
+    }
+
+    fn create_function(&mut self, name: Object, lambda: bool) -> Result<()> {
+        let mut funcstate = FuncState::new(self.source_name.clone(), true);
+        funcstate.name = name;
+        funcstate.add_parameter(Object::new_string("this"));
+        let mut defparams = 0;
+        while self.token != Token::Char(b')') {
+            if self.token == Token::VarParams {
+                if defparams > 0 {
+                    return self.error(
+                        "function with default parameters cannot have variable number of parameters",
+                    );
+                }
+                funcstate.add_parameter(Object::new_string("vargv"));
+                funcstate.varparams = true;
+                self.lex()?;
+                if self.token != Token::Char(b')') {
+                    return self.error("expected ')'");
+                }
+                break;
+            } else {
+                let paramname = self.expect(Token::Identifier)?;
+                funcstate.add_parameter(paramname);
+                if self.token == Token::Char(b'=') {
+                    // default values are evaluated in the enclosing function
+                    self.lex()?;
+                    self.expression()?;
+                    funcstate.add_default_param(self.fs.top_target());
+                    defparams += 1;
+                } else if defparams > 0 {
+                    return self.error("expected '='");
+                }
+                if self.token == Token::Char(b',') {
+                    self.lex()?;
+                } else if self.token != Token::Char(b')') {
+                    return self.error("expected ')' or ','");
+                }
+            }
+        }
+        self.expect(Token::Char(b')'))?;
+        for _ in 0..defparams {
+            self.fs.pop_target();
+        }
+
+        let parent = std::mem::replace(&mut self.fs, funcstate);
+        self.parents.push(parent);
+        if lambda {
+            self.expression()?;
+            let trg = self.fs.pop_target();
+            self.fs.add_instruction(Opcode::RETURN, 1, trg, 0, 0);
+        } else {
+            self.statement(false)?;
+        }
+        let line = if self.lex.prev_token == Token::Char(b'\n') {
+            self.lex.last_token_line
+        } else {
+            self.lex.current_line
+        };
+        self.fs.add_line_infos(line as isize, true);
+        self.fs
+            .add_instruction(Opcode::RETURN, MAX_FUNC_STACKSIZE, 0, 0, 0);
+        self.fs.set_stack_size(0);
+
+        let parent = self.parents.pop().unwrap();
+        let funcstate = std::mem::replace(&mut self.fs, parent);
+        self.fs.functions.push(new_func_proto(funcstate));
+        Ok(())
+    }
+}
+
+fn arith_op_by_token(tok: Token) -> Opcode {
+    match tok {
+        Token::Char(b'+') | Token::PlusEq => Opcode::ADD,
+        Token::Char(b'-') | Token::MinusEq => Opcode::SUB,
+        Token::Char(b'*') | Token::MulEq => Opcode::MUL,
+        Token::Char(b'/') | Token::DivEq => Opcode::DIV,
+        Token::Char(b'%') | Token::ModEq => Opcode::MOD,
+        _ => unreachable!(),
+    }
+}
+
+fn comp_arith_char_by_token(tok: Token) -> u8 {
+    match tok {
+        Token::MinusEq => b'-',
+        Token::PlusEq => b'+',
+        Token::MulEq => b'*',
+        Token::DivEq => b'/',
+        Token::ModEq => b'%',
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile_str;
+    use crate::io::read_closure;
+    use crate::object::FuncProto;
+    use crate::vm::tests::run;
+    use crate::vm::Executor;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn assert_same_proto(compiled: &FuncProto, reference: &FuncProto) {
+        let name = format!("{:?}", reference.name);
+        assert_eq!(compiled.source_name, reference.source_name, "{}", name);
+        assert_eq!(format!("{:?}", compiled.name), name);
+        assert_eq!(
+            format!("{:?}", compiled.literals),
+            format!("{:?}", reference.literals),
+            "{}",
+            name
+        );
+        assert_eq!(compiled.parameters, reference.parameters, "{}", name);
+        assert_eq!(
+            format!("{:?}", compiled.outervalues),
+            format!("{:?}", reference.outervalues),
+            "{}",
+            name
+        );
+        assert_eq!(
+            format!("{:?}", compiled.localvarinfos),
+            format!("{:?}", reference.localvarinfos),
+            "{}",
+            name
+        );
+        assert_eq!(compiled.lineinfos, reference.lineinfos, "{}", name);
+        assert_eq!(compiled.defaultparams, reference.defaultparams, "{}", name);
+        assert_eq!(
+            format!("{:?}", compiled.instructions),
+            format!("{:?}", reference.instructions),
+            "{}",
+            name
+        );
+        assert_eq!(compiled.stacksize, reference.stacksize, "{}", name);
+        assert_eq!(
+            compiled.functions.len(),
+            reference.functions.len(),
+            "{}",
+            name
+        );
+        for (c, r) in compiled.functions.iter().zip(reference.functions.iter()) {
+            assert_same_proto(c.func_proto_ref().unwrap(), r.func_proto_ref().unwrap());
+        }
+    }
+
+    fn compare_with_reference(source: &str, source_name: &str, mut cnut: &[u8]) {
+        let compiled = compile_str(source, source_name).unwrap();
+        let reference = read_closure(&mut cnut).unwrap();
+        assert_same_proto(
+            compiled
+                .closure()
+                .unwrap()
+                .func_proto
+                .func_proto_ref()
+                .unwrap(),
+            reference
+                .closure()
+                .unwrap()
+                .func_proto
+                .func_proto_ref()
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn same_bytecode_as_reference() {
+        compare_with_reference(
+            include_str!("factorial.nut"),
+            "factorial.nut",
+            include_bytes!("out.cnut"),
+        );
+        macro_rules! example {
+            ($name:expr) => {
+                compare_with_reference(
+                    include_str!(concat!("../examples/", $name, ".nut")),
+                    concat!($name, ".nut"),
+                    include_bytes!(concat!("../examples/", $name, ".cnut")),
+                );
+            };
+        }
+        example!("ackermann");
+        example!("delegation");
+        example!("factorial");
+        example!("flow");
+        example!("loops");
+        example!("tailcall");
+        example!("test");
+    }
+
+    #[test]
+    fn run_compiled() {
+        let output = Rc::new(RefCell::new(String::new()));
+        let mut exec = Executor::new();
+        let print_output = output.clone();
+        exec.add_native_func(
+            "print",
+            crate::native_closure(
+                Box::new(move |ctx| {
+                    print_output.borrow_mut().push_str(&ctx.arg(0)?.to_string());
+                    Ok(crate::Object::Null)
+                }),
+                2,
+            ),
+        )
+        .unwrap();
+        run(&mut exec, include_str!("../examples/flow.nut"));
+        assert_eq!(
+            *output.borrow(),
+            "I'm useless statement just to show up the if/else\n\na is a number\nb is a container\nc is other stuff\n"
+        );
+    }
+}
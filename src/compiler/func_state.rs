@@ -0,0 +1,488 @@
+use crate::bytecode::{AppendArrayType, Instruction, Opcode, OUTER_TYPE_LOCAL, OUTER_TYPE_OUTER};
+use crate::{object, types, Object};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub const MAX_FUNC_STACKSIZE: isize = 0xFF;
+
+// literals are deduplicated by value. Floats are keyed by their bit pattern since they are not
+// hashable.
+#[derive(PartialEq, Eq, Hash)]
+enum LiteralKey {
+    Integer(types::Integer),
+    Float(u64),
+    String(Rc<object::Str>),
+}
+
+impl LiteralKey {
+    // types::Float is f64 with the double feature
+    #[allow(clippy::useless_conversion)]
+    fn new(obj: &Object) -> LiteralKey {
+        match obj {
+            Object::Integer(i) => LiteralKey::Integer(*i),
+            Object::Float(f) => LiteralKey::Float(f64::from(*f).to_bits()),
+            Object::String(s) => LiteralKey::String(s.clone()),
+            _ => panic!("unsupported literal type {}", obj.type_name()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LocalVarInfo {
+    pub name: Object,
+    pub start_op: isize,
+    pub end_op: isize,
+    pub pos: isize,
+    // set when a nested function captured the variable
+    pub outer: bool,
+}
+
+pub struct FuncState {
+    pub name: Object,
+    pub source_name: Object,
+    pub has_parent: bool,
+
+    literal_map: HashMap<LiteralKey, usize>,
+    literals: Vec<Object>,
+    pub parameters: Vec<Object>,
+    pub outervalues: Vec<(types::Integer, Object, Object)>,
+    localvarinfos: Vec<LocalVarInfo>,
+    lineinfos: Vec<(types::Integer, types::Integer)>,
+    defaultparams: Vec<types::Integer>,
+    instructions: Vec<Instruction>,
+    pub functions: Vec<Object>,
+
+    vlocals: Vec<LocalVarInfo>,
+    targetstack: Vec<isize>,
+    stacksize: isize,
+    lastline: isize,
+    optimization: bool,
+
+    pub varparams: bool,
+    pub bgenerator: bool,
+    pub traps: isize,
+    pub outers: isize,
+    pub returnexp: isize,
+
+    pub unresolved_breaks: Vec<isize>,
+    pub unresolved_continues: Vec<isize>,
+    pub break_targets: Vec<isize>,
+    pub continue_targets: Vec<isize>,
+}
+
+impl FuncState {
+    pub fn new(source_name: Object, has_parent: bool) -> FuncState {
+        FuncState {
+            name: Object::Null,
+            source_name,
+            has_parent,
+            literal_map: HashMap::new(),
+            literals: Vec::new(),
+            parameters: Vec::new(),
+            outervalues: Vec::new(),
+            localvarinfos: Vec::new(),
+            lineinfos: Vec::new(),
+            defaultparams: Vec::new(),
+            instructions: Vec::new(),
+            functions: Vec::new(),
+            vlocals: Vec::new(),
+            targetstack: Vec::new(),
+            stacksize: 0,
+            lastline: 0,
+            optimization: true,
+            varparams: false,
+            bgenerator: false,
+            traps: 0,
+            outers: 0,
+            returnexp: 0,
+            unresolved_breaks: Vec::new(),
+            unresolved_continues: Vec::new(),
+            break_targets: Vec::new(),
+            continue_targets: Vec::new(),
+        }
+    }
+
+    pub fn get_constant(&mut self, cons: Object) -> isize {
+        let key = LiteralKey::new(&cons);
+        let literals = &mut self.literals;
+        *self.literal_map.entry(key).or_insert_with(|| {
+            literals.push(cons);
+            literals.len() - 1
+        }) as isize
+    }
+
+    pub fn get_current_pos(&self) -> isize {
+        self.instructions.len() as isize - 1
+    }
+
+    pub fn get_instruction(&self, pos: isize) -> Instruction {
+        self.instructions[pos as usize].clone()
+    }
+
+    pub fn pop_instructions(&mut self, size: isize) {
+        let len = self.instructions.len() - size as usize;
+        self.instructions.truncate(len);
+    }
+
+    pub fn set_instruction_params(
+        &mut self,
+        pos: isize,
+        arg0: isize,
+        arg1: isize,
+        arg2: isize,
+        arg3: isize,
+    ) {
+        let i = &mut self.instructions[pos as usize];
+        i.arg0 = arg0 as u8;
+        i.arg1 = arg1 as i32;
+        i.arg2 = arg2 as u8;
+        i.arg3 = arg3 as u8;
+    }
+
+    pub fn set_instruction_param(&mut self, pos: isize, arg: usize, val: isize) {
+        let i = &mut self.instructions[pos as usize];
+        match arg {
+            0 => i.arg0 = val as u8,
+            1 => i.arg1 = val as i32,
+            2 => i.arg2 = val as u8,
+            3 => i.arg3 = val as u8,
+            _ => panic!("invalid instruction parameter {}", arg),
+        }
+    }
+
+    fn alloc_stack_pos(&mut self) -> isize {
+        let npos = self.vlocals.len() as isize;
+        self.vlocals.push(LocalVarInfo {
+            name: Object::Null,
+            start_op: 0,
+            end_op: 0,
+            pos: 0,
+            outer: false,
+        });
+        if self.vlocals.len() as isize > self.stacksize {
+            self.stacksize = self.vlocals.len() as isize;
+        }
+        npos
+    }
+
+    // push a new temporary on the target stack
+    pub fn push_target(&mut self) -> isize {
+        let n = self.alloc_stack_pos();
+        self.targetstack.push(n);
+        n
+    }
+
+    // push an already allocated stack position (e.g. a local) on the target stack
+    pub fn push_target_at(&mut self, n: isize) -> isize {
+        self.targetstack.push(n);
+        n
+    }
+
+    pub fn top_target(&self) -> isize {
+        *self.targetstack.last().unwrap()
+    }
+
+    pub fn pop_target(&mut self) -> isize {
+        let npos = self.targetstack.pop().unwrap();
+        if let Object::Null = self.vlocals[npos as usize].name {
+            self.vlocals.pop();
+        }
+        npos
+    }
+
+    pub fn get_stack_size(&self) -> isize {
+        self.vlocals.len() as isize
+    }
+
+    pub fn count_outers(&self, stacksize: isize) -> isize {
+        self.vlocals[stacksize as usize..]
+            .iter()
+            .filter(|lvi| lvi.outer)
+            .count() as isize
+    }
+
+    pub fn set_stack_size(&mut self, n: isize) {
+        while self.vlocals.len() as isize > n {
+            let mut lvi = self.vlocals.pop().unwrap();
+            if let Object::Null = lvi.name {
+                continue;
+            }
+            if lvi.outer {
+                self.outers -= 1;
+            }
+            lvi.end_op = self.get_current_pos();
+            self.localvarinfos.push(lvi);
+        }
+    }
+
+    pub fn is_local(&self, stkpos: usize) -> bool {
+        match self.vlocals.get(stkpos) {
+            Some(lvi) => !matches!(lvi.name, Object::Null),
+            None => false,
+        }
+    }
+
+    pub fn push_local_variable(&mut self, name: Object) -> isize {
+        let pos = self.vlocals.len() as isize;
+        self.vlocals.push(LocalVarInfo {
+            name,
+            start_op: self.get_current_pos() + 1,
+            end_op: 0,
+            pos,
+            outer: false,
+        });
+        if self.vlocals.len() as isize > self.stacksize {
+            self.stacksize = self.vlocals.len() as isize;
+        }
+        pos
+    }
+
+    pub fn get_local_variable(&self, name: &Object) -> Option<isize> {
+        self.vlocals
+            .iter()
+            .rposition(|lvi| matches!(lvi.name, Object::String(_)) && lvi.name == *name)
+            .map(|pos| pos as isize)
+    }
+
+    pub fn mark_local_as_outer(&mut self, pos: isize) {
+        self.vlocals[pos as usize].outer = true;
+        self.outers += 1;
+    }
+
+    // looks up `name` as a free variable, registering it in the outer values of every function
+    // between its declaration and this one. `parents` are the enclosing functions, innermost last.
+    pub fn get_outer_variable(
+        &mut self,
+        parents: &mut [FuncState],
+        name: &Object,
+    ) -> Option<isize> {
+        if let Some(i) = self.outervalues.iter().position(|(_, _, n)| n == name) {
+            return Some(i as isize);
+        }
+        let (parent, parents) = parents.split_last_mut()?;
+        let (outer_type, pos) = match parent.get_local_variable(name) {
+            Some(pos) => {
+                parent.mark_local_as_outer(pos);
+                (OUTER_TYPE_LOCAL, pos)
+            }
+            None => (OUTER_TYPE_OUTER, parent.get_outer_variable(parents, name)?),
+        };
+        self.outervalues.push((
+            outer_type,
+            Object::Integer(pos as types::Integer),
+            name.clone(),
+        ));
+        Some(self.outervalues.len() as isize - 1)
+    }
+
+    pub fn add_parameter(&mut self, name: Object) {
+        self.push_local_variable(name.clone());
+        self.parameters.push(name);
+    }
+
+    pub fn add_default_param(&mut self, trg: isize) {
+        self.defaultparams.push(trg as types::Integer);
+    }
+
+    pub fn add_line_infos(&mut self, line: isize, force: bool) {
+        if self.lastline != line || force {
+            if self.lastline != line {
+                self.lineinfos.push((
+                    line as types::Integer,
+                    (self.get_current_pos() + 1) as types::Integer,
+                ));
+            }
+            self.lastline = line;
+        }
+    }
+
+    pub fn snooze_opt(&mut self) {
+        self.optimization = false;
+    }
+
+    pub fn discard_target(&mut self) {
+        let discarded = self.pop_target();
+        if !self.optimization {
+            return;
+        }
+        if let Some(pi) = self.instructions.last_mut() {
+            let op = pi.opcode;
+            if (op == Opcode::SET as u8
+                || op == Opcode::NEWSLOT as u8
+                || op == Opcode::SETOUTER as u8
+                || op == Opcode::CALL as u8)
+                && pi.arg0 as isize == discarded
+            {
+                pi.arg0 = 0xFF;
+            }
+        }
+    }
+
+    pub fn add_instruction(
+        &mut self,
+        op: Opcode,
+        arg0: isize,
+        arg1: isize,
+        arg2: isize,
+        arg3: isize,
+    ) {
+        self.add_instr(Instruction {
+            arg1: arg1 as i32,
+            opcode: op as u8,
+            arg0: arg0 as u8,
+            arg2: arg2 as u8,
+            arg3: arg3 as u8,
+        });
+    }
+
+    // appends an instruction, merging it with the previous one where possible (the peephole
+    // optimizer of the reference implementation)
+    pub fn add_instr(&mut self, mut i: Instruction) {
+        let size = self.instructions.len();
+        if size > 0 && self.optimization {
+            let pi_is_local = self.is_local(self.instructions[size - 1].arg0 as usize);
+            let pi = &mut self.instructions[size - 1];
+            let op = i.opcode;
+            if op == Opcode::JZ as u8 {
+                if pi.opcode == Opcode::CMP as u8 && pi.arg1 < 0xFF {
+                    pi.opcode = Opcode::JCMP as u8;
+                    pi.arg0 = pi.arg1 as u8;
+                    pi.arg1 = i.arg1;
+                    return;
+                }
+            } else if op == Opcode::SET as u8 || op == Opcode::NEWSLOT as u8 {
+                if i.arg0 == i.arg3 {
+                    i.arg0 = 0xFF;
+                }
+            } else if op == Opcode::SETOUTER as u8 {
+                if i.arg0 == i.arg2 {
+                    i.arg0 = 0xFF;
+                }
+            } else if op == Opcode::RETURN as u8 {
+                if self.has_parent
+                    && i.arg0 as isize != MAX_FUNC_STACKSIZE
+                    && pi.opcode == Opcode::CALL as u8
+                    && self.returnexp < size as isize - 1
+                {
+                    pi.opcode = Opcode::TAILCALL as u8;
+                } else if pi.opcode == Opcode::CLOSE as u8 {
+                    *pi = i;
+                    return;
+                }
+            } else if op == Opcode::GET as u8 {
+                if pi.opcode == Opcode::LOAD as u8 && pi.arg0 == i.arg2 && !pi_is_local {
+                    pi.arg2 = i.arg1 as u8;
+                    pi.opcode = Opcode::GETK as u8;
+                    pi.arg0 = i.arg0;
+                    return;
+                }
+            } else if op == Opcode::PREPCALL as u8 {
+                if pi.opcode == Opcode::LOAD as u8 && pi.arg0 as i32 == i.arg1 && !pi_is_local {
+                    pi.opcode = Opcode::PREPCALLK as u8;
+                    pi.arg0 = i.arg0;
+                    pi.arg2 = i.arg2;
+                    pi.arg3 = i.arg3;
+                    return;
+                }
+            } else if op == Opcode::APPENDARRAY as u8 {
+                let aat = match pi.opcode {
+                    x if x == Opcode::LOAD as u8 => Some(AppendArrayType::LITERAL),
+                    x if x == Opcode::LOADINT as u8 => Some(AppendArrayType::INT),
+                    x if x == Opcode::LOADBOOL as u8 => Some(AppendArrayType::BOOL),
+                    x if x == Opcode::LOADFLOAT as u8 => Some(AppendArrayType::FLOAT),
+                    _ => None,
+                };
+                if let Some(aat) = aat {
+                    if pi.arg0 as i32 == i.arg1 && !pi_is_local {
+                        pi.opcode = Opcode::APPENDARRAY as u8;
+                        pi.arg0 = i.arg0;
+                        pi.arg2 = aat as u8;
+                        pi.arg3 = MAX_FUNC_STACKSIZE as u8;
+                        return;
+                    }
+                }
+            } else if op == Opcode::MOVE as u8 {
+                let retargetable = [
+                    Opcode::GET,
+                    Opcode::ADD,
+                    Opcode::SUB,
+                    Opcode::MUL,
+                    Opcode::DIV,
+                    Opcode::MOD,
+                    Opcode::BITW,
+                    Opcode::LOADINT,
+                    Opcode::LOADFLOAT,
+                    Opcode::LOADBOOL,
+                    Opcode::LOAD,
+                ];
+                if retargetable.iter().any(|o| *o as u8 == pi.opcode) && pi.arg0 as i32 == i.arg1 {
+                    pi.arg0 = i.arg0;
+                    self.optimization = false;
+                    return;
+                }
+                if pi.opcode == Opcode::MOVE as u8 {
+                    pi.opcode = Opcode::DMOVE as u8;
+                    pi.arg2 = i.arg0;
+                    pi.arg3 = i.arg1 as u8;
+                    return;
+                }
+            } else if op == Opcode::LOAD as u8 {
+                if pi.opcode == Opcode::LOAD as u8 && i.arg1 < 256 {
+                    pi.opcode = Opcode::DLOAD as u8;
+                    pi.arg2 = i.arg0;
+                    pi.arg3 = i.arg1 as u8;
+                    return;
+                }
+            } else if op == Opcode::EQ as u8 || op == Opcode::NE as u8 {
+                if pi.opcode == Opcode::LOAD as u8 && pi.arg0 as i32 == i.arg1 && !pi_is_local {
+                    pi.opcode = i.opcode;
+                    pi.arg0 = i.arg0;
+                    pi.arg2 = i.arg2;
+                    pi.arg3 = MAX_FUNC_STACKSIZE as u8;
+                    return;
+                }
+            } else if op == Opcode::LOADNULLS as u8
+                && pi.opcode == Opcode::LOADNULLS as u8
+                && pi.arg0 as i32 + pi.arg1 == i.arg0 as i32
+            {
+                pi.arg1 += 1;
+                return;
+            }
+        }
+        self.optimization = true;
+        self.instructions.push(i);
+    }
+
+    pub fn build_proto(self) -> object::FuncProto {
+        object::FuncProto {
+            source_name: self.source_name,
+            name: self.name,
+            literals: self.literals,
+            parameters: self.parameters,
+            outervalues: self.outervalues,
+            localvarinfos: self
+                .localvarinfos
+                .into_iter()
+                .map(|lvi| {
+                    (
+                        lvi.name,
+                        lvi.pos as types::Integer,
+                        lvi.start_op as types::Integer,
+                        lvi.end_op as types::Integer,
+                    )
+                })
+                .collect(),
+            lineinfos: self.lineinfos,
+            defaultparams: self.defaultparams,
+            instructions: self.instructions,
+            functions: self.functions,
+            stacksize: self.stacksize as types::Integer,
+            bgenerator: self.bgenerator,
+            varparams: self.varparams,
+        }
+    }
+}
+
+pub fn new_func_proto(fs: FuncState) -> Object {
+    Object::FuncProto(Rc::new(fs.build_proto()))
+}
@@ -0,0 +1,619 @@
+use crate::{types, Error, Result};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Token {
+    // single character tokens ('{', '+', '\n', ...)
+    Char(u8),
+    Identifier,
+    StringLiteral,
+    Integer,
+    Float,
+    Base,
+    Delete,
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Switch,
+    And,
+    Or,
+    If,
+    Else,
+    While,
+    Break,
+    For,
+    Do,
+    Null,
+    Foreach,
+    In,
+    NewSlot,
+    Local,
+    Clone,
+    Function,
+    Return,
+    Typeof,
+    PlusEq,
+    MinusEq,
+    Continue,
+    Yield,
+    Try,
+    Catch,
+    Throw,
+    ShiftL,
+    ShiftR,
+    Resume,
+    DoubleColon,
+    Case,
+    Default,
+    This,
+    PlusPlus,
+    MinusMinus,
+    ThreeWayCmp,
+    UShiftR,
+    Class,
+    Extends,
+    Constructor,
+    InstanceOf,
+    VarParams,
+    Line,
+    File,
+    True,
+    False,
+    MulEq,
+    DivEq,
+    ModEq,
+    AttrOpen,
+    AttrClose,
+    Static,
+    Enum,
+    Const,
+    Eof,
+}
+
+const KEYWORDS: &[(&str, Token)] = &[
+    ("while", Token::While),
+    ("do", Token::Do),
+    ("if", Token::If),
+    ("else", Token::Else),
+    ("break", Token::Break),
+    ("continue", Token::Continue),
+    ("return", Token::Return),
+    ("null", Token::Null),
+    ("function", Token::Function),
+    ("local", Token::Local),
+    ("for", Token::For),
+    ("foreach", Token::Foreach),
+    ("in", Token::In),
+    ("typeof", Token::Typeof),
+    ("base", Token::Base),
+    ("delete", Token::Delete),
+    ("try", Token::Try),
+    ("catch", Token::Catch),
+    ("throw", Token::Throw),
+    ("clone", Token::Clone),
+    ("yield", Token::Yield),
+    ("resume", Token::Resume),
+    ("switch", Token::Switch),
+    ("case", Token::Case),
+    ("default", Token::Default),
+    ("this", Token::This),
+    ("class", Token::Class),
+    ("extends", Token::Extends),
+    ("constructor", Token::Constructor),
+    ("instanceof", Token::InstanceOf),
+    ("true", Token::True),
+    ("false", Token::False),
+    ("static", Token::Static),
+    ("enum", Token::Enum),
+    ("const", Token::Const),
+    ("__LINE__", Token::Line),
+    ("__FILE__", Token::File),
+];
+
+impl Token {
+    pub fn name(self) -> String {
+        match self {
+            Token::Char(c) => format!("{}", c as char),
+            Token::Identifier => "IDENTIFIER".to_string(),
+            Token::StringLiteral => "STRING_LITERAL".to_string(),
+            Token::Integer => "INTEGER".to_string(),
+            Token::Float => "FLOAT".to_string(),
+            Token::Eq => "==".to_string(),
+            Token::Ne => "!=".to_string(),
+            Token::Le => "<=".to_string(),
+            Token::Ge => ">=".to_string(),
+            Token::And => "&&".to_string(),
+            Token::Or => "||".to_string(),
+            Token::NewSlot => "<-".to_string(),
+            Token::PlusEq => "+=".to_string(),
+            Token::MinusEq => "-=".to_string(),
+            Token::MulEq => "*=".to_string(),
+            Token::DivEq => "/=".to_string(),
+            Token::ModEq => "%=".to_string(),
+            Token::ShiftL => "<<".to_string(),
+            Token::ShiftR => ">>".to_string(),
+            Token::UShiftR => ">>>".to_string(),
+            Token::DoubleColon => "::".to_string(),
+            Token::PlusPlus => "++".to_string(),
+            Token::MinusMinus => "--".to_string(),
+            Token::ThreeWayCmp => "<=>".to_string(),
+            Token::VarParams => "...".to_string(),
+            Token::AttrOpen => "</".to_string(),
+            Token::AttrClose => "/>".to_string(),
+            Token::Eof => "end of file".to_string(),
+            _ => KEYWORDS
+                .iter()
+                .find(|(_, tok)| *tok == self)
+                .map(|(name, _)| name.to_string())
+                .unwrap_or_else(|| format!("{:?}", self)),
+        }
+    }
+}
+
+const EOB: u8 = 0;
+
+pub struct Lexer<'a> {
+    src: &'a [u8],
+    pos: usize,
+    cur_char: u8,
+
+    pub current_line: usize,
+    pub current_column: usize,
+    pub last_token_line: usize,
+    pub prev_token: Token,
+    cur_token: Token,
+
+    pub svalue: String,
+    pub nvalue: types::Integer,
+    pub fvalue: types::Float,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a [u8]) -> Lexer<'a> {
+        let mut lexer = Lexer {
+            src,
+            pos: 0,
+            cur_char: EOB,
+            current_line: 1,
+            current_column: 0,
+            last_token_line: 1,
+            prev_token: Token::Eof,
+            cur_token: Token::Eof,
+            svalue: String::new(),
+            nvalue: 0,
+            fvalue: 0.0,
+        };
+        lexer.next();
+        lexer
+    }
+
+    fn next(&mut self) {
+        self.cur_char = match self.src.get(self.pos) {
+            Some(c) => {
+                self.pos += 1;
+                *c
+            }
+            None => EOB,
+        };
+        self.current_column += 1;
+    }
+
+    // the position is added by the compiler
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        Err(Error::CompileError(msg.to_string()))
+    }
+
+    fn token(&mut self, tok: Token) -> Result<Token> {
+        self.prev_token = self.cur_token;
+        self.cur_token = tok;
+        Ok(tok)
+    }
+
+    fn next_token(&mut self, tok: Token) -> Result<Token> {
+        self.next();
+        self.token(tok)
+    }
+
+    pub fn lex(&mut self) -> Result<Token> {
+        self.last_token_line = self.current_line;
+        while self.cur_char != EOB {
+            match self.cur_char {
+                b'\t' | b'\r' | b' ' => self.next(),
+                b'\n' => {
+                    self.current_line += 1;
+                    self.prev_token = self.cur_token;
+                    self.cur_token = Token::Char(b'\n');
+                    self.next();
+                    self.current_column = 1;
+                }
+                b'#' => self.lex_line_comment(),
+                b'/' => {
+                    self.next();
+                    match self.cur_char {
+                        b'*' => {
+                            self.next();
+                            self.lex_block_comment()?;
+                        }
+                        b'/' => self.lex_line_comment(),
+                        b'=' => return self.next_token(Token::DivEq),
+                        b'>' => return self.next_token(Token::AttrClose),
+                        _ => return self.token(Token::Char(b'/')),
+                    }
+                }
+                b'=' => {
+                    self.next();
+                    if self.cur_char != b'=' {
+                        return self.token(Token::Char(b'='));
+                    }
+                    return self.next_token(Token::Eq);
+                }
+                b'<' => {
+                    self.next();
+                    return match self.cur_char {
+                        b'=' => {
+                            self.next();
+                            if self.cur_char == b'>' {
+                                return self.next_token(Token::ThreeWayCmp);
+                            }
+                            self.token(Token::Le)
+                        }
+                        b'-' => self.next_token(Token::NewSlot),
+                        b'<' => self.next_token(Token::ShiftL),
+                        b'/' => self.next_token(Token::AttrOpen),
+                        _ => self.token(Token::Char(b'<')),
+                    };
+                }
+                b'>' => {
+                    self.next();
+                    return match self.cur_char {
+                        b'=' => self.next_token(Token::Ge),
+                        b'>' => {
+                            self.next();
+                            if self.cur_char == b'>' {
+                                return self.next_token(Token::UShiftR);
+                            }
+                            self.token(Token::ShiftR)
+                        }
+                        _ => self.token(Token::Char(b'>')),
+                    };
+                }
+                b'!' => {
+                    self.next();
+                    if self.cur_char != b'=' {
+                        return self.token(Token::Char(b'!'));
+                    }
+                    return self.next_token(Token::Ne);
+                }
+                b'@' => {
+                    self.next();
+                    if self.cur_char != b'"' {
+                        return self.token(Token::Char(b'@'));
+                    }
+                    let tok = self.read_string(b'"', true)?;
+                    return self.token(tok);
+                }
+                b'"' | b'\'' => {
+                    let tok = self.read_string(self.cur_char, false)?;
+                    return self.token(tok);
+                }
+                b'{' | b'}' | b'(' | b')' | b'[' | b']' | b';' | b',' | b'?' | b'^' | b'~' => {
+                    let c = self.cur_char;
+                    return self.next_token(Token::Char(c));
+                }
+                b'.' => {
+                    self.next();
+                    if self.cur_char != b'.' {
+                        return self.token(Token::Char(b'.'));
+                    }
+                    self.next();
+                    if self.cur_char != b'.' {
+                        return self.error("invalid token '..'");
+                    }
+                    return self.next_token(Token::VarParams);
+                }
+                b'&' => {
+                    self.next();
+                    if self.cur_char != b'&' {
+                        return self.token(Token::Char(b'&'));
+                    }
+                    return self.next_token(Token::And);
+                }
+                b'|' => {
+                    self.next();
+                    if self.cur_char != b'|' {
+                        return self.token(Token::Char(b'|'));
+                    }
+                    return self.next_token(Token::Or);
+                }
+                b':' => {
+                    self.next();
+                    if self.cur_char != b':' {
+                        return self.token(Token::Char(b':'));
+                    }
+                    return self.next_token(Token::DoubleColon);
+                }
+                b'*' => {
+                    self.next();
+                    if self.cur_char == b'=' {
+                        return self.next_token(Token::MulEq);
+                    }
+                    return self.token(Token::Char(b'*'));
+                }
+                b'%' => {
+                    self.next();
+                    if self.cur_char == b'=' {
+                        return self.next_token(Token::ModEq);
+                    }
+                    return self.token(Token::Char(b'%'));
+                }
+                b'-' => {
+                    self.next();
+                    return match self.cur_char {
+                        b'=' => self.next_token(Token::MinusEq),
+                        b'-' => self.next_token(Token::MinusMinus),
+                        _ => self.token(Token::Char(b'-')),
+                    };
+                }
+                b'+' => {
+                    self.next();
+                    return match self.cur_char {
+                        b'=' => self.next_token(Token::PlusEq),
+                        b'+' => self.next_token(Token::PlusPlus),
+                        _ => self.token(Token::Char(b'+')),
+                    };
+                }
+                c if c.is_ascii_digit() => {
+                    let tok = self.read_number()?;
+                    return self.token(tok);
+                }
+                c if c.is_ascii_alphabetic() || c == b'_' => {
+                    let tok = self.read_id();
+                    return self.token(tok);
+                }
+                c => {
+                    if c.is_ascii_control() {
+                        return self.error("unexpected character(control)");
+                    }
+                    return self.next_token(Token::Char(c));
+                }
+            }
+        }
+        // like the reference lexer, the end of the buffer does not count as a token
+        Ok(Token::Eof)
+    }
+
+    fn lex_line_comment(&mut self) {
+        while self.cur_char != b'\n' && self.cur_char != EOB {
+            self.next();
+        }
+    }
+
+    fn lex_block_comment(&mut self) -> Result<()> {
+        loop {
+            match self.cur_char {
+                b'*' => {
+                    self.next();
+                    if self.cur_char == b'/' {
+                        self.next();
+                        return Ok(());
+                    }
+                }
+                b'\n' => {
+                    self.current_line += 1;
+                    self.next();
+                }
+                EOB => return self.error("missing \"*/\" in comment"),
+                _ => self.next(),
+            }
+        }
+    }
+
+    fn read_id(&mut self) -> Token {
+        let mut id = String::new();
+        loop {
+            id.push(self.cur_char as char);
+            self.next();
+            if !(self.cur_char.is_ascii_alphanumeric() || self.cur_char == b'_') {
+                break;
+            }
+        }
+        let tok = KEYWORDS
+            .iter()
+            .find(|(name, _)| *name == id)
+            .map(|(_, tok)| *tok)
+            .unwrap_or(Token::Identifier);
+        if tok == Token::Identifier || tok == Token::Constructor {
+            self.svalue = id;
+        }
+        tok
+    }
+
+    fn read_string(&mut self, delim: u8, verbatim: bool) -> Result<Token> {
+        let mut buf = Vec::new();
+        self.next();
+        loop {
+            while self.cur_char != delim {
+                match self.cur_char {
+                    EOB => return self.error("unfinished string"),
+                    b'\n' => {
+                        if !verbatim {
+                            return self.error("newline in a constant");
+                        }
+                        buf.push(self.cur_char);
+                        self.next();
+                        self.current_line += 1;
+                    }
+                    b'\\' if verbatim => {
+                        buf.push(b'\\');
+                        self.next();
+                    }
+                    b'\\' => {
+                        self.next();
+                        let c = match self.cur_char {
+                            b'x' => {
+                                self.next();
+                                if !self.cur_char.is_ascii_hexdigit() {
+                                    return self.error("hexadecimal number expected");
+                                }
+                                let mut value = 0u32;
+                                let mut digits = 0;
+                                while self.cur_char.is_ascii_hexdigit() && digits < 4 {
+                                    value =
+                                        value * 16 + (self.cur_char as char).to_digit(16).unwrap();
+                                    digits += 1;
+                                    self.next();
+                                }
+                                let mut utf8 = [0u8; 4];
+                                let encoded = char::from(value as u8).encode_utf8(&mut utf8);
+                                buf.extend_from_slice(encoded.as_bytes());
+                                continue;
+                            }
+                            b't' => b'\t',
+                            b'a' => 0x07,
+                            b'b' => 0x08,
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b'v' => 0x0b,
+                            b'f' => 0x0c,
+                            b'0' => 0,
+                            b'\\' => b'\\',
+                            b'"' => b'"',
+                            b'\'' => b'\'',
+                            _ => return self.error("unrecognised escaper char"),
+                        };
+                        buf.push(c);
+                        self.next();
+                    }
+                    c => {
+                        buf.push(c);
+                        self.next();
+                    }
+                }
+            }
+            self.next();
+            if verbatim && self.cur_char == b'"' {
+                // double quotation
+                buf.push(self.cur_char);
+                self.next();
+            } else {
+                break;
+            }
+        }
+
+        if delim == b'\'' {
+            return match buf.len() {
+                0 => self.error("empty constant"),
+                1 => {
+                    self.nvalue = buf[0] as types::Integer;
+                    Ok(Token::Integer)
+                }
+                _ => self.error("constant too long"),
+            };
+        }
+
+        self.svalue = match String::from_utf8(buf) {
+            Ok(s) => s,
+            Err(err) => return self.error(&format!("failed to decode utf8: {}", err)),
+        };
+        Ok(Token::StringLiteral)
+    }
+
+    fn read_number(&mut self) -> Result<Token> {
+        enum NumberType {
+            Int,
+            Float,
+            Hex,
+            Octal,
+        }
+        let mut ntype = NumberType::Int;
+        let firstchar = self.cur_char;
+        let mut buf = String::new();
+
+        self.next();
+        if firstchar == b'0'
+            && (self.cur_char.eq_ignore_ascii_case(&b'X') || is_odigit(self.cur_char))
+        {
+            if is_odigit(self.cur_char) {
+                ntype = NumberType::Octal;
+                while is_odigit(self.cur_char) {
+                    buf.push(self.cur_char as char);
+                    self.next();
+                }
+                if self.cur_char.is_ascii_digit() {
+                    return self.error("invalid octal number");
+                }
+            } else {
+                self.next();
+                ntype = NumberType::Hex;
+                while self.cur_char.is_ascii_hexdigit() {
+                    buf.push(self.cur_char as char);
+                    self.next();
+                }
+                if buf.len() > std::mem::size_of::<types::Integer>() * 2 {
+                    return self.error("too many digits for an Hex number");
+                }
+            }
+        } else {
+            buf.push(firstchar as char);
+            while self.cur_char == b'.'
+                || self.cur_char.is_ascii_digit()
+                || is_exponent(self.cur_char)
+            {
+                if self.cur_char == b'.' || is_exponent(self.cur_char) {
+                    ntype = NumberType::Float;
+                }
+                if is_exponent(self.cur_char) {
+                    buf.push(self.cur_char as char);
+                    self.next();
+                    if self.cur_char == b'+' || self.cur_char == b'-' {
+                        buf.push(self.cur_char as char);
+                        self.next();
+                    }
+                    if !self.cur_char.is_ascii_digit() {
+                        return self.error("exponent expected");
+                    }
+                }
+                buf.push(self.cur_char as char);
+                self.next();
+            }
+        }
+
+        match ntype {
+            NumberType::Float => match buf.parse::<f64>() {
+                Ok(f) => {
+                    self.fvalue = f as types::Float;
+                    Ok(Token::Float)
+                }
+                Err(_) => self.error("invalid numeric format"),
+            },
+            NumberType::Int => {
+                self.nvalue = lex_integer(&buf, 10);
+                Ok(Token::Integer)
+            }
+            NumberType::Hex => {
+                self.nvalue = lex_integer(&buf, 16);
+                Ok(Token::Integer)
+            }
+            NumberType::Octal => {
+                self.nvalue = lex_integer(&buf, 8);
+                Ok(Token::Integer)
+            }
+        }
+    }
+}
+
+fn is_odigit(c: u8) -> bool {
+    (b'0'..=b'7').contains(&c)
+}
+
+fn is_exponent(c: u8) -> bool {
+    c == b'e' || c == b'E'
+}
+
+// integer literals wrap around like the reference lexer
+fn lex_integer(digits: &str, radix: u32) -> types::Integer {
+    digits.chars().fold(0 as types::Integer, |acc, c| {
+        acc.wrapping_mul(radix as types::Integer)
+            .wrapping_add(c.to_digit(radix).unwrap() as types::Integer)
+    })
+}
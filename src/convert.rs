@@ -0,0 +1,451 @@
+use crate::object::{self, NativeClosure, NativeFn};
+use crate::{gc, raw_type, types, Error, Object, Result};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// type mask accepting any value
+pub const ANY_TYPE: isize = -1;
+
+// conversion of script values into rust values. Values like &str borrow from the object.
+pub trait FromObject<'a>: Sized {
+    // the raw types accepted by from_object. Used for the parameter check of bound functions.
+    const TYPE_MASK: isize;
+    fn from_object(obj: &'a Object) -> Result<Self>;
+}
+
+// conversion of rust values into script values
+pub trait IntoObject {
+    fn into_object(self) -> Object;
+}
+
+fn conversion_error(obj: &Object, expected: &str) -> Error {
+    Error::RuntimeError(format!(
+        "expected {}. found {}",
+        expected,
+        obj.typesystem_name()
+    ))
+}
+
+impl FromObject<'_> for Object {
+    const TYPE_MASK: isize = ANY_TYPE;
+    fn from_object(obj: &Object) -> Result<Self> {
+        Ok(obj.clone())
+    }
+}
+
+impl IntoObject for Object {
+    fn into_object(self) -> Object {
+        self
+    }
+}
+
+impl IntoObject for () {
+    fn into_object(self) -> Object {
+        Object::Null
+    }
+}
+
+// the casts depend on the sizes selected by the cargo features
+macro_rules! impl_number {
+    ($t:ty, $variant:ident, $native:ty) => {
+        #[allow(clippy::unnecessary_cast)]
+        impl FromObject<'_> for $t {
+            const TYPE_MASK: isize = raw_type::INTEGER | raw_type::FLOAT;
+            fn from_object(obj: &Object) -> Result<Self> {
+                match obj {
+                    Object::Integer(i) => Ok(*i as $t),
+                    Object::Float(f) => Ok(*f as $t),
+                    _ => Err(conversion_error(obj, "number")),
+                }
+            }
+        }
+
+        #[allow(clippy::unnecessary_cast)]
+        impl IntoObject for $t {
+            fn into_object(self) -> Object {
+                Object::$variant(self as $native)
+            }
+        }
+    };
+}
+
+impl_number!(i32, Integer, types::Integer);
+impl_number!(i64, Integer, types::Integer);
+impl_number!(f32, Float, types::Float);
+impl_number!(f64, Float, types::Float);
+
+impl FromObject<'_> for bool {
+    const TYPE_MASK: isize = raw_type::BOOL;
+    fn from_object(obj: &Object) -> Result<Self> {
+        match obj {
+            Object::Bool(b) => Ok(*b),
+            _ => Err(conversion_error(obj, "bool")),
+        }
+    }
+}
+
+impl IntoObject for bool {
+    fn into_object(self) -> Object {
+        Object::Bool(self)
+    }
+}
+
+impl FromObject<'_> for String {
+    const TYPE_MASK: isize = raw_type::STRING;
+    fn from_object(obj: &Object) -> Result<Self> {
+        match obj {
+            Object::String(s) => Ok(s.to_string()),
+            _ => Err(conversion_error(obj, "string")),
+        }
+    }
+}
+
+impl IntoObject for String {
+    fn into_object(self) -> Object {
+        Object::String(Rc::new(object::Str::new(self)))
+    }
+}
+
+impl<'a> FromObject<'a> for &'a str {
+    const TYPE_MASK: isize = raw_type::STRING;
+    fn from_object(obj: &'a Object) -> Result<Self> {
+        match obj {
+            Object::String(s) => Ok(s),
+            _ => Err(conversion_error(obj, "string")),
+        }
+    }
+}
+
+impl IntoObject for &str {
+    fn into_object(self) -> Object {
+        Object::new_string(self)
+    }
+}
+
+// the elements are converted while the array is borrowed, so they can't borrow from it
+impl<T: for<'a> FromObject<'a>> FromObject<'_> for Vec<T> {
+    const TYPE_MASK: isize = raw_type::ARRAY;
+    fn from_object(obj: &Object) -> Result<Self> {
+        match obj {
+            Object::Array(array) => array.borrow().array.iter().map(T::from_object).collect(),
+            _ => Err(conversion_error(obj, "array")),
+        }
+    }
+}
+
+impl<T: IntoObject> IntoObject for Vec<T> {
+    fn into_object(self) -> Object {
+        let array = self.into_iter().map(T::into_object).collect();
+        gc::track(Object::Array(Rc::new(RefCell::new(
+            object::Array::with_values(array),
+        ))))
+    }
+}
+
+impl<T: for<'a> FromObject<'a>> FromObject<'_> for HashMap<String, T> {
+    const TYPE_MASK: isize = raw_type::TABLE;
+    fn from_object(obj: &Object) -> Result<Self> {
+        match obj {
+            Object::Table(table) => table
+                .borrow()
+                .map
+                .iter()
+                .map(|(key, value)| Ok((String::from_object(key)?, T::from_object(value)?)))
+                .collect(),
+            _ => Err(conversion_error(obj, "table")),
+        }
+    }
+}
+
+impl<T: IntoObject> IntoObject for HashMap<String, T> {
+    fn into_object(self) -> Object {
+        let mut table = object::Table::new();
+        for (key, value) in self {
+            table.map.insert(key.into_object(), value.into_object());
+        }
+        gc::track(Object::Table(Rc::new(RefCell::new(table))))
+    }
+}
+
+impl<'a, T: FromObject<'a>> FromObject<'a> for Option<T> {
+    const TYPE_MASK: isize = T::TYPE_MASK | raw_type::NULL;
+    fn from_object(obj: &'a Object) -> Result<Self> {
+        match obj {
+            Object::Null => Ok(None),
+            _ => T::from_object(obj).map(Some),
+        }
+    }
+}
+
+impl<T: IntoObject> IntoObject for Option<T> {
+    fn into_object(self) -> Object {
+        match self {
+            Some(value) => value.into_object(),
+            None => Object::Null,
+        }
+    }
+}
+
+// compiles a parameter type mask in the format of sq_setparamscheck, e.g. "tn|s". The
+// first entry is the mask of `this`.
+pub fn compile_typemask(typemask: &str) -> Result<Vec<isize>> {
+    let mut res = Vec::new();
+    let mut mask = 0;
+    let mut alternative = false;
+    for c in typemask.chars() {
+        let t = match c {
+            ' ' => continue,
+            '|' => {
+                if mask == 0 {
+                    return Err(Error::RuntimeError("invalid typemask".to_string()));
+                }
+                alternative = true;
+                continue;
+            }
+            'o' => raw_type::NULL,
+            'i' => raw_type::INTEGER,
+            'f' => raw_type::FLOAT,
+            'n' => raw_type::INTEGER | raw_type::FLOAT,
+            's' => raw_type::STRING,
+            't' => raw_type::TABLE,
+            'a' => raw_type::ARRAY,
+            'u' => raw_type::USERDATA,
+            'c' => raw_type::CLOSURE | raw_type::NATIVECLOSURE,
+            'b' => raw_type::BOOL,
+            'g' => raw_type::GENERATOR,
+            'p' => raw_type::USERPOINTER,
+            'v' => raw_type::THREAD,
+            'x' => raw_type::INSTANCE,
+            'y' => raw_type::CLASS,
+            'r' => raw_type::WEAKREF,
+            '.' => ANY_TYPE,
+            _ => {
+                return Err(Error::RuntimeError(format!(
+                    "invalid typemask character '{}'",
+                    c
+                )))
+            }
+        };
+        if alternative {
+            mask |= t;
+            alternative = false;
+        } else {
+            if mask != 0 {
+                res.push(mask);
+            }
+            mask = t;
+        }
+    }
+    if alternative {
+        return Err(Error::RuntimeError("invalid typemask".to_string()));
+    }
+    if mask != 0 {
+        res.push(mask);
+    }
+    Ok(res)
+}
+
+// parameters of functions registered with Executor::add_native_func. Item is the type the
+// function takes, which can borrow from the argument, as &str does.
+pub trait NativeArg {
+    type Item<'a>;
+    // the raw types accepted by from_arg
+    const TYPE_MASK: isize;
+    fn from_arg(obj: &Object) -> Result<Self::Item<'_>>;
+}
+
+macro_rules! impl_owned_arg {
+    ($($t:ty),*) => {
+        $(
+            impl NativeArg for $t {
+                type Item<'a> = $t;
+                const TYPE_MASK: isize = <$t as FromObject>::TYPE_MASK;
+                fn from_arg(obj: &Object) -> Result<$t> {
+                    <$t>::from_object(obj)
+                }
+            }
+        )*
+    };
+}
+
+impl_owned_arg!(Object, i32, i64, f32, f64, bool, String);
+
+impl NativeArg for &str {
+    type Item<'a> = &'a str;
+    const TYPE_MASK: isize = raw_type::STRING;
+    fn from_arg(obj: &Object) -> Result<&str> {
+        <&str>::from_object(obj)
+    }
+}
+
+impl<T: for<'a> FromObject<'a>> NativeArg for Vec<T> {
+    type Item<'a> = Vec<T>;
+    const TYPE_MASK: isize = raw_type::ARRAY;
+    fn from_arg(obj: &Object) -> Result<Vec<T>> {
+        Vec::from_object(obj)
+    }
+}
+
+impl<T: for<'a> FromObject<'a>> NativeArg for HashMap<String, T> {
+    type Item<'a> = HashMap<String, T>;
+    const TYPE_MASK: isize = raw_type::TABLE;
+    fn from_arg(obj: &Object) -> Result<HashMap<String, T>> {
+        HashMap::from_object(obj)
+    }
+}
+
+impl<T: NativeArg> NativeArg for Option<T> {
+    type Item<'a> = Option<T::Item<'a>>;
+    const TYPE_MASK: isize = T::TYPE_MASK | raw_type::NULL;
+    fn from_arg(obj: &Object) -> Result<Self::Item<'_>> {
+        match obj {
+            Object::Null => Ok(None),
+            _ => T::from_arg(obj).map(Some),
+        }
+    }
+}
+
+// functions that can be registered with Executor::add_native_func. Args is the tuple of
+// the parameter types and only serves to tell the implementations apart.
+pub trait IntoNativeFunc<Args> {
+    fn into_native_func(self) -> Object;
+}
+
+impl IntoNativeFunc<Object> for Object {
+    fn into_native_func(self) -> Object {
+        self
+    }
+}
+
+macro_rules! impl_native_func {
+    ($($arg:ident),*) => {
+        // the second Fn bound takes the parameters borrowing from the arguments, the first
+        // one names their types for the impl
+        impl<F, R, $($arg,)*> IntoNativeFunc<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> Result<R>
+                + Fn($(<$arg as NativeArg>::Item<'_>),*) -> Result<R>
+                + 'static,
+            R: IntoObject,
+            $($arg: NativeArg,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables, unused_assignments)]
+            fn into_native_func(self) -> Object {
+                // calling through a function that only knows the second bound
+                fn call<R, $($arg,)*>(f: &impl Fn($($arg),*) -> Result<R>, $($arg: $arg),*) -> Result<R> {
+                    f($($arg),*)
+                }
+                let typemask = vec![ANY_TYPE, $(<$arg as NativeArg>::TYPE_MASK,)*];
+                let nargs = typemask.len() as types::Integer;
+                let func: NativeFn = Box::new(move |ctx| {
+                    let mut n = 0;
+                    $(
+                        let $arg = $arg::from_arg(ctx.arg(n)?)?;
+                        n += 1;
+                    )*
+                    call(&self, $($arg),*).map(R::into_object)
+                });
+                let mut closure = NativeClosure::new(func, nargs);
+                closure.typemask = typemask;
+                Object::NativeClosure(Rc::new(closure))
+            }
+        }
+    };
+}
+
+impl_native_func!();
+impl_native_func!(A1);
+impl_native_func!(A1, A2);
+impl_native_func!(A1, A2, A3);
+impl_native_func!(A1, A2, A3, A4);
+impl_native_func!(A1, A2, A3, A4, A5);
+impl_native_func!(A1, A2, A3, A4, A5, A6);
+
+#[cfg(test)]
+mod tests {
+    use super::{compile_typemask, FromObject, IntoObject};
+    use crate::vm::tests::{run, strings};
+    use crate::vm::Executor;
+    use crate::{raw_type, Error, Object, Result};
+    use std::collections::HashMap;
+
+    #[test]
+    fn conversions() {
+        let values = vec![vec![1_i64, 2], vec![3]];
+        assert_eq!(
+            Vec::<Vec<i64>>::from_object(&values.clone().into_object()).unwrap(),
+            values
+        );
+        let mut map = HashMap::new();
+        map.insert("a".to_string(), Some(1.5_f64));
+        map.insert("b".to_string(), None);
+        assert_eq!(
+            HashMap::<String, Option<f64>>::from_object(&map.clone().into_object()).unwrap(),
+            map
+        );
+        assert_eq!(f32::from_object(&Object::Integer(2)).unwrap(), 2.0);
+        assert!(bool::from_object(&"x".into_object()).is_err());
+        let text = "text".into_object();
+        assert_eq!(<&str>::from_object(&text).unwrap(), "text");
+        assert_eq!(Option::<&str>::from_object(&Object::Null).unwrap(), None);
+        assert!(<&str>::from_object(&Object::Integer(1)).is_err());
+        assert_eq!(
+            compile_typemask("t n|s .").unwrap(),
+            [
+                raw_type::TABLE,
+                raw_type::INTEGER | raw_type::FLOAT | raw_type::STRING,
+                -1
+            ]
+        );
+        assert!(compile_typemask("t|").is_err());
+    }
+
+    #[test]
+    fn bind_closures() {
+        let source = "
+            local errors = [];
+            try { repeat(\"x\", \"y\"); } catch (e) { errors.append(e); }
+            try { repeat(\"x\"); } catch (e) { errors.append(e); }
+            try { checked(-1); } catch (e) { errors.append(e); }
+            try { shout(1); } catch (e) { errors.append(e); }
+            return [repeat(\"ab\", 3), sum([1, 2.5, 3]), checked(2), checked(null),
+                shout(\"hey\"), greet(\"bob\"), greet(null), errors[0], errors[1], errors[2],
+                errors[3]];
+        ";
+        let mut exec = Executor::new();
+        exec.add_native_func("repeat", |s: String, n: i64| -> Result<String> {
+            Ok(s.repeat(n as usize))
+        })
+        .unwrap();
+        exec.add_native_func("sum", |v: Vec<f64>| Ok(v.iter().sum::<f64>()))
+            .unwrap();
+        exec.add_native_func("checked", |n: Option<i64>| match n {
+            Some(n) if n < 0 => Err(Error::RuntimeError("negative".to_string())),
+            n => Ok(n.map(|n| n * 10)),
+        })
+        .unwrap();
+        exec.add_native_func("shout", |s: &str| Ok(s.to_uppercase()))
+            .unwrap();
+        exec.add_native_func("greet", |name: Option<&str>| {
+            Ok(format!("hello {}", name.unwrap_or("world")))
+        })
+        .unwrap();
+        assert_eq!(
+            strings(run(&mut exec, source)),
+            [
+                "ababab",
+                "6.5",
+                "20",
+                "null",
+                "HEY",
+                "hello bob",
+                "hello world",
+                "parameter 2 has an invalid type 'string' ; expected: 'integer|float'",
+                "wrong number of parameters",
+                "negative",
+                "parameter 1 has an invalid type 'integer' ; expected: 'string'"
+            ]
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use crate::{object, Object};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+// objects that can be part of reference cycles. They are registered when created so that
+// cycles which are no longer referenced from outside can be found. Threads and native
+// closures are not tracked, the objects they reference are never collected.
+enum Tracked {
+    Table(Weak<RefCell<object::Table>>),
+    Array(Weak<RefCell<object::Array>>),
+    Closure(Weak<object::Closure>),
+    Outer(Weak<RefCell<object::Outer>>),
+    Class(Weak<RefCell<object::Class>>),
+    Instance(Weak<RefCell<object::Instance>>),
+    Generator(Weak<RefCell<object::Generator>>),
+    UserData(Weak<RefCell<object::UserData>>),
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Object> {
+        match self {
+            Tracked::Table(t) => t.upgrade().map(Object::Table),
+            Tracked::Array(a) => a.upgrade().map(Object::Array),
+            Tracked::Closure(c) => c.upgrade().map(Object::Closure),
+            Tracked::Outer(o) => o.upgrade().map(Object::Outer),
+            Tracked::Class(c) => c.upgrade().map(Object::Class),
+            Tracked::Instance(i) => i.upgrade().map(Object::Instance),
+            Tracked::Generator(g) => g.upgrade().map(Object::Generator),
+            Tracked::UserData(u) => u.upgrade().map(Object::UserData),
+        }
+    }
+}
+
+// the objects created while an executor runs. Every executor has its own registry, so
+// collecting garbage only looks at the objects of that executor.
+pub(crate) struct Registry {
+    tracked: RefCell<Vec<Tracked>>,
+    // the registry is pruned of released objects when it grows beyond this size
+    prune_at: Cell<usize>,
+}
+
+thread_local! {
+    // the registries of the executors running on this thread, innermost last
+    static ACTIVE: RefCell<Vec<Rc<Registry>>> = const { RefCell::new(Vec::new()) };
+}
+
+// keeps a registry active until it is dropped
+pub(crate) struct Activation;
+
+impl Drop for Activation {
+    fn drop(&mut self) {
+        ACTIVE.with(|active| active.borrow_mut().pop());
+    }
+}
+
+// registers a newly created object with the registry of the running executor. Objects
+// created while no executor runs are not tracked.
+pub(crate) fn track(obj: Object) -> Object {
+    ACTIVE.with(|active| {
+        if let Some(registry) = active.borrow().last() {
+            registry.add(&obj);
+        }
+    });
+    obj
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry {
+            tracked: RefCell::new(Vec::new()),
+            prune_at: Cell::new(1024),
+        }
+    }
+}
+
+impl Registry {
+    // objects created until the activation is dropped are tracked by this registry
+    pub(crate) fn activate(self: &Rc<Self>) -> Activation {
+        ACTIVE.with(|active| active.borrow_mut().push(self.clone()));
+        Activation
+    }
+
+    pub(crate) fn add(&self, obj: &Object) {
+        let tracked = match obj {
+            Object::Table(t) => Tracked::Table(Rc::downgrade(t)),
+            Object::Array(a) => Tracked::Array(Rc::downgrade(a)),
+            Object::Closure(c) => Tracked::Closure(Rc::downgrade(c)),
+            Object::Outer(o) => Tracked::Outer(Rc::downgrade(o)),
+            Object::Class(c) => Tracked::Class(Rc::downgrade(c)),
+            Object::Instance(i) => Tracked::Instance(Rc::downgrade(i)),
+            Object::Generator(g) => Tracked::Generator(Rc::downgrade(g)),
+            Object::UserData(u) => Tracked::UserData(Rc::downgrade(u)),
+            _ => return,
+        };
+        let mut tracked_objects = self.tracked.borrow_mut();
+        tracked_objects.push(tracked);
+        if tracked_objects.len() >= self.prune_at.get() {
+            tracked_objects.retain(|t| t.upgrade().is_some());
+            self.prune_at.set((tracked_objects.len() * 2).max(1024));
+        }
+    }
+
+    // finds the tracked objects that are only referenced by other unreachable objects. An
+    // object is reachable if it has references from outside the tracked objects, e.g. from
+    // the stack, the root table or the host, or if it is referenced by a reachable object.
+    pub(crate) fn unreachable(&self) -> Vec<Object> {
+        let objects: Vec<Object> = {
+            let mut tracked_objects = self.tracked.borrow_mut();
+            tracked_objects.retain(|t| t.upgrade().is_some());
+            tracked_objects
+                .iter()
+                .filter_map(Tracked::upgrade)
+                .collect()
+        };
+        unreachable(objects)
+    }
+
+    // releases the contents of the unreachable objects, breaking the cycles between them.
+    // Returns the number of released objects.
+    pub(crate) fn collect_garbage(&self) -> usize {
+        release(self.unreachable())
+    }
+}
+
+fn id(obj: &Object) -> Option<usize> {
+    Some(match obj {
+        Object::Table(t) => Rc::as_ptr(t) as *const () as usize,
+        Object::Array(a) => Rc::as_ptr(a) as *const () as usize,
+        Object::Closure(c) => Rc::as_ptr(c) as *const () as usize,
+        Object::Outer(o) => Rc::as_ptr(o) as *const () as usize,
+        Object::Class(c) => Rc::as_ptr(c) as *const () as usize,
+        Object::Instance(i) => Rc::as_ptr(i) as *const () as usize,
+        Object::Generator(g) => Rc::as_ptr(g) as *const () as usize,
+        Object::UserData(u) => Rc::as_ptr(u) as *const () as usize,
+        _ => return None,
+    })
+}
+
+fn strong_count(obj: &Object) -> usize {
+    match obj {
+        Object::Table(t) => Rc::strong_count(t),
+        Object::Array(a) => Rc::strong_count(a),
+        Object::Closure(c) => Rc::strong_count(c),
+        Object::Outer(o) => Rc::strong_count(o),
+        Object::Class(c) => Rc::strong_count(c),
+        Object::Instance(i) => Rc::strong_count(i),
+        Object::Generator(g) => Rc::strong_count(g),
+        Object::UserData(u) => Rc::strong_count(u),
+        _ => 0,
+    }
+}
+
+// calls f for every reference held by obj. Objects that are borrowed at the moment are
+// skipped, which keeps everything they reference alive.
+fn references(obj: &Object, f: &mut dyn FnMut(&Object)) {
+    match obj {
+        Object::Table(t) => {
+            if let Ok(t) = t.try_borrow() {
+                for (key, value) in &t.map {
+                    f(key);
+                    f(value);
+                }
+                t.delegate.iter().for_each(f);
+            }
+        }
+        Object::Array(a) => {
+            if let Ok(a) = a.try_borrow() {
+                a.array.iter().for_each(f);
+            }
+        }
+        Object::Closure(c) => {
+            c.outervalues.iter().for_each(&mut *f);
+            c.defaultparams.iter().for_each(&mut *f);
+            c.base.iter().for_each(f);
+        }
+        Object::Outer(o) => {
+            if let Ok(o) = o.try_borrow() {
+                if let object::Outer::Closed(value) = &*o {
+                    f(value);
+                }
+            }
+        }
+        Object::Class(c) => {
+            if let Ok(c) = c.try_borrow() {
+                c.base.iter().for_each(&mut *f);
+                for member in c.defaultvalues.iter().chain(&c.methods) {
+                    f(&member.val);
+                    f(&member.attributes);
+                }
+                f(&c.attributes);
+            }
+        }
+        Object::Instance(i) => {
+            if let Ok(i) = i.try_borrow() {
+                f(&i.class);
+                i.values.iter().for_each(&mut *f);
+                i.userdata.iter().for_each(f);
+            }
+        }
+        Object::Generator(g) => {
+            if let Ok(g) = g.try_borrow() {
+                f(&g.closure);
+                g.stack.iter().for_each(f);
+            }
+        }
+        Object::UserData(u) => {
+            if let Ok(u) = u.try_borrow() {
+                u.delegate.iter().for_each(f);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn unreachable(objects: Vec<Object>) -> Vec<Object> {
+    let index: HashMap<usize, usize> = objects
+        .iter()
+        .enumerate()
+        .filter_map(|(i, obj)| Some((id(obj)?, i)))
+        .collect();
+    let tracked_index = |obj: &Object| id(obj).and_then(|id| index.get(&id).copied());
+
+    // references from outside the tracked objects. The one held by `objects` doesn't count.
+    let mut external: Vec<usize> = objects.iter().map(|obj| strong_count(obj) - 1).collect();
+    for obj in &objects {
+        references(obj, &mut |child| {
+            if let Some(i) = tracked_index(child) {
+                external[i] = external[i].saturating_sub(1);
+            }
+        });
+    }
+
+    let mut reachable = vec![false; objects.len()];
+    let mut pending: Vec<usize> = (0..objects.len()).filter(|i| external[*i] > 0).collect();
+    while let Some(i) = pending.pop() {
+        if reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+        references(&objects[i], &mut |child| {
+            if let Some(child) = tracked_index(child) {
+                if !reachable[child] {
+                    pending.push(child);
+                }
+            }
+        });
+    }
+    objects
+        .into_iter()
+        .zip(reachable)
+        .filter(|(_, reachable)| !reachable)
+        .map(|(obj, _)| obj)
+        .collect()
+}
+
+fn release(garbage: Vec<Object>) -> usize {
+    // the contents are dropped after all borrows have ended, as dropping them may run
+    // release hooks of userdata
+    let mut released: Vec<Box<dyn Any>> = Vec::new();
+    for obj in &garbage {
+        match obj {
+            Object::Table(t) => released.push(Box::new(std::mem::take(&mut *t.borrow_mut()))),
+            Object::Array(a) => released.push(Box::new(std::mem::take(&mut *a.borrow_mut()))),
+            Object::Outer(o) => released.push(Box::new(std::mem::replace(
+                &mut *o.borrow_mut(),
+                object::Outer::Closed(Object::Null),
+            ))),
+            Object::Class(c) => {
+                let mut c = c.borrow_mut();
+                released.push(Box::new(c.base.take()));
+                released.push(Box::new(std::mem::take(&mut c.defaultvalues)));
+                released.push(Box::new(std::mem::take(&mut c.methods)));
+                released.push(Box::new(std::mem::replace(&mut c.attributes, Object::Null)));
+                c.members.clear();
+                c.constructor = None;
+            }
+            Object::Instance(i) => {
+                let mut i = i.borrow_mut();
+                // the slots stay in place, they are indexed through the class
+                let values = vec![Object::Null; i.values.len()];
+                released.push(Box::new(std::mem::replace(&mut i.values, values)));
+                released.push(Box::new(i.userdata.take()));
+            }
+            Object::Generator(g) => g.borrow_mut().kill(),
+            Object::UserData(u) => released.push(Box::new(u.borrow_mut().delegate.take())),
+            // closures hold their references through outers, which are released
+            _ => {}
+        }
+    }
+    drop(released);
+    garbage.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::tests::{run, strings};
+    use crate::vm::Executor;
+
+    #[test]
+    fn collect_cycles() {
+        let source = "
+            local a = {};
+            local b = {other = a};
+            a.other <- b;
+            a = null;
+            b = null;
+            local array = [];
+            array.append(array);
+            array = null;
+            class Node { next = null; }
+            local node = Node();
+            node.next = node;
+            node = null;
+            function make() {
+                local t = {};
+                t.f <- function() { return t; };
+            }
+            make();
+            local kept = {};
+            kept.self <- kept;
+            local unreachable = resurrectunreachable().len();
+            return [unreachable, collectgarbage(), collectgarbage(), resurrectunreachable(), kept.self == kept];
+        ";
+        assert_eq!(
+            strings(run(&mut Executor::new(), source)),
+            ["7", "7", "0", "null", "true"]
+        );
+    }
+
+    #[test]
+    fn registry_per_executor() {
+        let cycle = "local a = []; a.append(a); a = null; local b = {}; b.b <- b;";
+        let mut first = Executor::new();
+        let mut second = Executor::new();
+        run(&mut first, cycle);
+        run(&mut second, cycle);
+        let collected = run(&mut second, "return collectgarbage();");
+        assert_eq!(collected.integer().unwrap(), 2);
+        assert_eq!(first.resurrect_unreachable().len(), 2);
+        assert_eq!(first.collect_garbage(), 2);
+        assert_eq!(second.collect_garbage(), 0);
+    }
+}
@@ -1,20 +1,109 @@
-use super::{object, Error, FileTags, Object, ObjectType, Result};
+use super::{object, types, Error, FileTags, Object, ObjectType, Result};
 
 use crate::bytecode::Instruction;
-use byteorder::{LittleEndian, ReadBytesExt};
-use num_traits::{FromPrimitive, ToPrimitive};
-use std::io::Read;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use num_traits::FromPrimitive;
+use std::io::{Read, Write};
 use std::rc::Rc;
 
-fn read_string(rdr: &mut dyn Read) -> Result<Object> {
-    let len = rdr.read_u64::<LittleEndian>()? as usize;
+// sizes of SQInteger and SQFloat in a bytecode stream. Streams using other sizes than
+// types::Integer and types::Float are converted while reading.
+#[derive(Clone, Copy, Debug)]
+struct Widths {
+    integer: u32,
+    float: u32,
+}
+
+// the casts depend on the sizes selected by the cargo features
+#[allow(clippy::unnecessary_cast)]
+impl Widths {
+    fn native() -> Widths {
+        Widths {
+            integer: std::mem::size_of::<types::Integer>() as u32,
+            float: std::mem::size_of::<types::Float>() as u32,
+        }
+    }
+
+    fn read(rdr: &mut dyn Read) -> Result<Widths> {
+        let integer = rdr.read_u32::<LittleEndian>()?;
+        let float = rdr.read_u32::<LittleEndian>()?;
+        if integer != 4 && integer != 8 {
+            return Err(Error::RuntimeError(format!(
+                "unsupported integer size {}",
+                integer
+            )));
+        }
+        if float != 4 && float != 8 {
+            return Err(Error::RuntimeError(format!(
+                "unsupported float size {}",
+                float
+            )));
+        }
+        Ok(Widths { integer, float })
+    }
+
+    fn write(&self, wtr: &mut dyn Write) -> Result<()> {
+        wtr.write_u32::<LittleEndian>(self.integer)?;
+        wtr.write_u32::<LittleEndian>(self.float)?;
+        Ok(())
+    }
+
+    fn read_int(&self, rdr: &mut dyn Read) -> Result<types::Integer> {
+        let i = match self.integer {
+            4 => rdr.read_i32::<LittleEndian>()? as i64,
+            _ => rdr.read_i64::<LittleEndian>()?,
+        };
+        let res = i as types::Integer;
+        if res as i64 != i {
+            return Err(Error::RuntimeError(format!(
+                "integer {} does not fit into types::Integer",
+                i
+            )));
+        }
+        Ok(res)
+    }
+
+    fn write_int(&self, wtr: &mut dyn Write, i: types::Integer) -> Result<()> {
+        match self.integer {
+            4 => {
+                let res = i as i32;
+                if res as types::Integer != i {
+                    return Err(Error::RuntimeError(format!(
+                        "integer {} does not fit into 32 bits",
+                        i
+                    )));
+                }
+                wtr.write_i32::<LittleEndian>(res)?
+            }
+            _ => wtr.write_i64::<LittleEndian>(i as i64)?,
+        }
+        Ok(())
+    }
+
+    fn read_float(&self, rdr: &mut dyn Read) -> Result<types::Float> {
+        Ok(match self.float {
+            4 => rdr.read_f32::<LittleEndian>()? as types::Float,
+            _ => rdr.read_f64::<LittleEndian>()? as types::Float,
+        })
+    }
+
+    fn write_float(&self, wtr: &mut dyn Write, f: types::Float) -> Result<()> {
+        match self.float {
+            4 => wtr.write_f32::<LittleEndian>(f as f32)?,
+            _ => wtr.write_f64::<LittleEndian>(f as f64)?,
+        }
+        Ok(())
+    }
+}
+
+fn read_string(rdr: &mut dyn Read, widths: &Widths) -> Result<Object> {
+    let len = widths.read_int(rdr)? as usize;
     let mut buf = vec![0; len];
     match rdr.read(&mut buf) {
-        Ok(rlen) if rlen == len => Ok(Object::String(
+        Ok(rlen) if rlen == len => Ok(Object::String(Rc::new(object::Str::new(
             String::from_utf8(buf)
-                .map_err(|x| Error::RuntimeError(format!("failed to decode utf8: {}", x)))?
-                .into(),
-        )),
+                .map_err(|x| Error::RuntimeError(format!("failed to decode utf8: {}", x)))?,
+        )))),
         Ok(rlen) => Err(Error::RuntimeError(format!(
             "could not read {} bytes for string. Got {}",
             len, rlen
@@ -23,14 +112,15 @@ fn read_string(rdr: &mut dyn Read) -> Result<Object> {
     }
 }
 
-fn read_object(rdr: &mut dyn Read) -> Result<Object> {
+fn read_object(rdr: &mut dyn Read, widths: &Widths) -> Result<Object> {
     let obj_type = FromPrimitive::from_u32(rdr.read_u32::<LittleEndian>()?);
 
     match obj_type {
-        Some(ObjectType::Integer) => Ok(Object::Integer(rdr.read_i64::<LittleEndian>()?)),
-        Some(ObjectType::Float) => Ok(Object::Float(rdr.read_f32::<LittleEndian>()?)),
-        Some(ObjectType::String) => read_string(rdr),
-        Some(Null) => Ok(Object::Null),
+        Some(ObjectType::Integer) => Ok(Object::Integer(widths.read_int(rdr)?)),
+        Some(ObjectType::Float) => Ok(Object::Float(widths.read_float(rdr)?)),
+        Some(ObjectType::Bool) => Ok(Object::Bool(widths.read_int(rdr)? != 0)),
+        Some(ObjectType::String) => read_string(rdr, widths),
+        Some(ObjectType::Null) => Ok(Object::Null),
         Some(_) => panic!("unhandled object type {:?}", obj_type),
         None => Err(Error::RuntimeError(format!(
             "failed to decode object type: {:?}",
@@ -70,9 +160,8 @@ pub fn read_closure(rdr: &mut dyn Read) -> Result<Object> {
 
     expect_tag(rdr, FileTags::ClosurestreamHead)?;
     expect_tag(rdr, FileTags::SizeChar)?;
-    expect_tag(rdr, FileTags::SizeInteger)?;
-    expect_tag(rdr, FileTags::SizeFloat)?;
-    let func_proto = read_funcproto(rdr)?;
+    let widths = Widths::read(rdr)?;
+    let func_proto = read_funcproto_with(rdr, &widths)?;
     expect_tag(rdr, FileTags::ClosurestreamTail)?;
 
     let closure = object::Closure::new(func_proto);
@@ -80,40 +169,44 @@ pub fn read_closure(rdr: &mut dyn Read) -> Result<Object> {
 }
 
 pub fn read_funcproto(rdr: &mut dyn Read) -> Result<Object> {
+    read_funcproto_with(rdr, &Widths::native())
+}
+
+fn read_funcproto_with(rdr: &mut dyn Read, widths: &Widths) -> Result<Object> {
     expect_tag(rdr, FileTags::ClosurestreamPart)?;
-    let source_name = read_object(rdr)?;
-    let name = read_object(rdr)?;
+    let source_name = read_object(rdr, widths)?;
+    let name = read_object(rdr, widths)?;
 
     expect_tag(rdr, FileTags::ClosurestreamPart)?;
 
-    let nliterals = rdr.read_i64::<LittleEndian>()?;
-    let nparameters = rdr.read_i64::<LittleEndian>()?;
-    let noutervalues = rdr.read_i64::<LittleEndian>()?;
-    let nlocalvarinfos = rdr.read_i64::<LittleEndian>()?;
-    let nlineinfos = rdr.read_i64::<LittleEndian>()?;
-    let ndefaultparams = rdr.read_i64::<LittleEndian>()?;
-    let ninstructions = rdr.read_i64::<LittleEndian>()?;
-    let nfunctions = rdr.read_i64::<LittleEndian>()?;
+    let nliterals = widths.read_int(rdr)?;
+    let nparameters = widths.read_int(rdr)?;
+    let noutervalues = widths.read_int(rdr)?;
+    let nlocalvarinfos = widths.read_int(rdr)?;
+    let nlineinfos = widths.read_int(rdr)?;
+    let ndefaultparams = widths.read_int(rdr)?;
+    let ninstructions = widths.read_int(rdr)?;
+    let nfunctions = widths.read_int(rdr)?;
 
     expect_tag(rdr, FileTags::ClosurestreamPart)?;
     let mut literals = Vec::new();
     for _i in 0..nliterals {
-        literals.push(read_object(rdr)?);
+        literals.push(read_object(rdr, widths)?);
     }
 
     expect_tag(rdr, FileTags::ClosurestreamPart)?;
     let mut parameters = Vec::new();
     for _i in 0..nparameters {
-        parameters.push(read_object(rdr)?);
+        parameters.push(read_object(rdr, widths)?);
     }
 
     expect_tag(rdr, FileTags::ClosurestreamPart)?;
     let mut outervalues = Vec::new();
     for _i in 0..noutervalues {
         outervalues.push((
-            rdr.read_i64::<LittleEndian>()?,
-            read_object(rdr)?,
-            read_object(rdr)?,
+            widths.read_int(rdr)?,
+            read_object(rdr, widths)?,
+            read_object(rdr, widths)?,
         ));
     }
 
@@ -121,26 +214,23 @@ pub fn read_funcproto(rdr: &mut dyn Read) -> Result<Object> {
     let mut localvarinfos = Vec::new();
     for _i in 0..nlocalvarinfos {
         localvarinfos.push((
-            read_object(rdr)?,
-            rdr.read_i64::<LittleEndian>()?,
-            rdr.read_i64::<LittleEndian>()?,
-            rdr.read_i64::<LittleEndian>()?,
+            read_object(rdr, widths)?,
+            widths.read_int(rdr)?,
+            widths.read_int(rdr)?,
+            widths.read_int(rdr)?,
         ));
     }
 
     expect_tag(rdr, FileTags::ClosurestreamPart)?;
     let mut lineinfos = Vec::new();
     for _i in 0..nlineinfos {
-        lineinfos.push((
-            rdr.read_i64::<LittleEndian>()?,
-            rdr.read_i64::<LittleEndian>()?,
-        ));
+        lineinfos.push((widths.read_int(rdr)?, widths.read_int(rdr)?));
     }
 
     expect_tag(rdr, FileTags::ClosurestreamPart)?;
     let mut defaultparams = Vec::new();
     for _i in 0..ndefaultparams {
-        defaultparams.push(rdr.read_i64::<LittleEndian>()?);
+        defaultparams.push(widths.read_int(rdr)?);
     }
 
     expect_tag(rdr, FileTags::ClosurestreamPart)?;
@@ -152,39 +242,164 @@ pub fn read_funcproto(rdr: &mut dyn Read) -> Result<Object> {
     expect_tag(rdr, FileTags::ClosurestreamPart)?;
     let mut functions = Vec::new();
     for _i in 0..nfunctions {
-        functions.push(read_funcproto(rdr)?);
+        functions.push(read_funcproto_with(rdr, widths)?);
     }
 
-    let stacksize = rdr.read_i64::<LittleEndian>()?;
-    let mut bgenerator = [0u8; 1];
+    let stacksize = widths.read_int(rdr)?;
+    let bgenerator = rdr.read_u8()? != 0;
+    let varparams = widths.read_int(rdr)? != 0;
 
-    rdr.read(&mut bgenerator)?;
-    let varparams = rdr.read_i64::<LittleEndian>()?;
+    let obj = object::FuncProto {
+        source_name,
+        name,
 
-    let mut obj = object::FuncProto {
-        source_name: source_name,
-        name: name,
-
-        literals: literals,
-        parameters: parameters,
-        outervalues: outervalues,
-        localvarinfos: localvarinfos,
-        lineinfos: lineinfos,
-        defaultparams: defaultparams,
-        instructions: instructions,
-        functions: functions,
-        stacksize: stacksize,
+        literals,
+        parameters,
+        outervalues,
+        localvarinfos,
+        lineinfos,
+        defaultparams,
+        instructions,
+        functions,
+        stacksize,
+        bgenerator,
+        varparams,
     };
 
     // Ok(obj)
     Ok(Object::FuncProto(Rc::new(obj)))
 }
 
+fn write_string(wtr: &mut dyn Write, s: &str, widths: &Widths) -> Result<()> {
+    widths.write_int(wtr, s.len() as types::Integer)?;
+    wtr.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn write_object(wtr: &mut dyn Write, obj: &Object, widths: &Widths) -> Result<()> {
+    match obj {
+        Object::Integer(i) => {
+            wtr.write_u32::<LittleEndian>(ObjectType::Integer as u32)?;
+            widths.write_int(wtr, *i)?;
+        }
+        Object::Float(f) => {
+            wtr.write_u32::<LittleEndian>(ObjectType::Float as u32)?;
+            widths.write_float(wtr, *f)?;
+        }
+        Object::Bool(b) => {
+            wtr.write_u32::<LittleEndian>(ObjectType::Bool as u32)?;
+            widths.write_int(wtr, *b as types::Integer)?;
+        }
+        Object::String(s) => {
+            wtr.write_u32::<LittleEndian>(ObjectType::String as u32)?;
+            write_string(wtr, s, widths)?;
+        }
+        Object::Null => wtr.write_u32::<LittleEndian>(ObjectType::Null as u32)?,
+        _ => {
+            return Err(Error::RuntimeError(format!(
+                "cannot serialize object of type {}",
+                obj.type_name()
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn write_tag(wtr: &mut dyn Write, tag: FileTags) -> Result<()> {
+    wtr.write_u32::<LittleEndian>(tag as u32)?;
+    Ok(())
+}
+
+pub fn write_closure(closure: &Object, wtr: &mut dyn Write) -> Result<()> {
+    write_closure_with(closure, wtr, &Widths::native())
+}
+
+fn write_closure_with(closure: &Object, wtr: &mut dyn Write, widths: &Widths) -> Result<()> {
+    let closure = closure.closure_ref()?;
+    wtr.write_u16::<LittleEndian>(FileTags::BytecodeStreamTag as u16)?;
+    write_tag(wtr, FileTags::ClosurestreamHead)?;
+    write_tag(wtr, FileTags::SizeChar)?;
+    widths.write(wtr)?;
+    write_funcproto_with(&closure.func_proto, wtr, widths)?;
+    write_tag(wtr, FileTags::ClosurestreamTail)
+}
+
+pub fn write_funcproto(func_proto: &Object, wtr: &mut dyn Write) -> Result<()> {
+    write_funcproto_with(func_proto, wtr, &Widths::native())
+}
+
+fn write_funcproto_with(func_proto: &Object, wtr: &mut dyn Write, widths: &Widths) -> Result<()> {
+    let fp = func_proto.func_proto_ref()?;
+    write_tag(wtr, FileTags::ClosurestreamPart)?;
+    write_object(wtr, &fp.source_name, widths)?;
+    write_object(wtr, &fp.name, widths)?;
+
+    write_tag(wtr, FileTags::ClosurestreamPart)?;
+    widths.write_int(wtr, fp.literals.len() as types::Integer)?;
+    widths.write_int(wtr, fp.parameters.len() as types::Integer)?;
+    widths.write_int(wtr, fp.outervalues.len() as types::Integer)?;
+    widths.write_int(wtr, fp.localvarinfos.len() as types::Integer)?;
+    widths.write_int(wtr, fp.lineinfos.len() as types::Integer)?;
+    widths.write_int(wtr, fp.defaultparams.len() as types::Integer)?;
+    widths.write_int(wtr, fp.instructions.len() as types::Integer)?;
+    widths.write_int(wtr, fp.functions.len() as types::Integer)?;
+
+    write_tag(wtr, FileTags::ClosurestreamPart)?;
+    for literal in &fp.literals {
+        write_object(wtr, literal, widths)?;
+    }
+
+    write_tag(wtr, FileTags::ClosurestreamPart)?;
+    for parameter in &fp.parameters {
+        write_object(wtr, parameter, widths)?;
+    }
+
+    write_tag(wtr, FileTags::ClosurestreamPart)?;
+    for (outer_type, src, name) in &fp.outervalues {
+        widths.write_int(wtr, *outer_type)?;
+        write_object(wtr, src, widths)?;
+        write_object(wtr, name, widths)?;
+    }
+
+    write_tag(wtr, FileTags::ClosurestreamPart)?;
+    for (name, pos, start_op, end_op) in &fp.localvarinfos {
+        write_object(wtr, name, widths)?;
+        widths.write_int(wtr, *pos)?;
+        widths.write_int(wtr, *start_op)?;
+        widths.write_int(wtr, *end_op)?;
+    }
+
+    write_tag(wtr, FileTags::ClosurestreamPart)?;
+    for (line, op) in &fp.lineinfos {
+        widths.write_int(wtr, *line)?;
+        widths.write_int(wtr, *op)?;
+    }
+
+    write_tag(wtr, FileTags::ClosurestreamPart)?;
+    for defaultparam in &fp.defaultparams {
+        widths.write_int(wtr, *defaultparam)?;
+    }
+
+    write_tag(wtr, FileTags::ClosurestreamPart)?;
+    for instruction in &fp.instructions {
+        instruction.write(wtr)?;
+    }
+
+    write_tag(wtr, FileTags::ClosurestreamPart)?;
+    for function in &fp.functions {
+        write_funcproto_with(function, wtr, widths)?;
+    }
+
+    widths.write_int(wtr, fp.stacksize)?;
+    wtr.write_u8(fp.bgenerator as u8)?;
+    widths.write_int(wtr, fp.varparams as types::Integer)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use super::read_closure;
     use super::Object;
-    use std::io::Seek;
+    use super::{read_closure, write_closure, write_closure_with, Widths};
 
     // fn read_cnut<R: std::io::Read + Seek>(rdr: &mut R) -> super::Result<Object> {
     //     let closure = read_closure(rdr);
@@ -213,4 +428,57 @@ mod tests {
             }
         }
     }
+
+    // the fixtures are built with 64 bit integers and 32 bit floats
+    #[cfg(not(any(feature = "double", feature = "int32")))]
+    #[test]
+    fn write_closure_roundtrip() {
+        let files: [&[u8]; 8] = [
+            include_bytes!("out.cnut"),
+            include_bytes!("../examples/ackermann.cnut"),
+            include_bytes!("../examples/delegation.cnut"),
+            include_bytes!("../examples/factorial.cnut"),
+            include_bytes!("../examples/flow.cnut"),
+            include_bytes!("../examples/loops.cnut"),
+            include_bytes!("../examples/tailcall.cnut"),
+            include_bytes!("../examples/test.cnut"),
+        ];
+        for bc in files.iter() {
+            let closure = read_closure(&mut &bc[..]).unwrap();
+            let mut out = Vec::new();
+            write_closure(&closure, &mut out).unwrap();
+            assert_eq!(&out[..], *bc);
+        }
+
+        let closure = crate::compiler::compile_str(
+            include_str!("../examples/factorial.nut"),
+            "factorial.nut",
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        write_closure(&closure, &mut out).unwrap();
+        assert_eq!(&out[..], &include_bytes!("../examples/factorial.cnut")[..]);
+    }
+
+    #[test]
+    fn convert_widths() {
+        let files: [&[u8]; 3] = [
+            include_bytes!("../examples/ackermann.cnut"),
+            include_bytes!("../examples/delegation.cnut"),
+            include_bytes!("../examples/loops.cnut"),
+        ];
+        for bc in files.iter() {
+            let closure = read_closure(&mut &bc[..]).unwrap();
+            let mut native = Vec::new();
+            write_closure(&closure, &mut native).unwrap();
+            for &(integer, float) in &[(4, 4), (4, 8), (8, 4), (8, 8)] {
+                let mut converted = Vec::new();
+                write_closure_with(&closure, &mut converted, &Widths { integer, float }).unwrap();
+                let closure = read_closure(&mut &converted[..]).unwrap();
+                let mut out = Vec::new();
+                write_closure(&closure, &mut out).unwrap();
+                assert_eq!(out, native);
+            }
+        }
+    }
 }
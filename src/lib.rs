@@ -4,14 +4,29 @@ use std::rc::Rc;
 
 // use num_traits::FromPrimitive;
 
+mod baselib;
+pub mod binding;
 pub mod bytecode;
+pub mod compiler;
+pub mod convert;
+mod gc;
 pub mod io;
+pub mod memory;
 pub mod vm;
 
 pub mod object;
 #[derive(Debug)]
 pub enum Error {
     RuntimeError(String),
+    // an object thrown by a script that was not caught
+    Exception(Object),
+    // the instruction budget ran out. The execution can be continued after raising the budget.
+    BudgetExhausted,
+    // the execution was aborted and can't be continued
+    Terminated(String),
+    // the stack size or call depth limit was exceeded
+    StackOverflow,
+    CompileError(String),
     IoError(std::io::Error),
 }
 
@@ -85,13 +100,21 @@ enum FileTags {
         ((('T' as isize) << 24) | (('A' as isize) << 16) | (('I' as isize) << 8) | ('L' as isize)),
 
     SizeChar = 1,
-    SizeInteger = 8,
-    SizeFloat = 4,
 }
 
 pub mod types {
+    #[cfg(not(feature = "int32"))]
     pub type Integer = i64;
+    #[cfg(not(feature = "int32"))]
+    pub type UnsignedInteger = u64;
+    #[cfg(feature = "int32")]
+    pub type Integer = i32;
+    #[cfg(feature = "int32")]
+    pub type UnsignedInteger = u32;
+    #[cfg(not(feature = "double"))]
     pub type Float = f32;
+    #[cfg(feature = "double")]
+    pub type Float = f64;
 }
 
 #[derive(Clone)]
@@ -100,26 +123,53 @@ pub enum Object {
     Float(types::Float),
     Bool(bool),
     // String(String),
-    String(Box<str>),
+    String(Rc<object::Str>),
     FuncProto(Rc<object::FuncProto>),
     Closure(Rc<object::Closure>),
     NativeClosure(Rc<object::NativeClosure>),
     Table(Rc<RefCell<object::Table>>),
     Array(Rc<RefCell<object::Array>>),
+    Outer(Rc<RefCell<object::Outer>>),
+    Class(Rc<RefCell<object::Class>>),
+    Instance(Rc<RefCell<object::Instance>>),
+    Generator(Rc<RefCell<object::Generator>>),
+    Thread(Rc<RefCell<vm::Thread>>),
+    UserData(Rc<RefCell<object::UserData>>),
+    // an opaque handle of the host
+    UserPointer(usize),
+    WeakRef(Rc<object::WeakRef>),
     Null,
 }
 
 impl Object {
     pub fn new_table() -> Object {
-        Object::Table(Rc::new(RefCell::new(object::Table::new())))
+        gc::track(Object::Table(Rc::new(RefCell::new(object::Table::new()))))
     }
     pub fn new_array(capacity: types::Integer) -> Object {
         let mut array = object::Array::new();
         array.reserve(capacity);
-        Object::Array(Rc::new(RefCell::new(array)))
+        gc::track(Object::Array(Rc::new(RefCell::new(array))))
+    }
+    // the address of the value of a reference type
+    fn address(&self) -> Option<usize> {
+        Some(match self {
+            Object::FuncProto(p) => Rc::as_ptr(p) as *const () as usize,
+            Object::Closure(c) => Rc::as_ptr(c) as *const () as usize,
+            Object::NativeClosure(c) => Rc::as_ptr(c) as *const () as usize,
+            Object::Table(t) => Rc::as_ptr(t) as *const () as usize,
+            Object::Array(a) => Rc::as_ptr(a) as *const () as usize,
+            Object::Outer(o) => Rc::as_ptr(o) as *const () as usize,
+            Object::Class(c) => Rc::as_ptr(c) as *const () as usize,
+            Object::Instance(i) => Rc::as_ptr(i) as *const () as usize,
+            Object::Generator(g) => Rc::as_ptr(g) as *const () as usize,
+            Object::Thread(t) => Rc::as_ptr(t) as *const () as usize,
+            Object::UserData(u) => Rc::as_ptr(u) as *const () as usize,
+            Object::WeakRef(w) => Rc::as_ptr(w) as *const () as usize,
+            _ => return None,
+        })
     }
     pub fn new_string(s: &str) -> Object {
-        Object::String(s.into())
+        Object::String(Rc::new(object::Str::new(s)))
     }
     pub fn string(&self) -> Result<&str> {
         match self {
@@ -169,14 +219,14 @@ impl Object {
     }
     pub fn integer(&self) -> Result<types::Integer> {
         match self {
-            Object::Integer(i) => Ok(i.clone()),
+            Object::Integer(i) => Ok(*i),
             _ => Err(Error::RuntimeError(format!(
                 "expected Integer. found {}",
                 self.type_name()
             ))),
         }
     }
-    pub fn table(&self) -> Result<Ref<object::Table>> {
+    pub fn table(&self) -> Result<Ref<'_, object::Table>> {
         match self {
             Object::Table(t) => Ok(t.borrow()),
             _ => Err(Error::RuntimeError(format!(
@@ -185,7 +235,7 @@ impl Object {
             ))),
         }
     }
-    pub fn table_mut(&mut self) -> Result<RefMut<object::Table>> {
+    pub fn table_mut(&mut self) -> Result<RefMut<'_, object::Table>> {
         match self {
             Object::Table(t) => Ok(t.borrow_mut()),
             _ => Err(Error::RuntimeError(format!(
@@ -194,7 +244,7 @@ impl Object {
             ))),
         }
     }
-    pub fn array(&mut self) -> Result<Ref<object::Array>> {
+    pub fn array(&mut self) -> Result<Ref<'_, object::Array>> {
         match self {
             Object::Array(a) => Ok(a.borrow()),
             _ => Err(Error::RuntimeError(format!(
@@ -203,7 +253,7 @@ impl Object {
             ))),
         }
     }
-    pub fn array_mut(&mut self) -> Result<RefMut<object::Array>> {
+    pub fn array_mut(&mut self) -> Result<RefMut<'_, object::Array>> {
         match self {
             Object::Array(a) => Ok(a.borrow_mut()),
             _ => Err(Error::RuntimeError(format!(
@@ -212,6 +262,45 @@ impl Object {
             ))),
         }
     }
+    pub fn class(&self) -> Result<Ref<'_, object::Class>> {
+        match self {
+            Object::Class(c) => Ok(c.borrow()),
+            _ => Err(Error::RuntimeError(format!(
+                "expected class. found {}",
+                self.type_name()
+            ))),
+        }
+    }
+    pub fn class_mut(&self) -> Result<RefMut<'_, object::Class>> {
+        match self {
+            Object::Class(c) => Ok(c.borrow_mut()),
+            _ => Err(Error::RuntimeError(format!(
+                "expected class. found {}",
+                self.type_name()
+            ))),
+        }
+    }
+    pub fn new_userdata(userdata: object::UserData) -> Object {
+        gc::track(Object::UserData(Rc::new(RefCell::new(userdata))))
+    }
+    pub fn userdata(&self) -> Result<Ref<'_, object::UserData>> {
+        match self {
+            Object::UserData(u) => Ok(u.borrow()),
+            _ => Err(Error::RuntimeError(format!(
+                "expected userdata. found {}",
+                self.type_name()
+            ))),
+        }
+    }
+    pub fn userdata_mut(&self) -> Result<RefMut<'_, object::UserData>> {
+        match self {
+            Object::UserData(u) => Ok(u.borrow_mut()),
+            _ => Err(Error::RuntimeError(format!(
+                "expected userdata. found {}",
+                self.type_name()
+            ))),
+        }
+    }
     pub fn type_name(&self) -> &'static str {
         match self {
             Object::Integer(_) => "int",
@@ -223,6 +312,14 @@ impl Object {
             Object::NativeClosure(_) => "nativeclosure",
             Object::Table(_) => "table",
             Object::Array(_) => "array",
+            Object::Outer(_) => "outer",
+            Object::Class(_) => "class",
+            Object::Instance(_) => "instance",
+            Object::Generator(_) => "generator",
+            Object::Thread(_) => "thread",
+            Object::UserData(_) => "userdata",
+            Object::UserPointer(_) => "userpointer",
+            Object::WeakRef(_) => "weakref",
             Object::Null => "null",
         }
     }
@@ -236,20 +333,58 @@ impl Object {
             Object::FuncProto(_) | Object::Closure(_) | Object::NativeClosure(_) => "function",
             Object::Table(_) => "table",
             Object::Array(_) => "array",
+            Object::Outer(_) => "outer",
+            Object::Class(_) => "class",
+            Object::Instance(_) => "instance",
+            Object::Generator(_) => "generator",
+            Object::Thread(_) => "thread",
+            Object::UserData(_) => "userdata",
+            Object::UserPointer(_) => "userpointer",
+            Object::WeakRef(_) => "weakref",
             Object::Null => "null",
         }
     }
 
+    pub fn raw_type(&self) -> isize {
+        match self {
+            Object::Integer(_) => raw_type::INTEGER,
+            Object::Bool(_) => raw_type::BOOL,
+            Object::Float(_) => raw_type::FLOAT,
+            Object::String(_) => raw_type::STRING,
+            Object::FuncProto(_) => raw_type::FUNCPROTO,
+            Object::Closure(_) => raw_type::CLOSURE,
+            Object::NativeClosure(_) => raw_type::NATIVECLOSURE,
+            Object::Table(_) => raw_type::TABLE,
+            Object::Array(_) => raw_type::ARRAY,
+            Object::Outer(_) => raw_type::OUTER,
+            Object::Class(_) => raw_type::CLASS,
+            Object::Instance(_) => raw_type::INSTANCE,
+            Object::Generator(_) => raw_type::GENERATOR,
+            Object::Thread(_) => raw_type::THREAD,
+            Object::UserData(_) => raw_type::USERDATA,
+            Object::UserPointer(_) => raw_type::USERPOINTER,
+            Object::WeakRef(_) => raw_type::WEAKREF,
+            Object::Null => raw_type::NULL,
+        }
+    }
+
     pub fn clone_object(&self) -> Result<Object> {
         match self {
             Object::Integer(_) | Object::Bool(_) | Object::Float(_) | Object::String(_) => {
                 Ok(self.clone())
             }
-            Object::Table(table) => {
-                Ok(Object::Table(Rc::new(RefCell::new(table.borrow().clone()))))
-            }
-            Object::Array(array) => {
-                Ok(Object::Array(Rc::new(RefCell::new(array.borrow().clone()))))
+            Object::Table(table) => Ok(gc::track(Object::Table(Rc::new(RefCell::new(
+                table.borrow().clone(),
+            ))))),
+            Object::Array(array) => Ok(gc::track(Object::Array(Rc::new(RefCell::new(
+                array.borrow().clone(),
+            ))))),
+            Object::Instance(instance) => {
+                let mut instance = instance.borrow().clone();
+                // the rust value of a bound instance belongs to the instance it was constructed
+                // for, the copy gets none
+                instance.userdata = None;
+                Ok(gc::track(Object::Instance(Rc::new(RefCell::new(instance)))))
             }
             _ => Err(Error::RuntimeError(format!("cannot clone {}", self))),
         }
@@ -283,6 +418,14 @@ impl std::fmt::Display for Object {
             Object::NativeClosure(_) => write!(fmt, "nativeclosure()"),
             Object::Table(_) => write!(fmt, "table"),
             Object::Array(_) => write!(fmt, "array"),
+            Object::Outer(_) => write!(fmt, "outer"),
+            Object::Class(_) => write!(fmt, "class"),
+            Object::Instance(_) => write!(fmt, "instance"),
+            Object::Generator(_) => write!(fmt, "generator"),
+            Object::Thread(_) => write!(fmt, "thread"),
+            Object::UserData(_) => write!(fmt, "userdata"),
+            Object::UserPointer(p) => write!(fmt, "userpointer({:#x})", p),
+            Object::WeakRef(_) => write!(fmt, "weakref"),
             Object::Null => write!(fmt, "null"),
         }
     }
@@ -300,18 +443,30 @@ impl std::fmt::Debug for Object {
             Object::NativeClosure(_) => write!(fmt, "nativeclosure()"),
             Object::Table(table) => write!(fmt, "table({:?})", table.borrow().map),
             Object::Array(arr) => write!(fmt, "array({:?})", arr.borrow().array),
+            Object::Outer(outer) => write!(fmt, "outer({:?})", outer.borrow()),
+            Object::Class(_) => write!(fmt, "class"),
+            Object::Instance(_) => write!(fmt, "instance"),
+            Object::Generator(_) => write!(fmt, "generator"),
+            Object::Thread(_) => write!(fmt, "thread"),
+            Object::UserData(userdata) => write!(fmt, "{:?}", userdata.borrow()),
+            Object::UserPointer(p) => write!(fmt, "userpointer({:#x})", p),
+            Object::WeakRef(_) => write!(fmt, "weakref"),
             Object::Null => write!(fmt, "null"),
         }
     }
 }
 
+// values of reference types are keys by identity
 impl std::hash::Hash for Object {
     fn hash<H: std::hash::Hasher>(&self, hasher: &mut H) {
         match self {
             Object::Integer(int) => int.hash(hasher),
+            Object::Float(f) => f.to_bits().hash(hasher),
             Object::Bool(b) => b.hash(hasher),
             Object::String(str) => str.hash(hasher),
-            _ => panic!("hash not implemented for {:?}", self),
+            Object::UserPointer(p) => p.hash(hasher),
+            Object::Null => {}
+            _ => self.address().hash(hasher),
         }
     }
 }
@@ -320,9 +475,16 @@ impl std::cmp::PartialEq for Object {
     fn eq(&self, rhs: &Self) -> bool {
         match (self, rhs) {
             (Object::Integer(i1), Object::Integer(i2)) => i1.eq(i2),
+            (Object::Float(f1), Object::Float(f2)) => f1.to_bits() == f2.to_bits(),
             (Object::String(s1), Object::String(s2)) => s1.eq(s2),
             (Object::Bool(b1), Object::Bool(b2)) => b1.eq(b2),
-            (_, _) => false,
+            (Object::UserPointer(p1), Object::UserPointer(p2)) => p1 == p2,
+            (Object::Null, Object::Null) => true,
+            (_, _) => {
+                std::mem::discriminant(self) == std::mem::discriminant(rhs)
+                    && self.address().is_some()
+                    && self.address() == rhs.address()
+            }
         }
     }
 }
@@ -338,13 +500,6 @@ impl std::cmp::Eq for Object {}
 //     }
 // }
 
-trait KeyValueAccess {
-    fn get(&self, key: &Object) -> Result<&Object>;
-    fn get_mut(&mut self, key: &Object) -> Result<&mut Object>;
-
-    fn set(&mut self, key: &Object, value: Object);
-}
-
-pub fn native_closure(func: Box<Fn(&mut vm::Stack)>, nargs: types::Integer) -> Object {
+pub fn native_closure(func: object::NativeFn, nargs: types::Integer) -> Object {
     Object::NativeClosure(Rc::new(object::NativeClosure::new(func, nargs)))
 }
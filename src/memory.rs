@@ -0,0 +1,167 @@
+use crate::{object, Error, Object, Result};
+use std::cell::Cell;
+use std::mem::size_of;
+use std::rc::Rc;
+
+// memory usage of an executor. The sizes are estimates of the memory held by the strings,
+// tables, arrays, closures, classes, instances and generators created by scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    // bytes currently allocated
+    pub allocated: usize,
+    // the highest number of bytes allocated so far
+    pub peak: usize,
+    pub limit: Option<usize>,
+}
+
+// the memory accounted to an executor
+#[derive(Default)]
+pub(crate) struct Accountant {
+    allocated: Cell<usize>,
+    peak: Cell<usize>,
+    limit: Cell<Option<usize>>,
+}
+
+impl Accountant {
+    pub(crate) fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            allocated: self.allocated.get(),
+            peak: self.peak.get(),
+            limit: self.limit.get(),
+        }
+    }
+
+    pub(crate) fn set_limit(&self, limit: Option<usize>) {
+        self.limit.set(limit);
+    }
+
+    // fails if allocating another `bytes` would exceed the limit
+    pub(crate) fn check(&self, bytes: usize) -> Result<()> {
+        match self.limit.get() {
+            Some(limit) if self.allocated.get().saturating_add(bytes) > limit => {
+                Err(Error::RuntimeError("out of memory".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // adds bytes to an allocation, or removes them if negative. Growing beyond the limit fails
+    // and leaves the allocation as it was. Allocations of other executors are left alone.
+    pub(crate) fn charge(self: &Rc<Self>, allocation: &mut Allocation, bytes: isize) -> Result<()> {
+        if let Some(owner) = &allocation.owner {
+            if !Rc::ptr_eq(owner, self) {
+                return Ok(());
+            }
+        }
+        if bytes >= 0 {
+            let bytes = bytes as usize;
+            self.check(bytes)?;
+            allocation.bytes += bytes;
+            self.allocated.set(self.allocated.get() + bytes);
+            self.peak.set(self.peak.get().max(self.allocated.get()));
+        } else {
+            let bytes = bytes.unsigned_abs().min(allocation.bytes);
+            allocation.bytes -= bytes;
+            self.allocated.set(self.allocated.get() - bytes);
+        }
+        if allocation.owner.is_none() {
+            allocation.owner = Some(self.clone());
+        }
+        Ok(())
+    }
+}
+
+// the bytes charged for an object. They are given back to the executor when the object is
+// released. Copies of an object start out without any bytes charged.
+#[derive(Default)]
+pub(crate) struct Allocation {
+    owner: Option<Rc<Accountant>>,
+    bytes: usize,
+}
+
+impl Allocation {
+    pub(crate) fn is_charged(&self) -> bool {
+        self.owner.is_some()
+    }
+}
+
+impl Clone for Allocation {
+    fn clone(&self) -> Self {
+        Allocation::default()
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        if let Some(owner) = self.owner.take() {
+            owner.allocated.set(owner.allocated.get() - self.bytes);
+        }
+    }
+}
+
+impl std::fmt::Debug for Allocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Allocation({})", self.bytes)
+    }
+}
+
+// the size of a value held by another object. Strings are accounted on their own.
+pub(crate) fn value_size(_value: &Object) -> usize {
+    size_of::<Object>()
+}
+
+// the size of a table slot, including its hash
+pub(crate) fn slot_size(key: &Object, value: &Object) -> usize {
+    value_size(key) + value_size(value) + size_of::<u64>()
+}
+
+// the size of a class member, including its hash
+pub(crate) fn class_slot_size() -> usize {
+    size_of::<Object>()
+        + size_of::<object::ClassMemberIndex>()
+        + size_of::<object::ClassMember>()
+        + size_of::<u64>()
+}
+
+// the size of a new object
+pub(crate) fn object_size(obj: &Object) -> usize {
+    match obj {
+        Object::String(s) => size_of::<object::Str>() + s.len(),
+        Object::Table(table) => table_size(&table.borrow()),
+        Object::Array(array) => array_size(&array.borrow()),
+        Object::Closure(closure) => closure_size(closure),
+        Object::Class(class) => {
+            size_of::<object::Class>() + class.borrow().members.len() * class_slot_size()
+        }
+        Object::Instance(instance) => {
+            size_of::<object::Instance>() + instance.borrow().values.len() * size_of::<Object>()
+        }
+        Object::Generator(generator) => {
+            size_of::<object::Generator>() + generator.borrow().stack.len() * size_of::<Object>()
+        }
+        _ => 0,
+    }
+}
+
+pub(crate) fn table_size(table: &object::Table) -> usize {
+    size_of::<object::Table>()
+        + table
+            .map
+            .iter()
+            .map(|(key, value)| slot_size(key, value))
+            .sum::<usize>()
+}
+
+pub(crate) fn array_size(array: &object::Array) -> usize {
+    size_of::<object::Array>() + array.array.iter().map(value_size).sum::<usize>()
+}
+
+pub(crate) fn closure_size(closure: &object::Closure) -> usize {
+    size_of::<object::Closure>()
+        + closure
+            .outervalues
+            .iter()
+            .chain(&closure.defaultparams)
+            .map(value_size)
+            .sum::<usize>()
+}
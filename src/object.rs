@@ -1,30 +1,197 @@
-use super::{bytecode, types, Object};
-use std::collections::HashMap;
+use super::{bytecode, types, Error, Object, Result};
+use indexmap::IndexMap;
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+// the text of a string object. Copies of the object share it.
+pub struct Str {
+    text: Box<str>,
+    pub(crate) allocation: super::memory::Allocation,
+}
+
+impl Str {
+    pub fn new(text: impl Into<Box<str>>) -> Self {
+        Str {
+            text: text.into(),
+            allocation: Default::default(),
+        }
+    }
+}
+
+impl std::ops::Deref for Str {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.text
+    }
+}
+
+impl PartialEq for Str {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+impl Eq for Str {}
+
+impl PartialOrd for Str {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Str {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.text.cmp(&other.text)
+    }
+}
+
+impl std::hash::Hash for Str {
+    fn hash<H: std::hash::Hasher>(&self, hasher: &mut H) {
+        self.text.hash(hasher)
+    }
+}
+
+impl std::fmt::Display for Str {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}", self.text)
+    }
+}
+
+impl std::fmt::Debug for Str {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{:?}", self.text)
+    }
+}
+
 #[derive(Debug)]
 pub struct Closure {
     pub func_proto: Object,
+    pub outervalues: Vec<Object>,
+    // values of the default parameters at the time the closure was created
+    pub defaultparams: Vec<Object>,
+    // base class of the class this closure is a method of
+    pub base: Option<Object>,
+    pub(crate) allocation: super::memory::Allocation,
 }
 
 impl Closure {
     pub fn new(func_proto: Object) -> Self {
         Closure {
-            func_proto: func_proto,
+            func_proto,
+            outervalues: Vec::new(),
+            defaultparams: Vec::new(),
+            base: None,
+            allocation: Default::default(),
+        }
+    }
+    pub fn with_outers(
+        func_proto: Object,
+        outervalues: Vec<Object>,
+        defaultparams: Vec<Object>,
+    ) -> Self {
+        Closure {
+            func_proto,
+            outervalues,
+            defaultparams,
+            base: None,
+            allocation: Default::default(),
         }
     }
 }
 
+// a variable captured by a closure. It refers to the stack slot of the local while the declaring
+// function is active and holds the value once the local went out of scope.
+#[derive(Debug)]
+pub enum Outer {
+    Open(usize),
+    Closed(Object),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeneratorState {
+    Running,
+    Suspended,
+    Dead,
+}
+
+#[derive(Debug)]
+pub struct Generator {
+    pub closure: Object,
+    pub state: GeneratorState,
+    // the frame of a suspended generator: its stack slots, instruction pointer and traps
+    pub stack: Vec<Object>,
+    pub ip: types::Integer,
+    pub(crate) traps: Vec<super::vm::ExceptionTrap>,
+    pub(crate) allocation: super::memory::Allocation,
+}
+
+impl Generator {
+    pub fn new(closure: Object, args: Vec<Object>, stacksize: types::Integer) -> Self {
+        let mut stack = args;
+        stack.resize(stacksize as usize, Object::Null);
+        Generator {
+            closure,
+            state: GeneratorState::Suspended,
+            stack,
+            ip: 0,
+            traps: Vec::new(),
+            allocation: Default::default(),
+        }
+    }
+
+    pub fn kill(&mut self) {
+        self.state = GeneratorState::Dead;
+        self.stack.clear();
+        self.traps.clear();
+    }
+}
+
+pub type NativeFn = Box<dyn Fn(&mut super::vm::CallContext) -> Result<Object>>;
+
+pub enum NativeFunction {
+    // functions registered by the host. The returned value is the result of the call and an
+    // error is thrown as an exception in the calling script.
+    Native(NativeFn),
+    // functions provided by the vm itself. They get the call arguments (`this` first) and
+    // access to the executor.
+    Builtin(fn(&mut super::vm::Executor, Vec<Object>) -> Result<Object>),
+}
+
 pub struct NativeClosure {
-    pub func: Box<dyn Fn(&mut super::vm::Stack)>,
+    pub func: NativeFunction,
+    // the number of arguments including `this`. A negative value requires at least -nargs
+    // arguments and 0 disables the check.
     pub nargs: types::Integer,
+    // raw type masks of the arguments, `this` first. Arguments without a mask are not checked.
+    pub typemask: Vec<isize>,
 }
 
 impl NativeClosure {
-    pub fn new(func: Box<Fn(&mut super::vm::Stack)>, nargs: types::Integer) -> NativeClosure {
+    pub fn new(func: NativeFn, nargs: types::Integer) -> NativeClosure {
         NativeClosure {
-            func: func,
-            nargs: nargs,
+            func: NativeFunction::Native(func),
+            nargs,
+            typemask: Vec::new(),
         }
     }
+    pub fn builtin(
+        func: fn(&mut super::vm::Executor, Vec<Object>) -> Result<Object>,
+        nargs: types::Integer,
+    ) -> NativeClosure {
+        NativeClosure {
+            func: NativeFunction::Builtin(func),
+            nargs,
+            typemask: Vec::new(),
+        }
+    }
+}
+
+impl NativeClosure {
+    // sets the parameter check like sq_setparamscheck
+    pub fn with_typemask(mut self, typemask: &str) -> Result<NativeClosure> {
+        self.typemask = crate::convert::compile_typemask(typemask)?;
+        Ok(self)
+    }
 }
 
 impl std::fmt::Debug for NativeClosure {
@@ -48,6 +215,8 @@ pub struct FuncProto {
     pub functions: Vec<Object>,
 
     pub stacksize: types::Integer,
+    pub bgenerator: bool,
+    pub varparams: bool,
 }
 
 impl FuncProto {
@@ -65,15 +234,119 @@ impl FuncProto {
     }
 }
 
+// the slots of a table in insertion order, which foreach iterates by position. Removing a slot
+// leaves a hole so that the positions of the other slots don't change while a loop deletes
+// them. The holes are dropped when a new key is inserted and they outnumber the slots.
+#[derive(Clone, Default)]
+pub struct TableMap {
+    entries: IndexMap<Object, Option<Object>>,
+    len: usize,
+}
+
+impl TableMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn get(&self, key: &Object) -> Option<&Object> {
+        self.entries.get(key)?.as_ref()
+    }
+    pub fn contains_key(&self, key: &Object) -> bool {
+        self.get(key).is_some()
+    }
+    pub fn insert(&mut self, key: Object, value: Object) -> Option<Object> {
+        if let Some(slot) = self.entries.get_mut(&key) {
+            let old = slot.replace(value);
+            if old.is_none() {
+                self.len += 1;
+            }
+            return old;
+        }
+        if self.entries.len() - self.len > self.len {
+            self.entries.retain(|_, value| value.is_some());
+        }
+        self.entries.insert(key, Some(value));
+        self.len += 1;
+        None
+    }
+    pub fn remove_entry(&mut self, key: &Object) -> Option<(Object, Object)> {
+        let (_, key, slot) = self.entries.get_full_mut(key)?;
+        let value = slot.take()?;
+        self.len -= 1;
+        Some((key.clone(), value))
+    }
+    // the first slot at or after position index, with the position after it
+    pub fn next_slot(&self, index: usize) -> Option<(usize, &Object, &Object)> {
+        self.entries
+            .get_range(index..)?
+            .iter()
+            .enumerate()
+            .find_map(|(i, (key, value))| Some((index + i + 1, key, value.as_ref()?)))
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&Object, &Object)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, value)| Some((key, value.as_ref()?)))
+    }
+}
+
+impl std::ops::Index<&Object> for TableMap {
+    type Output = Object;
+    fn index(&self, key: &Object) -> &Object {
+        self.get(key).expect("key not found in table")
+    }
+}
+
+impl IntoIterator for TableMap {
+    type Item = (Object, Object);
+    type IntoIter = Box<dyn Iterator<Item = Self::Item>>;
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(
+            self.entries
+                .into_iter()
+                .filter_map(|(key, value)| Some((key, value?))),
+        )
+    }
+}
+
+impl<'a> IntoIterator for &'a TableMap {
+    type Item = (&'a Object, &'a Object);
+    type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+impl std::fmt::Debug for TableMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Table {
-    pub map: HashMap<Object, Object>,
+    pub map: TableMap,
+    pub delegate: Option<Object>,
+    pub(crate) allocation: super::memory::Allocation,
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Table {
     pub fn new() -> Self {
         Table {
-            map: HashMap::new(),
+            map: TableMap::new(),
+            delegate: None,
+            allocation: Default::default(),
         }
     }
 }
@@ -81,12 +354,339 @@ impl Table {
 #[derive(Clone)]
 pub struct Array {
     pub array: Vec<Object>,
+    pub(crate) allocation: super::memory::Allocation,
+}
+impl Default for Array {
+    fn default() -> Self {
+        Self::new()
+    }
 }
+
 impl Array {
     pub fn new() -> Self {
-        Array { array: Vec::new() }
+        Self::with_values(Vec::new())
+    }
+    pub fn with_values(array: Vec<Object>) -> Self {
+        Array {
+            array,
+            allocation: Default::default(),
+        }
     }
     pub fn reserve(&mut self, size: types::Integer) {
         self.array.reserve(size as usize);
     }
 }
+
+// a reference that doesn't keep its target alive. Values of weak references stored in tables,
+// arrays and instances are replaced by their targets when read.
+pub enum WeakRef {
+    Table(Weak<RefCell<Table>>),
+    Array(Weak<RefCell<Array>>),
+    Closure(Weak<Closure>),
+    NativeClosure(Weak<NativeClosure>),
+    Class(Weak<RefCell<Class>>),
+    Instance(Weak<RefCell<Instance>>),
+    Generator(Weak<RefCell<Generator>>),
+    Thread(Weak<RefCell<super::vm::Thread>>),
+    UserData(Weak<RefCell<UserData>>),
+}
+
+impl WeakRef {
+    // returns None for values that are not reference counted
+    pub fn new(obj: &Object) -> Option<WeakRef> {
+        Some(match obj {
+            Object::Table(t) => WeakRef::Table(Rc::downgrade(t)),
+            Object::Array(a) => WeakRef::Array(Rc::downgrade(a)),
+            Object::Closure(c) => WeakRef::Closure(Rc::downgrade(c)),
+            Object::NativeClosure(c) => WeakRef::NativeClosure(Rc::downgrade(c)),
+            Object::Class(c) => WeakRef::Class(Rc::downgrade(c)),
+            Object::Instance(i) => WeakRef::Instance(Rc::downgrade(i)),
+            Object::Generator(g) => WeakRef::Generator(Rc::downgrade(g)),
+            Object::Thread(t) => WeakRef::Thread(Rc::downgrade(t)),
+            Object::UserData(u) => WeakRef::UserData(Rc::downgrade(u)),
+            _ => return None,
+        })
+    }
+
+    // the target, or null once it has been released
+    pub fn get(&self) -> Object {
+        let target = match self {
+            WeakRef::Table(t) => t.upgrade().map(Object::Table),
+            WeakRef::Array(a) => a.upgrade().map(Object::Array),
+            WeakRef::Closure(c) => c.upgrade().map(Object::Closure),
+            WeakRef::NativeClosure(c) => c.upgrade().map(Object::NativeClosure),
+            WeakRef::Class(c) => c.upgrade().map(Object::Class),
+            WeakRef::Instance(i) => i.upgrade().map(Object::Instance),
+            WeakRef::Generator(g) => g.upgrade().map(Object::Generator),
+            WeakRef::Thread(t) => t.upgrade().map(Object::Thread),
+            WeakRef::UserData(u) => u.upgrade().map(Object::UserData),
+        };
+        target.unwrap_or(Object::Null)
+    }
+}
+
+pub type ReleaseHook = Box<dyn FnOnce(&mut dyn Any)>;
+
+// host data exposed to scripts. Scripts access it only through the delegate.
+pub struct UserData {
+    pub value: Box<dyn Any>,
+    pub delegate: Option<Object>,
+    // identifies the kind of host data, as sq_settypetag. 0 means no tag.
+    pub type_tag: usize,
+    // called with the value when the userdata is released
+    pub release_hook: Option<ReleaseHook>,
+}
+
+impl UserData {
+    pub fn new(value: impl Any) -> Self {
+        UserData {
+            value: Box::new(value),
+            delegate: None,
+            type_tag: 0,
+            release_hook: None,
+        }
+    }
+    pub fn check_type_tag(&self, type_tag: usize) -> Result<()> {
+        if self.type_tag != type_tag {
+            return Err(Error::RuntimeError("invalid type tag".to_string()));
+        }
+        Ok(())
+    }
+    pub fn get<T: Any>(&self) -> Result<&T> {
+        self.value
+            .downcast_ref()
+            .ok_or_else(|| userdata_type_error::<T>())
+    }
+    pub fn get_mut<T: Any>(&mut self) -> Result<&mut T> {
+        self.value
+            .downcast_mut()
+            .ok_or_else(|| userdata_type_error::<T>())
+    }
+}
+
+fn userdata_type_error<T>() -> Error {
+    Error::RuntimeError(format!(
+        "expected userdata of type {}",
+        std::any::type_name::<T>()
+    ))
+}
+
+impl Drop for UserData {
+    fn drop(&mut self) {
+        if let Some(hook) = self.release_hook.take() {
+            hook(&mut *self.value);
+        }
+    }
+}
+
+impl std::fmt::Debug for UserData {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "userdata({})", self.type_tag)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ClassMemberIndex {
+    Field(usize),
+    Method(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct ClassMember {
+    pub val: Object,
+    pub attributes: Object,
+}
+
+impl ClassMember {
+    fn new(val: Object) -> Self {
+        ClassMember {
+            val,
+            attributes: Object::Null,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub base: Option<Object>,
+    pub members: IndexMap<Object, ClassMemberIndex>,
+    pub defaultvalues: Vec<ClassMember>,
+    pub methods: Vec<ClassMember>,
+    pub attributes: Object,
+    pub constructor: Option<usize>,
+    // set once the class has been instantiated. Only methods can be added afterwards.
+    pub locked: bool,
+    pub(crate) allocation: super::memory::Allocation,
+}
+
+impl Class {
+    pub fn new(base: Option<Object>) -> Result<Self> {
+        let mut class = Class {
+            base: None,
+            members: IndexMap::new(),
+            defaultvalues: Vec::new(),
+            methods: Vec::new(),
+            attributes: Object::Null,
+            constructor: None,
+            locked: false,
+            allocation: Default::default(),
+        };
+        if let Some(base) = base {
+            {
+                let base_class = base.class()?;
+                class.members = base_class.members.clone();
+                class.defaultvalues = base_class.defaultvalues.clone();
+                class.methods = base_class.methods.clone();
+                class.constructor = base_class.constructor;
+            }
+            class.base = Some(base);
+        }
+        Ok(class)
+    }
+
+    pub fn new_slot(&mut self, key: Object, val: Object, is_static: bool) -> Result<()> {
+        let is_method = matches!(val, Object::Closure(_) | Object::NativeClosure(_)) || is_static;
+        if self.locked && !is_method {
+            return Err(Error::RuntimeError(
+                "trying to modify a class that has already been instantiated".to_string(),
+            ));
+        }
+        let existing = self.members.get(&key).copied();
+        if let Some(ClassMemberIndex::Field(idx)) = existing {
+            self.defaultvalues[idx].val = val;
+            return Ok(());
+        }
+        if !is_method {
+            self.members
+                .insert(key, ClassMemberIndex::Field(self.defaultvalues.len()));
+            self.defaultvalues.push(ClassMember::new(val));
+            return Ok(());
+        }
+        // methods inherited by a derived class need to know their base for `base` access
+        let val = match (&self.base, &val) {
+            (Some(base), Object::Closure(closure)) => {
+                super::gc::track(Object::Closure(Rc::new(Closure {
+                    func_proto: closure.func_proto.clone(),
+                    outervalues: closure.outervalues.clone(),
+                    defaultparams: closure.defaultparams.clone(),
+                    base: Some(base.clone()),
+                    allocation: Default::default(),
+                })))
+            }
+            _ => val,
+        };
+        match existing {
+            Some(ClassMemberIndex::Method(idx)) => self.methods[idx].val = val,
+            _ => {
+                if let Object::String(name) = &key {
+                    if &name[..] == "constructor" {
+                        self.constructor = Some(self.methods.len());
+                    }
+                }
+                self.members
+                    .insert(key, ClassMemberIndex::Method(self.methods.len()));
+                self.methods.push(ClassMember::new(val));
+            }
+        }
+        Ok(())
+    }
+
+    fn member(&self, key: &Object) -> Option<&ClassMember> {
+        match self.members.get(key)? {
+            ClassMemberIndex::Field(idx) => Some(&self.defaultvalues[*idx]),
+            ClassMemberIndex::Method(idx) => Some(&self.methods[*idx]),
+        }
+    }
+
+    pub fn get(&self, key: &Object) -> Option<Object> {
+        self.member(key).map(|member| member.val.clone())
+    }
+
+    pub fn get_attributes(&self, key: &Object) -> Option<Object> {
+        self.member(key).map(|member| member.attributes.clone())
+    }
+
+    pub fn set_attributes(&mut self, key: &Object, attributes: Object) -> Result<()> {
+        let member = match self.members.get(key) {
+            Some(ClassMemberIndex::Field(idx)) => &mut self.defaultvalues[*idx],
+            Some(ClassMemberIndex::Method(idx)) => &mut self.methods[*idx],
+            None => {
+                return Err(Error::RuntimeError(format!(
+                    "the index '{}' does not exist",
+                    key
+                )))
+            }
+        };
+        member.attributes = attributes;
+        Ok(())
+    }
+
+    pub fn constructor(&self) -> Option<Object> {
+        self.constructor.map(|idx| self.methods[idx].val.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub class: Object,
+    pub values: Vec<Object>,
+    // host data of instances of bound classes, see binding.rs
+    pub userdata: Option<Object>,
+    pub(crate) allocation: super::memory::Allocation,
+}
+
+impl Instance {
+    pub fn new(class: Object) -> Result<Self> {
+        let values = {
+            let mut class = class.class_mut()?;
+            class.locked = true;
+            class
+                .defaultvalues
+                .iter()
+                .map(|member| member.val.clone())
+                .collect()
+        };
+        Ok(Instance {
+            class,
+            values,
+            userdata: None,
+            allocation: Default::default(),
+        })
+    }
+
+    pub fn get(&self, key: &Object) -> Result<Option<Object>> {
+        let class = self.class.class()?;
+        Ok(match class.members.get(key) {
+            Some(ClassMemberIndex::Field(idx)) => Some(self.values[*idx].clone()),
+            Some(ClassMemberIndex::Method(idx)) => Some(class.methods[*idx].val.clone()),
+            None => None,
+        })
+    }
+
+    // only fields can be assigned. Returns false if key is not a field of the class.
+    pub fn set(&mut self, key: &Object, val: Object) -> Result<bool> {
+        let class = self.class.class()?;
+        Ok(match class.members.get(key) {
+            Some(ClassMemberIndex::Field(idx)) => {
+                self.values[*idx] = val;
+                true
+            }
+            _ => false,
+        })
+    }
+
+    pub fn instance_of(&self, class: &Rc<RefCell<Class>>) -> Result<bool> {
+        let mut current = self.class.clone();
+        loop {
+            let base = match &current {
+                Object::Class(c) if Rc::ptr_eq(c, class) => return Ok(true),
+                Object::Class(c) => c.borrow().base.clone(),
+                _ => None,
+            };
+            match base {
+                Some(base) => current = base,
+                None => return Ok(false),
+            }
+        }
+    }
+}
@@ -1,6 +1,9 @@
 #![allow(dead_code)]
-use crate::bytecode::{AppendArrayType, CompOp, NewObjectType, Opcode};
-use crate::{bytecode, object, types, Object};
+use crate::bytecode::{
+    AppendArrayType, BitwOp, CompOp, NewObjectType, Opcode, NEW_SLOT_ATTRIBUTES_FLAG,
+    NEW_SLOT_STATIC_FLAG, OUTER_TYPE_LOCAL,
+};
+use crate::{baselib, binding, bytecode, convert, gc, memory, object, raw_type, types, Object};
 use crate::{Error, Result};
 use core::ops::Range;
 use num_traits::FromPrimitive;
@@ -8,6 +11,12 @@ use std::cell::{Ref, RefCell, RefMut};
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::rc::Rc;
+use std::time::Instant;
+
+const DEFAULT_DEADLINE_CHECK_INTERVAL: u64 = 1024;
+const INITIAL_STACK_SIZE: usize = 1024;
+const DEFAULT_STACK_LIMIT: usize = 1024 * 1024;
+const DEFAULT_MAX_CALL_DEPTH: usize = 10000;
 
 #[derive(Copy, Clone, Debug)]
 struct StackFrame {
@@ -18,7 +27,13 @@ struct StackFrame {
 #[derive(Debug)]
 pub struct Stack {
     stack: Vec<RefCell<Object>>,
+    // maximum number of slots the stack can grow to
+    limit: usize,
     frame: StackFrame,
+    // outers referencing slots of this stack and the indices of their slots
+    open_outers: Vec<(usize, Rc<RefCell<object::Outer>>)>,
+    // value passed to suspend by a native function, handled once it returns
+    suspend_request: Option<Object>,
 }
 
 impl Display for Stack {
@@ -32,26 +47,29 @@ impl Display for Stack {
 }
 
 impl Stack {
-    fn new() -> Stack {
+    fn new(limit: usize) -> Stack {
         Stack {
-            stack: vec![RefCell::new(Object::Null); 1024 * 100],
+            stack: vec![RefCell::new(Object::Null); INITIAL_STACK_SIZE.min(limit)],
+            limit,
             frame: StackFrame { base: 1, top: 1 },
+            open_outers: Vec::new(),
+            suspend_request: None,
         }
     }
 
-    pub fn up(&mut self, pos: isize) -> RefMut<Object> {
+    pub fn up(&mut self, pos: isize) -> RefMut<'_, Object> {
         self.stack[(self.frame.top as isize + pos) as usize].borrow_mut()
     }
-    pub fn top(&mut self) -> RefMut<Object> {
+    pub fn top(&mut self) -> RefMut<'_, Object> {
         // &mut self.stack[self.frame.top - 1]
         self.up(-1)
     }
 
-    fn value(&self, pos: types::Integer) -> Ref<Object> {
+    fn value(&self, pos: types::Integer) -> Ref<'_, Object> {
         self.stack[(self.frame.base + pos) as usize].borrow()
     }
 
-    fn value_mut(&mut self, pos: types::Integer) -> RefMut<Object> {
+    fn value_mut(&mut self, pos: types::Integer) -> RefMut<'_, Object> {
         self.stack[(self.frame.base + pos) as usize].borrow_mut()
         // self.stack
         //         .get_mut((self.frame.base + pos) as usize)
@@ -67,19 +85,75 @@ impl Stack {
         );
     }
     fn get_frame(&self) -> StackFrame {
-        self.frame.clone()
+        self.frame
     }
     fn set_frame(&mut self, frame: StackFrame) {
         self.frame = frame;
     }
+    // sets a new frame, growing the stack if needed
+    fn enter_frame(&mut self, frame: StackFrame) -> Result<()> {
+        // the slot at top is accessed by native functions
+        self.reserve(frame.top as usize + 1)?;
+        self.frame = frame;
+        Ok(())
+    }
+    // grows the stack to at least size slots
+    fn reserve(&mut self, size: usize) -> Result<()> {
+        if size > self.limit {
+            return Err(Error::StackOverflow);
+        }
+        self.grow(size);
+        Ok(())
+    }
+    fn grow(&mut self, size: usize) {
+        if size > self.stack.len() {
+            let new_len = (self.stack.len() * 2).max(size);
+            self.stack
+                .resize_with(new_len, || RefCell::new(Object::Null));
+        }
+    }
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // releases the values of the current frame. Used when a call returns so that its locals
+    // don't keep objects alive.
+    fn clear_frame(&mut self) {
+        for slot in &self.stack[self.frame.base as usize..self.frame.top as usize] {
+            *slot.borrow_mut() = Object::Null;
+        }
+    }
+
+    // slots above the current frame can keep the values of returned root calls. They are
+    // cleared before looking for unreachable objects.
+    fn clear_unused(&mut self) {
+        for slot in &self.stack[self.frame.top as usize..] {
+            *slot.borrow_mut() = Object::Null;
+        }
+    }
 
     pub fn pop(&mut self, num: types::Integer) {
         self.frame.top -= num;
     }
 
-    pub fn push(&mut self, obj: Object) {
-        self.stack[self.frame.top as usize].swap(&mut RefCell::new(obj));
+    pub fn push(&mut self, obj: Object) -> Result<()> {
+        // the slot at the new top is accessed by native functions, as in enter_frame
+        self.reserve(self.frame.top as usize + 2)?;
+        self.stack[self.frame.top as usize].swap(&RefCell::new(obj));
         self.frame.top += 1;
+        Ok(())
+    }
+
+    // pushes all values or, if the stack overflows, none of them
+    fn push_all(&mut self, values: impl IntoIterator<Item = Object>) -> Result<()> {
+        let top = self.frame.top;
+        for value in values {
+            if let Err(err) = self.push(value) {
+                self.frame.top = top;
+                return Err(err);
+            }
+        }
+        Ok(())
     }
 
     fn set_arg0(&mut self, instr: &bytecode::Instruction, value: Object) {
@@ -97,29 +171,29 @@ impl Stack {
     fn set_target(&mut self, instr: &bytecode::Instruction, value: Object) {
         self.set_arg0(instr, value);
     }
-    fn get_arg0(&self, instr: &bytecode::Instruction) -> Ref<Object> {
+    fn get_arg0(&self, instr: &bytecode::Instruction) -> Ref<'_, Object> {
         self.value(instr.arg0 as types::Integer)
     }
-    fn get_arg1(&self, instr: &bytecode::Instruction) -> Ref<Object> {
+    fn get_arg1(&self, instr: &bytecode::Instruction) -> Ref<'_, Object> {
         self.value(instr.arg1 as types::Integer)
     }
-    fn get_arg2(&self, instr: &bytecode::Instruction) -> Ref<Object> {
+    fn get_arg2(&self, instr: &bytecode::Instruction) -> Ref<'_, Object> {
         self.value(instr.arg2 as types::Integer)
     }
-    fn get_arg3(&self, instr: &bytecode::Instruction) -> Ref<Object> {
+    fn get_arg3(&self, instr: &bytecode::Instruction) -> Ref<'_, Object> {
         self.value(instr.arg3 as types::Integer)
     }
 
-    fn get_arg0_mut(&mut self, instr: &bytecode::Instruction) -> RefMut<Object> {
+    fn get_arg0_mut(&mut self, instr: &bytecode::Instruction) -> RefMut<'_, Object> {
         self.value_mut(instr.arg0 as types::Integer)
     }
-    fn get_arg1_mut(&mut self, instr: &bytecode::Instruction) -> RefMut<Object> {
+    fn get_arg1_mut(&mut self, instr: &bytecode::Instruction) -> RefMut<'_, Object> {
         self.value_mut(instr.arg1 as types::Integer)
     }
-    fn get_arg2_mut(&mut self, instr: &bytecode::Instruction) -> RefMut<Object> {
+    fn get_arg2_mut(&mut self, instr: &bytecode::Instruction) -> RefMut<'_, Object> {
         self.value_mut(instr.arg2 as types::Integer)
     }
-    fn get_arg3_mut(&mut self, instr: &bytecode::Instruction) -> RefMut<Object> {
+    fn get_arg3_mut(&mut self, instr: &bytecode::Instruction) -> RefMut<'_, Object> {
         self.value_mut(instr.arg3 as types::Integer)
     }
 
@@ -139,12 +213,103 @@ impl Stack {
         println!(" ---");
     }
 
+    // returns the outer referencing the local at pos, creating it if no closure captured it yet
+    fn find_outer(&mut self, pos: types::Integer) -> Object {
+        let index = (self.frame.base + pos) as usize;
+        let existing = self.open_outers.iter().find(|(idx, _)| *idx == index);
+        let outer = match existing {
+            Some((_, outer)) => outer.clone(),
+            None => {
+                let outer = Rc::new(RefCell::new(object::Outer::Open(index)));
+                gc::track(Object::Outer(outer.clone()));
+                self.open_outers.push((index, outer.clone()));
+                outer
+            }
+        };
+        Object::Outer(outer)
+    }
+
+    // closes all open outers referencing locals at or above pos
+    fn close_outers(&mut self, pos: types::Integer) {
+        let index = (self.frame.base + pos) as usize;
+        let stack = &self.stack;
+        self.open_outers.retain(|(idx, outer)| {
+            if *idx < index {
+                return true;
+            }
+            *outer.borrow_mut() = object::Outer::Closed(stack[*idx].borrow().clone());
+            false
+        });
+    }
+
+    // open outers index into the active stack. Before another stack becomes active, e.g. when
+    // a thread runs, they take the values of their slots so that closures shared with the
+    // other stack keep working on the same variables.
+    fn park_outers(&mut self) {
+        for (idx, outer) in &self.open_outers {
+            let value = std::mem::replace(&mut *self.stack[*idx].borrow_mut(), Object::Null);
+            *outer.borrow_mut() = object::Outer::Closed(value);
+        }
+    }
+
+    // moves the values of parked outers back into their slots
+    fn unpark_outers(&mut self) {
+        for (idx, outer) in &self.open_outers {
+            let mut outer = outer.borrow_mut();
+            if let object::Outer::Closed(value) = &mut *outer {
+                *self.stack[*idx].borrow_mut() = std::mem::replace(value, Object::Null);
+            }
+            *outer = object::Outer::Open(*idx);
+        }
+    }
+
+    fn get_outer(&self, outer: &Object) -> Result<Object> {
+        match outer {
+            Object::Outer(outer) => match &*outer.borrow() {
+                object::Outer::Open(idx) => Ok(self.stack[*idx].borrow().clone()),
+                object::Outer::Closed(value) => Ok(value.clone()),
+            },
+            _ => Err(Error::RuntimeError(format!(
+                "expected outer. found {}",
+                outer
+            ))),
+        }
+    }
+
+    fn set_outer(&mut self, outer: &Object, value: Object) -> Result<()> {
+        match outer {
+            Object::Outer(outer) => match &mut *outer.borrow_mut() {
+                object::Outer::Open(idx) => *self.stack[*idx].borrow_mut() = value,
+                object::Outer::Closed(closed) => *closed = value,
+            },
+            _ => {
+                return Err(Error::RuntimeError(format!(
+                    "expected outer. found {}",
+                    outer
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    // suspends the vm once the currently running native function returns. The executor reports
+    // value to the host and the call evaluates to the value the vm is resumed with.
+    pub fn suspend(&mut self, value: Object) {
+        self.suspend_request = Some(value);
+    }
+
     pub fn slice_mut(&mut self, r: Range<types::Integer>) -> &[RefCell<Object>] {
         &mut self.stack
             [((r.start + self.frame.base) as usize)..((r.end + self.frame.base) as usize)]
     }
 }
 
+#[derive(Clone, Debug)]
+pub(crate) struct ExceptionTrap {
+    ip: types::Integer,
+    target: types::Integer,
+}
+
 #[derive(Clone)]
 struct CallInfo {
     prevframe: StackFrame,
@@ -152,6 +317,11 @@ struct CallInfo {
     closure: Object,
     ip: types::Integer,
     root: bool,
+    traps: Vec<ExceptionTrap>,
+    // generator running in this frame
+    generator: Option<Rc<RefCell<object::Generator>>>,
+    // a constructor returns the instance it runs on instead of its return value
+    constructor: bool,
 
     target: Option<types::Integer>,
 }
@@ -178,10 +348,180 @@ impl Profiling {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThreadStatus {
+    Idle,
+    Running,
+    Suspended,
+}
+
+impl ThreadStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThreadStatus::Idle => "idle",
+            ThreadStatus::Running => "running",
+            ThreadStatus::Suspended => "suspended",
+        }
+    }
+}
+
+// call target waiting for the value a suspended vm is woken up with
+#[derive(Debug, Clone, Copy)]
+struct Suspension {
+    target: Option<types::Integer>,
+}
+
+#[derive(Debug)]
+pub enum ExecutionState {
+    Returned(Object),
+    // the script called suspend or a native function requested suspension with the value
+    Suspended(Object),
+}
+
+// a script context with its own stack and callstack. It runs on the executor it was created by
+// and shares its root table.
+pub struct Thread {
+    stack: Stack,
+    callstack: Vec<CallInfo>,
+    suspended: Option<Suspension>,
+    closure: Object,
+    status: ThreadStatus,
+}
+
+impl Thread {
+    pub fn new(closure: Object, stack_limit: usize) -> Thread {
+        Thread {
+            stack: Stack::new(stack_limit),
+            callstack: Vec::new(),
+            suspended: None,
+            closure,
+            status: ThreadStatus::Idle,
+        }
+    }
+    pub fn status(&self) -> ThreadStatus {
+        self.status
+    }
+}
+
+impl std::fmt::Debug for Thread {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "thread({})", self.status.name())
+    }
+}
+
+// delegates of the builtin types, consulted for keys the object itself does not have
+pub(crate) struct DefaultDelegates {
+    pub table: Object,
+    pub array: Object,
+    pub closure: Object,
+    pub class: Object,
+    pub instance: Object,
+    pub generator: Object,
+    pub thread: Object,
+    pub weakref: Object,
+}
+
+// the arguments of a native function call. `this` is not counted as an argument.
+pub struct CallContext<'a> {
+    executor: &'a mut Executor,
+    args: Vec<Object>,
+}
+
+impl CallContext<'_> {
+    pub fn executor(&mut self) -> &mut Executor {
+        self.executor
+    }
+    pub fn this(&self) -> &Object {
+        &self.args[0]
+    }
+    pub fn num_args(&self) -> usize {
+        self.args.len() - 1
+    }
+    pub fn arg(&self, n: usize) -> Result<&Object> {
+        self.args
+            .get(n + 1)
+            .ok_or_else(|| Error::RuntimeError(format!("parameter {} is missing", n + 1)))
+    }
+    pub fn integer_arg(&self, n: usize) -> Result<types::Integer> {
+        match self.arg(n)? {
+            Object::Integer(i) => Ok(*i),
+            Object::Float(f) => Ok(*f as types::Integer),
+            other => Err(arg_type_error(n + 1, other, "integer")),
+        }
+    }
+    pub fn float_arg(&self, n: usize) -> Result<types::Float> {
+        match self.arg(n)? {
+            Object::Integer(i) => Ok(*i as types::Float),
+            Object::Float(f) => Ok(*f),
+            other => Err(arg_type_error(n + 1, other, "float")),
+        }
+    }
+    pub fn bool_arg(&self, n: usize) -> Result<bool> {
+        match self.arg(n)? {
+            Object::Bool(b) => Ok(*b),
+            other => Err(arg_type_error(n + 1, other, "bool")),
+        }
+    }
+    pub fn string_arg(&self, n: usize) -> Result<&str> {
+        match self.arg(n)? {
+            Object::String(s) => Ok(s),
+            other => Err(arg_type_error(n + 1, other, "string")),
+        }
+    }
+}
+
+// n counts `this` as parameter 0
+fn arg_type_error(n: usize, found: &Object, expected: &str) -> Error {
+    Error::RuntimeError(format!(
+        "parameter {} has an invalid type '{}' ; expected: '{}'",
+        n,
+        found.typesystem_name(),
+        expected
+    ))
+}
+
+fn typemask_names(mask: isize) -> String {
+    let names = [
+        (raw_type::NULL, "null"),
+        (raw_type::INTEGER, "integer"),
+        (raw_type::FLOAT, "float"),
+        (raw_type::BOOL, "bool"),
+        (raw_type::STRING, "string"),
+        (raw_type::TABLE, "table"),
+        (raw_type::ARRAY, "array"),
+        (raw_type::USERDATA, "userdata"),
+        (raw_type::CLOSURE | raw_type::NATIVECLOSURE, "function"),
+        (raw_type::GENERATOR, "generator"),
+        (raw_type::USERPOINTER, "userpointer"),
+        (raw_type::THREAD, "thread"),
+        (raw_type::CLASS, "class"),
+        (raw_type::INSTANCE, "instance"),
+        (raw_type::WEAKREF, "weakref"),
+    ];
+    names
+        .iter()
+        .filter(|(t, _)| mask & t != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
 pub struct Executor {
     stack: Stack,
     callstack: Vec<CallInfo>,
+    max_call_depth: usize,
+    suspended: Option<Suspension>,
+    delegates: DefaultDelegates,
+    instruction_budget: Option<u64>,
+    deadline: Option<Instant>,
+    deadline_check_interval: u64,
+    deadline_countdown: u64,
     roottable: Object,
+    // delegates of userdata by type tag
+    type_delegates: HashMap<usize, Object>,
+    memory: Rc<memory::Accountant>,
+    // the objects checked for reference cycles by collect_garbage
+    gc: Rc<gc::Registry>,
     profiling: Profiling,
     pub trace_call_return: bool,
     pub instr_profiling: bool,
@@ -202,56 +542,62 @@ enum LoopState {
         num_args: types::Integer,
         arg_offset: types::Integer,
     },
+    Resume {
+        generator: Object,
+        target: types::Integer,
+    },
 }
 
 macro_rules! arith {
-    ($op:tt, $self:expr, $instr:expr) => {
-        {
-            let res = {
-                let op1 = $self.stack.get_arg2($instr);
-                let op2 = $self.stack.get_arg1($instr);
-
-                match (&*op1, &*op2) {
-                    (Object::Integer(int1), Object::Integer(int2)) => Object::Integer(int1 $op int2),
-                    (Object::String(str1), _) => Object::new_string(&format!("{}{}",str1, op2)), // FIXME: this is crappy
-                    // (Object::String(str1), _) => match "$op" {
-                    //     "+" => Object::new_string(&format!("{}{}",str1, op2)),
-                    //     _ => return Err(Error::RuntimeError(format!(
-                    //         "unhandled operands {:?} {} {:?}",
-                    //         op1, "$op", op2
-                    //     ))),
-                    // }
-                    _ => {
-                        return Err(Error::RuntimeError(format!(
-                            "unhandled operands {:?} {:?}",
-                            op1, op2
-                        )))
-                    }
-                }
-            };
+    ($op:expr, $self:expr, $instr:expr) => {{
+        let op1 = $self.stack.get_arg2($instr).clone();
+        let op2 = $self.stack.get_arg1($instr).clone();
+        let res = $self.arith($op, &op1, &op2)?;
         $self.stack.set_target($instr, res);
         LoopState::Continue
     }};
 }
 
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Executor {
     pub fn new() -> Executor {
-        Executor {
-            stack: Stack::new(),
+        let gc = Rc::new(gc::Registry::default());
+        let _active = gc.activate();
+        let mut exec = Executor {
+            stack: Stack::new(DEFAULT_STACK_LIMIT),
             callstack: Vec::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            suspended: None,
+            instruction_budget: None,
+            deadline: None,
+            deadline_check_interval: DEFAULT_DEADLINE_CHECK_INTERVAL,
+            deadline_countdown: DEFAULT_DEADLINE_CHECK_INTERVAL,
+            delegates: baselib::default_delegates(),
             profiling: Profiling::new(),
             roottable: Object::new_table(),
+            type_delegates: HashMap::new(),
+            memory: Rc::default(),
+            gc: gc.clone(),
             trace_call_return: false,
             instr_profiling: false,
-        }
+        };
+        baselib::register(&mut exec);
+        exec
     }
     pub fn stack(&mut self) -> &mut Stack {
         &mut self.stack
     }
-    pub fn call(&mut self, num_params: types::Integer, retval: bool) -> Result<()> {
+    pub fn call(&mut self, num_params: types::Integer, _retval: bool) -> Result<()> {
+        let _active = self.gc.activate();
         let top = self.stack.frame.top;
 
         let closure = self.stack.up(-((num_params + 1) as isize)).clone();
+        self.stack.pop(num_params);
         self.start_call(
             closure,
             Some(top - num_params),
@@ -262,8 +608,6 @@ impl Executor {
             .last_mut()
             .ok_or_else(|| Error::RuntimeError("empty callstack".to_string()))?
             .root = true;
-
-        self.stack.pop(num_params);
         Ok(())
         // } else {
         //     Err(Error::RuntimeError(format!(
@@ -277,37 +621,327 @@ impl Executor {
         &mut self,
         closure: Object,
         target: Option<types::Integer>,
-        num_params: types::Integer,
+        num_args: types::Integer,
         stackbase: types::Integer,
     ) -> Result<()> {
         let func = closure.closure_ref()?.func_proto.func_proto_ref()?;
         let newtop = stackbase + func.stacksize;
+        if self.callstack.len() >= self.max_call_depth {
+            return Err(Error::StackOverflow);
+        }
+        let prevframe = self.stack.get_frame();
+        self.stack.enter_frame(StackFrame {
+            base: stackbase,
+            top: newtop,
+        })?;
+        // the arguments are at the start of the new frame
+        if let Err(err) = self.setup_params(closure.closure_ref()?, num_args, 0) {
+            self.stack.set_frame(prevframe);
+            return Err(err);
+        }
 
         self.callstack.push(CallInfo {
-            prevframe: self.stack.get_frame(),
-            closure: closure,
+            prevframe,
+            closure,
             ip: 0,
             root: false,
-            target: target,
+            traps: Vec::new(),
+            generator: None,
+            constructor: false,
+            target,
         });
 
-        self.stack.set_frame(StackFrame {
-            base: stackbase,
-            top: newtop,
-        });
+        Ok(())
+    }
 
+    // fills in missing arguments from the default parameters or collects the variable arguments
+    // into vargv. The arguments start at base relative to the current frame.
+    fn setup_params(
+        &mut self,
+        closure: &object::Closure,
+        num_args: types::Integer,
+        base: types::Integer,
+    ) -> Result<()> {
+        let func = closure.func_proto.func_proto_ref()?;
+        let mut num_params = func.parameters.len() as types::Integer;
+        if func.varparams {
+            num_params -= 1;
+            if num_args < num_params {
+                return Err(Error::RuntimeError(
+                    "wrong number of parameters".to_string(),
+                ));
+            }
+            let vargv = (num_params..num_args)
+                .map(|i| std::mem::replace(&mut *self.stack.value_mut(base + i), Object::Null))
+                .collect();
+            let vargv = self.account(gc::track(Object::Array(Rc::new(RefCell::new(
+                object::Array::with_values(vargv),
+            )))))?;
+            *self.stack.value_mut(base + num_params) = vargv;
+        } else if num_args != num_params {
+            let missing = num_params - num_args;
+            let ndefault = closure.defaultparams.len() as types::Integer;
+            if missing < 0 || missing > ndefault {
+                return Err(Error::RuntimeError(
+                    "wrong number of parameters".to_string(),
+                ));
+            }
+            for (i, value) in closure.defaultparams[(ndefault - missing) as usize..]
+                .iter()
+                .enumerate()
+            {
+                *self.stack.value_mut(base + num_args + i as types::Integer) = value.clone();
+            }
+        }
         Ok(())
     }
 
     pub fn execute(&mut self) -> Result<Object> {
-        let mut ci = self
-            .callstack
-            .last_mut()
-            .ok_or_else(|| Error::RuntimeError("callstack empty".to_string()))?;
+        match self.execute_resumable()? {
+            ExecutionState::Returned(retval) => Ok(retval),
+            ExecutionState::Suspended(_) => {
+                // the suspended call can't be continued, so it is aborted
+                self.suspended = None;
+                self.unwind();
+                Err(Error::RuntimeError(
+                    "the vm was suspended. use execute_resumable to run suspendable scripts"
+                        .to_string(),
+                ))
+            }
+        }
+    }
+
+    // like execute, but a suspension of the vm is returned to the caller which can continue the
+    // execution with resume. Calling it again after Error::BudgetExhausted continues where the
+    // budget ran out.
+    pub fn execute_resumable(&mut self) -> Result<ExecutionState> {
+        let _active = self.gc.activate();
+        loop {
+            match self.run() {
+                Err(err) => self.handle_error(err)?,
+                Ok(state) => return Ok(state),
+            }
+        }
+    }
+
+    // continues a suspended vm. value becomes the result of the call that suspended it.
+    pub fn resume(&mut self, value: Object) -> Result<ExecutionState> {
+        let suspension = self
+            .suspended
+            .take()
+            .ok_or_else(|| Error::RuntimeError("the vm is not suspended".to_string()))?;
+        if let Some(target) = suspension.target {
+            *self.stack.value_mut(target) = value;
+        }
+        self.execute_resumable()
+    }
+
+    fn swap_thread(&mut self, thread: &mut Thread) {
+        self.stack.park_outers();
+        std::mem::swap(&mut self.stack, &mut thread.stack);
+        self.stack.unpark_outers();
+        std::mem::swap(&mut self.callstack, &mut thread.callstack);
+        std::mem::swap(&mut self.suspended, &mut thread.suspended);
+    }
+
+    // runs the thread with the given arguments, or wakes it up with a value if it is suspended.
+    // Returns the value the thread suspended with or its return value.
+    pub(crate) fn run_thread(
+        &mut self,
+        thread: &Rc<RefCell<Thread>>,
+        args: Vec<Object>,
+    ) -> Result<Object> {
+        let status = thread.borrow().status;
+        let closure = thread.borrow().closure.clone();
+        if status == ThreadStatus::Running {
+            return Err(Error::RuntimeError(
+                "the thread is already running".to_string(),
+            ));
+        }
+        self.swap_thread(&mut thread.borrow_mut());
+        thread.borrow_mut().status = ThreadStatus::Running;
+        let res = if status == ThreadStatus::Suspended {
+            self.resume(args.into_iter().next().unwrap_or(Object::Null))
+        } else {
+            let num_args = args.len() as types::Integer + 1;
+            let values = vec![closure, self.roottable.clone()]
+                .into_iter()
+                .chain(args);
+            if let Err(err) = self.stack.push_all(values) {
+                self.swap_thread(&mut thread.borrow_mut());
+                thread.borrow_mut().status = status;
+                return Err(err);
+            }
+            self.call(num_args, true)
+                .and_then(|_| self.execute_resumable())
+        };
+        let (status, res) = match res {
+            Ok(ExecutionState::Suspended(value)) => (ThreadStatus::Suspended, Ok(value)),
+            Ok(ExecutionState::Returned(value)) => {
+                self.stack.pop(1);
+                (ThreadStatus::Idle, Ok(value))
+            }
+            Err(err) => {
+                // an interrupted thread can't be continued through the native call
+                let err = match err {
+                    Error::BudgetExhausted => {
+                        self.unwind();
+                        Error::Terminated("instruction budget exhausted".to_string())
+                    }
+                    err => err,
+                };
+                self.stack.pop(1);
+                (ThreadStatus::Idle, Err(err))
+            }
+        };
+        self.swap_thread(&mut thread.borrow_mut());
+        thread.borrow_mut().status = status;
+        res
+    }
+
+    // unwinds the callstack to the nearest exception trap and continues at its handler. Errors
+    // not caught before leaving the root frame of this execution are returned.
+    fn handle_error(&mut self, err: Error) -> Result<()> {
+        let exception = match &err {
+            Error::RuntimeError(msg) => Object::new_string(msg),
+            Error::Exception(obj) => obj.clone(),
+            Error::StackOverflow => Object::new_string("stack overflow"),
+            Error::Terminated(_) => {
+                self.unwind();
+                return Err(err);
+            }
+            _ => return Err(err),
+        };
+        loop {
+            let ci = self
+                .callstack
+                .last_mut()
+                .ok_or_else(|| Error::RuntimeError("callstack empty".to_string()))?;
+            if let Some(trap) = ci.traps.pop() {
+                ci.ip = trap.ip;
+                *self.stack.value_mut(trap.target) = exception;
+                return Ok(());
+            }
+            if self.pop_frame() {
+                return Err(err);
+            }
+        }
+    }
+
+    // pops the current frame of an aborted call. Returns true if it was the root frame.
+    fn pop_frame(&mut self) -> bool {
+        match self.callstack.pop() {
+            Some(ci) => {
+                if let Some(generator) = &ci.generator {
+                    generator.borrow_mut().kill();
+                }
+                self.stack.close_outers(0);
+                self.stack.set_frame(ci.prevframe);
+                ci.root
+            }
+            None => true,
+        }
+    }
+
+    // aborts the current execution up to and including its root frame
+    fn unwind(&mut self) {
+        while !self.pop_frame() {}
+    }
+
+    // maximum number of stack slots. Applies to threads created afterwards as well.
+    pub fn set_stack_limit(&mut self, limit: usize) {
+        self.stack.limit = limit;
+    }
+
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    pub fn set_instruction_budget(&mut self, budget: Option<u64>) {
+        self.instruction_budget = budget;
+    }
+
+    // remaining number of instructions the vm is allowed to execute
+    pub fn instruction_budget(&self) -> Option<u64> {
+        self.instruction_budget
+    }
+
+    // the clock is checked every check_interval instructions
+    pub fn set_deadline(&mut self, deadline: Option<Instant>, check_interval: u64) {
+        self.deadline = deadline;
+        self.deadline_check_interval = check_interval.max(1);
+        self.deadline_countdown = self.deadline_check_interval;
+    }
+
+    // userdata created by new_userdata with this type tag and no delegate of its own gets
+    // the delegate
+    pub fn set_type_delegate(&mut self, type_tag: usize, delegate: Object) -> Result<()> {
+        if type_tag == 0 {
+            return Err(Error::RuntimeError("invalid type tag".to_string()));
+        }
+        if !matches!(delegate, Object::Table(_)) {
+            return Err(Error::RuntimeError(format!(
+                "expected table. found {}",
+                delegate.type_name()
+            )));
+        }
+        self.type_delegates.insert(type_tag, delegate);
+        Ok(())
+    }
+
+    pub fn new_userdata(&self, mut userdata: object::UserData) -> Object {
+        if userdata.delegate.is_none() {
+            userdata.delegate = self.type_delegates.get(&userdata.type_tag).cloned();
+        }
+        let userdata = Object::UserData(Rc::new(RefCell::new(userdata)));
+        self.gc.add(&userdata);
+        userdata
+    }
+
+    // estimated memory held by the objects created by scripts
+    pub fn memory_stats(&self) -> memory::MemoryStats {
+        self.memory.stats()
+    }
+
+    // allocations that would exceed the limit fail with a catchable "out of memory" error
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory.set_limit(limit);
+    }
+
+    fn current_func(&self) -> Result<Rc<object::FuncProto>> {
+        self.callstack
+            .last()
+            .ok_or_else(|| Error::RuntimeError("callstack empty".to_string()))?
+            .closure
+            .closure_ref()?
+            .func_proto
+            .func_proto()
+    }
 
-        let mut func = ci.closure.closure_ref()?.func_proto.func_proto()?;
+    fn run(&mut self) -> Result<ExecutionState> {
+        let mut func = self.current_func()?;
 
         loop {
+            if let Some(budget) = &mut self.instruction_budget {
+                if *budget == 0 {
+                    return Err(Error::BudgetExhausted);
+                }
+                *budget -= 1;
+            }
+            if let Some(deadline) = self.deadline {
+                self.deadline_countdown -= 1;
+                if self.deadline_countdown == 0 {
+                    self.deadline_countdown = self.deadline_check_interval;
+                    if Instant::now() >= deadline {
+                        return Err(Error::Terminated("deadline exceeded".to_string()));
+                    }
+                }
+            }
+            // handlers calling back into the executor must not use ci afterwards
+            let ci = self
+                .callstack
+                .last_mut()
+                .ok_or_else(|| Error::RuntimeError("callstack empty".to_string()))?;
             let instr = &func.instructions[ci.ip as usize];
             ci.ip += 1;
 
@@ -318,6 +952,13 @@ impl Executor {
                 self.profiling.instruction(instr);
             }
             let state = match opcode {
+                Opcode::LOADFLOAT => {
+                    self.stack.set_target(
+                        instr,
+                        Object::Float(f32::from_bits(instr.arg1 as u32) as types::Float),
+                    );
+                    LoopState::Continue
+                }
                 Opcode::LOADINT => {
                     // *self.stack.value_mut(instr.arg0 as types::Integer) =
                     self.stack
@@ -341,7 +982,8 @@ impl Executor {
                 }
                 Opcode::TYPEOF => {
                     // dest = SQString::Create(_ss(this),GetTypeName(obj1));
-                    let name = Object::new_string(self.stack.get_arg1(instr).typesystem_name());
+                    let obj = self.stack.get_arg1(instr).clone();
+                    let name = self.type_of(&obj)?;
                     self.stack.set_target(instr, name);
                     LoopState::Continue
                 }
@@ -350,127 +992,179 @@ impl Executor {
                     self.stack.set_target(instr, src);
                     LoopState::Continue
                 }
-                Opcode::ADD => arith!(+,self, instr),
-                Opcode::SUB => arith!(-,self, instr),
-                Opcode::MUL => arith!(*,self, instr),
-                Opcode::DIV => arith!(/,self, instr),
-                Opcode::MOD => arith!(%,self, instr),
-
-                Opcode::EQ => {
-                    // if instr.arg3 != 0 {
-                    //     return Err(Error::RuntimeError(
-                    //         "literal compare not implemented".to_string(),
-                    //     ));
-                    // }
-                    let res = {
-                        let op1 = self.stack.get_arg2(instr);
-                        let op2 = if instr.arg3 != 0 {
-                            func.literals[instr.arg1 as usize].clone()
-                        } else {
-                            let x = self.stack.get_arg1(instr);
-                            x.clone()
-                        };
-                        match (&*op1, &op2) {
-                            (Object::Integer(int1), Object::Integer(int2)) => {
-                                Object::Bool(*int1 == *int2)
-                            }
-                            (Object::String(op1), Object::String(op2)) => {
-                                Object::Bool(*op1 == *op2)
-                            }
-                            _ => {
-                                return Err(Error::RuntimeError(format!(
-                                    "unhandled operands {} {}",
-                                    op1, op2
-                                )))
-                            }
-                        }
+                Opcode::ADD => arith!('+', self, instr),
+                Opcode::SUB => arith!('-', self, instr),
+                Opcode::MUL => arith!('*', self, instr),
+                Opcode::DIV => arith!('/', self, instr),
+                Opcode::MOD => arith!('%', self, instr),
+                Opcode::NEG => {
+                    let obj = self.stack.get_arg1(instr).clone();
+                    let res = match &obj {
+                        Object::Integer(i) => Object::Integer(i.wrapping_neg()),
+                        Object::Float(f) => Object::Float(-f),
+                        _ => self
+                            .call_metamethod(&obj, "_unm", Vec::new())?
+                            .ok_or_else(|| {
+                                Error::RuntimeError(format!(
+                                    "attempt to negate a {}",
+                                    obj.typesystem_name()
+                                ))
+                            })?,
                     };
                     self.stack.set_target(instr, res);
-
                     LoopState::Continue
                 }
-                Opcode::JZ => {
-                    let cond = self.stack.get_arg0(instr);
-                    let b = match *cond {
-                        Object::Bool(b) => b,
-                        Object::Integer(i) => i != 0 as types::Integer,
-                        Object::Null => false,
-                        Object::String(_) => true,
-                        _ => {
-                            return Err(Error::RuntimeError(format!(
-                                "unsopported condition in JZ {:?}",
-                                cond
-                            )))
-                        }
+                Opcode::CMP => {
+                    let op1 = self.stack.get_arg2(instr).clone();
+                    let op2 = self.stack.get_arg1(instr).clone();
+                    let res = self.cmp_op(instr.arg3, &op1, &op2)?;
+                    self.stack.set_target(instr, res);
+                    LoopState::Continue
+                }
+
+                Opcode::EQ | Opcode::NE => {
+                    let op2 = if instr.arg3 != 0 {
+                        func.literals[instr.arg1 as usize].clone()
+                    } else {
+                        self.stack.get_arg1(instr).clone()
                     };
+                    let eq = is_equal(&self.stack.get_arg2(instr), &op2);
+                    self.stack
+                        .set_target(instr, Object::Bool(eq == (opcode == Opcode::EQ)));
 
-                    if !b {
+                    LoopState::Continue
+                }
+                Opcode::JZ => {
+                    if is_false(&self.stack.get_arg0(instr)) {
+                        ci.ip += instr.arg1 as types::Integer;
+                    }
+                    LoopState::Continue
+                }
+                Opcode::AND | Opcode::OR => {
+                    let obj = self.stack.get_arg2(instr).clone();
+                    if is_false(&obj) == (opcode == Opcode::AND) {
+                        self.stack.set_target(instr, obj);
                         ci.ip += instr.arg1 as types::Integer;
                     }
                     LoopState::Continue
                 }
+                Opcode::NOT => {
+                    let res = is_false(&self.stack.get_arg1(instr));
+                    self.stack.set_target(instr, Object::Bool(res));
+                    LoopState::Continue
+                }
                 Opcode::JMP => {
                     ci.ip += instr.arg1 as types::Integer;
                     // println!("JMP {} {} -> {}", instr.arg1 as types::Integer, o, ci.ip);
                     LoopState::Continue
                 }
                 Opcode::JCMP => {
-                    let op1 = self.stack.get_arg2(instr);
-                    let op2 = self.stack.get_arg0(instr);
-
-                    let r = match (&*op1, &*op2) {
-                        (Object::Integer(int1), Object::Integer(int2)) => {
-                            if *int1 == *int2 {
-                                0
-                            } else if int1 < int2 {
-                                -1
-                            } else {
-                                1
-                            }
-                        }
-                        _ => {
-                            return Err(Error::RuntimeError(format!(
-                                "unhandled operands {:?} {:?}",
-                                op1, op2
-                            )))
-                        }
-                    };
-
-                    let res = match <CompOp as FromPrimitive>::from_u8(instr.arg3) {
-                        Some(CompOp::G) => Object::Bool(r > 0),
-                        Some(CompOp::GE) => Object::Bool(r >= 0),
-                        Some(CompOp::L) => Object::Bool(r < 0),
-                        Some(CompOp::LE) => Object::Bool(r <= 0),
-                        Some(CompOp::_3W) => Object::Integer(r),
-                        _ => {
-                            return Err(Error::RuntimeError(format!(
-                                "unhandled comparison op {:?}",
-                                instr.arg3 as isize,
-                            )))
-                        }
-                    };
+                    let op1 = self.stack.get_arg2(instr).clone();
+                    let op2 = self.stack.get_arg0(instr).clone();
+                    let res = self.cmp_op(instr.arg3, &op1, &op2)?;
 
-                    if let Object::Bool(b) = &res {
-                        if !*b {
-                            ci.ip += instr.arg1 as types::Integer;
-                        }
-                    } else {
-                        return Err(Error::RuntimeError(format!(
-                            "unsopported condition in JZ {:?}",
-                            res
-                        )));
+                    if let Object::Bool(false) = res {
+                        self.callstack
+                            .last_mut()
+                            .ok_or_else(|| Error::RuntimeError("callstack empty".to_string()))?
+                            .ip += instr.arg1 as types::Integer;
                     }
-                    // _GUARD(CMP_OP((CmpOP)arg3,STK(arg2),STK(arg0),temp_reg));
-                    // if(IsFalse(temp_reg)) ci->_ip+=(sarg1);
 
                     LoopState::Continue
                 }
 
                 Opcode::CLOSURE => {
                     let new_func = func.functions[instr.arg1 as usize].clone();
-                    let new_closure = object::Closure::new(new_func);
+                    let mut outervalues = Vec::new();
+                    for outervalue in &new_func.func_proto_ref()?.outervalues {
+                        let (type_, src, _) = outervalue;
+                        let src = src.integer()?;
+                        let outer = match *type_ {
+                            OUTER_TYPE_LOCAL => self.stack.find_outer(src),
+                            _ => ci.closure.closure_ref()?.outervalues[src as usize].clone(),
+                        };
+                        outervalues.push(outer);
+                    }
+                    let defaultparams = new_func
+                        .func_proto_ref()?
+                        .defaultparams
+                        .iter()
+                        .map(|pos| self.stack.value(*pos).clone())
+                        .collect();
+                    let mut new_closure =
+                        object::Closure::with_outers(new_func, outervalues, defaultparams);
+                    let size = memory::closure_size(&new_closure);
+                    self.memory
+                        .charge(&mut new_closure.allocation, size as isize)?;
                     self.stack
-                        .set_target(instr, Object::Closure(Rc::new(new_closure)));
+                        .set_target(instr, gc::track(Object::Closure(Rc::new(new_closure))));
+                    LoopState::Continue
+                }
+                Opcode::GETOUTER => {
+                    let outer = &ci.closure.closure_ref()?.outervalues[instr.arg1 as usize];
+                    let value = self.stack.get_outer(outer)?;
+                    self.stack.set_target(instr, value);
+                    LoopState::Continue
+                }
+                Opcode::SETOUTER => {
+                    let outer = &ci.closure.closure_ref()?.outervalues[instr.arg1 as usize];
+                    let value = self.stack.get_arg2(instr).clone();
+                    self.stack.set_outer(outer, value.clone())?;
+                    if instr.arg0 != 0xff {
+                        self.stack.set_target(instr, value);
+                    }
+                    LoopState::Continue
+                }
+                Opcode::PUSHTRAP => {
+                    ci.traps.push(ExceptionTrap {
+                        ip: ci.ip + instr.arg1 as types::Integer,
+                        target: instr.arg0 as types::Integer,
+                    });
+                    LoopState::Continue
+                }
+                Opcode::POPTRAP => {
+                    let len = ci.traps.len().saturating_sub(instr.arg0 as usize);
+                    ci.traps.truncate(len);
+                    LoopState::Continue
+                }
+                Opcode::THROW => {
+                    return Err(Error::Exception(self.stack.get_arg0(instr).clone()));
+                }
+                Opcode::YIELD => {
+                    let generator = ci.generator.clone().ok_or_else(|| {
+                        Error::RuntimeError(
+                            "trying to yield a 'null',only genenerator can be yielded".to_string(),
+                        )
+                    })?;
+                    let retval = if instr.arg0 == 0xff {
+                        Object::Null
+                    } else {
+                        self.stack.get_arg1(instr).clone()
+                    };
+                    let mut generator = generator.borrow_mut();
+                    let stack = &self.stack;
+                    generator.stack = (0..func.stacksize)
+                        .map(|i| stack.value(i).clone())
+                        .collect();
+                    generator.ip = ci.ip;
+                    generator.traps = std::mem::take(&mut ci.traps);
+                    generator.state = object::GeneratorState::Suspended;
+                    LoopState::LeaveFrame(retval)
+                }
+                Opcode::RESUME => LoopState::Resume {
+                    generator: self.stack.get_arg1(instr).clone(),
+                    target: instr.arg0 as types::Integer,
+                },
+                Opcode::POSTFOREACH => {
+                    if let Object::Generator(generator) = &*self.stack.get_arg0(instr) {
+                        if generator.borrow().state == object::GeneratorState::Dead {
+                            ci.ip += instr.arg1 as types::Integer - 1;
+                        }
+                    }
+                    LoopState::Continue
+                }
+                Opcode::CLOSE => {
+                    self.stack.close_outers(instr.arg1 as types::Integer);
                     LoopState::Continue
                 }
                 Opcode::NEWSLOT => {
@@ -487,14 +1181,64 @@ impl Executor {
 
                     // self.stack.print_compact();
 
+                    let obj = self.stack.get_arg1(instr).clone();
                     let key = self.stack.get_arg2(instr).clone();
                     let value = self.stack.get_arg3(instr).clone();
-                    let mut table = self.stack.get_arg1_mut(instr);
-                    let mut table = table.table_mut()?;
-                    table.map.insert(key, value);
+                    self.new_slot(&obj, key, value, false)?;
 
                     LoopState::Continue
                 }
+                Opcode::NEWSLOTA => {
+                    let obj = self.stack.get_arg1(instr).clone();
+                    let key = self.stack.get_arg2(instr).clone();
+                    let value = self.stack.get_arg3(instr).clone();
+                    if !matches!(obj, Object::Class(_)) {
+                        return Err(Error::RuntimeError("object must be a class".to_string()));
+                    }
+                    self.new_slot(
+                        &obj,
+                        key.clone(),
+                        value,
+                        instr.arg0 & NEW_SLOT_STATIC_FLAG != 0,
+                    )?;
+                    if instr.arg0 & NEW_SLOT_ATTRIBUTES_FLAG != 0 {
+                        let attributes = self.stack.value(instr.arg2 as types::Integer - 1).clone();
+                        obj.class_mut()?.set_attributes(&key, attributes)?;
+                    }
+                    LoopState::Continue
+                }
+                Opcode::SET => {
+                    let obj = self.stack.get_arg1(instr).clone();
+                    let key = self.stack.get_arg2(instr).clone();
+                    let value = self.stack.get_arg3(instr).clone();
+                    self.set(&obj, &key, value.clone(), instr.arg1 == 0)?;
+                    if instr.arg0 != 0xff {
+                        self.stack.set_target(instr, value);
+                    }
+                    LoopState::Continue
+                }
+                Opcode::GETBASE => {
+                    let base = ci.closure.closure_ref()?.base.clone();
+                    self.stack.set_target(instr, base.unwrap_or(Object::Null));
+                    LoopState::Continue
+                }
+                Opcode::INSTANCEOF => {
+                    let res = match &*self.stack.get_arg1(instr) {
+                        Object::Class(class) => match &*self.stack.get_arg2(instr) {
+                            Object::Instance(instance) => instance.borrow().instance_of(class)?,
+                            _ => false,
+                        },
+                        other => {
+                            return Err(Error::RuntimeError(format!(
+                                "cannot apply instanceof between a {} and a {}",
+                                other.type_name(),
+                                self.stack.get_arg2(instr).type_name()
+                            )))
+                        }
+                    };
+                    self.stack.set_target(instr, Object::Bool(res));
+                    LoopState::Continue
+                }
                 Opcode::PREPCALLK | Opcode::PREPCALL => {
                     // self.stack.print_compact(&format!("{:?} begin", opcode));
                     let key = if opcode == Opcode::PREPCALLK {
@@ -514,7 +1258,7 @@ impl Executor {
                     // }
                     // self.stack.set_arg3(instr, o);
                     let obj = self.stack.get_arg2(instr).clone();
-                    let res = get(&obj, &key)?;
+                    let res = self.get(&obj, &key, instr.arg2 == 0)?;
                     self.stack.set_arg3(instr, obj);
                     self.stack.set_target(instr, res);
                     // self.stack.print_compact();
@@ -530,17 +1274,36 @@ impl Executor {
                     num_args: instr.arg3 as types::Integer,
                     stack_inc: instr.arg2 as types::Integer,
                 },
-                Opcode::TAILCALL => LoopState::TailCall {
-                    closure: self.stack.get_arg1(instr).clone(),
-                    num_args: instr.arg3 as types::Integer,
-                    arg_offset: instr.arg2 as types::Integer,
-                },
+                Opcode::TAILCALL => {
+                    let closure = self.stack.get_arg1(instr).clone();
+                    let tail_callable = match &closure {
+                        Object::Closure(c) => !c.func_proto.func_proto_ref()?.bgenerator,
+                        _ => false,
+                    };
+                    match closure {
+                        Object::Closure(_) if tail_callable => LoopState::TailCall {
+                            closure,
+                            num_args: instr.arg3 as types::Integer,
+                            arg_offset: instr.arg2 as types::Integer,
+                        },
+                        // only script closures that aren't generators can reuse the frame
+                        _ => LoopState::Call {
+                            closure,
+                            target: Some(instr.arg0 as types::Integer),
+                            num_args: instr.arg3 as types::Integer,
+                            stack_inc: instr.arg2 as types::Integer,
+                        },
+                    }
+                }
                 Opcode::RETURN => {
                     let retval = if instr.arg0 == 0xff {
                         Object::Null
                     } else {
                         self.stack.get_arg1(instr).clone()
                     };
+                    if let Some(generator) = &ci.generator {
+                        generator.borrow_mut().kill();
+                    }
                     // println!("return: {}", instr.arg1);
                     // self.stack.print_compact();
                     LoopState::LeaveFrame(retval)
@@ -548,11 +1311,33 @@ impl Executor {
                 Opcode::NEWOBJ => {
                     match <NewObjectType as FromPrimitive>::from_u8(instr.arg3) {
                         Some(NewObjectType::ARRAY) => {
-                            self.stack
-                                .set_target(instr, Object::new_array(instr.arg1 as types::Integer));
+                            let array = Object::new_array(instr.arg1 as types::Integer);
+                            self.stack.set_target(instr, self.account(array)?);
                         }
                         Some(NewObjectType::TABLE) => {
-                            self.stack.set_target(instr, Object::new_table())
+                            let table = Object::new_table();
+                            self.stack.set_target(instr, self.account(table)?);
+                        }
+                        Some(NewObjectType::CLASS) => {
+                            let base = if instr.arg1 != -1 {
+                                let base = self.stack.get_arg1(instr).clone();
+                                if !matches!(base, Object::Class(_)) {
+                                    return Err(Error::RuntimeError(format!(
+                                        "trying to inherit from a {}",
+                                        base.type_name()
+                                    )));
+                                }
+                                Some(base)
+                            } else {
+                                None
+                            };
+                            let mut class = object::Class::new(base)?;
+                            if instr.arg2 != 0xff {
+                                class.attributes = self.stack.get_arg2(instr).clone();
+                            }
+                            let class = self
+                                .account(gc::track(Object::Class(Rc::new(RefCell::new(class)))))?;
+                            self.stack.set_target(instr, class);
                         }
                         _ => {
                             return Err(Error::RuntimeError(format!(
@@ -570,7 +1355,9 @@ impl Executor {
                             func.literals[instr.arg1 as usize].clone()
                         }
                         Some(AppendArrayType::INT) => Object::Integer(instr.arg1 as types::Integer),
-                        // Some(AppendArrayType::FLOAT) => Object::Null,
+                        Some(AppendArrayType::FLOAT) => {
+                            Object::Float(f32::from_bits(instr.arg1 as u32) as types::Float)
+                        }
                         Some(AppendArrayType::BOOL) => Object::Bool(instr.arg1 != 0),
                         _ => {
                             return Err(Error::RuntimeError(format!(
@@ -580,8 +1367,125 @@ impl Executor {
                         }
                     };
                     // self.stack.set_target(instr, val);
-                    let mut array = self.stack.get_arg0_mut(instr);
-                    array.array_mut()?.array.push(val);
+                    let array = self.stack.get_arg0(instr).clone();
+                    match &array {
+                        Object::Array(array) => self.array_push(array, val)?,
+                        other => {
+                            return Err(Error::RuntimeError(format!(
+                                "expected array. found {:?}",
+                                other
+                            )))
+                        }
+                    }
+                    LoopState::Continue
+                }
+                Opcode::LINE => LoopState::Continue,
+                Opcode::COMPARITH => {
+                    // arg1 holds the slot of the object in the upper and the value in the lower bits
+                    let selfidx = (instr.arg1 as u32 >> 16) as types::Integer;
+                    let obj = self.stack.value(selfidx).clone();
+                    let key = self.stack.get_arg2(instr).clone();
+                    let value = self
+                        .stack
+                        .value((instr.arg1 & 0xffff) as types::Integer)
+                        .clone();
+                    let res = self.deref_inc(
+                        instr.arg3 as char,
+                        &obj,
+                        &key,
+                        &value,
+                        false,
+                        selfidx == 0,
+                    )?;
+                    self.stack.set_target(instr, res);
+                    LoopState::Continue
+                }
+                Opcode::INC | Opcode::PINC => {
+                    let obj = self.stack.get_arg1(instr).clone();
+                    let key = self.stack.get_arg2(instr).clone();
+                    let res = self.deref_inc(
+                        '+',
+                        &obj,
+                        &key,
+                        &Object::Integer(instr.arg3 as i8 as types::Integer),
+                        opcode == Opcode::PINC,
+                        instr.arg1 == 0,
+                    )?;
+                    self.stack.set_target(instr, res);
+                    LoopState::Continue
+                }
+                Opcode::INCL | Opcode::PINCL => {
+                    let old = self.stack.get_arg1(instr).clone();
+                    let new = match old {
+                        Object::Integer(i) => {
+                            Object::Integer(i.wrapping_add(instr.arg3 as i8 as types::Integer))
+                        }
+                        _ => self.arith(
+                            '+',
+                            &old,
+                            &Object::Integer(instr.arg3 as i8 as types::Integer),
+                        )?,
+                    };
+                    if opcode == Opcode::PINCL {
+                        self.stack.set_target(instr, old);
+                    }
+                    self.stack.set_arg1(instr, new);
+                    LoopState::Continue
+                }
+                Opcode::LOADBOOL => {
+                    self.stack.set_target(instr, Object::Bool(instr.arg1 != 0));
+                    LoopState::Continue
+                }
+                Opcode::EXISTS => {
+                    let obj = self.stack.get_arg1(instr).clone();
+                    let key = self.stack.get_arg2(instr).clone();
+                    let res = matches!(self.raw_get(&obj, &key), Ok(Some(_)));
+                    self.stack.set_target(instr, Object::Bool(res));
+                    LoopState::Continue
+                }
+                Opcode::BITW => {
+                    let res = match (&*self.stack.get_arg2(instr), &*self.stack.get_arg1(instr)) {
+                        (Object::Integer(i1), Object::Integer(i2)) => {
+                            let (i1, i2) = (*i1, *i2);
+                            Object::Integer(match <BitwOp as FromPrimitive>::from_u8(instr.arg3) {
+                                Some(BitwOp::AND) => i1 & i2,
+                                Some(BitwOp::OR) => i1 | i2,
+                                Some(BitwOp::XOR) => i1 ^ i2,
+                                Some(BitwOp::SHIFTL) => i1.wrapping_shl(i2 as u32),
+                                Some(BitwOp::SHIFTR) => i1.wrapping_shr(i2 as u32),
+                                Some(BitwOp::USHIFTR) => (i1 as types::UnsignedInteger)
+                                    .wrapping_shr(i2 as u32)
+                                    as types::Integer,
+                                None => {
+                                    return Err(Error::RuntimeError(format!(
+                                        "unhandled bitwise op {}",
+                                        instr.arg3
+                                    )))
+                                }
+                            })
+                        }
+                        (op1, op2) => {
+                            return Err(Error::RuntimeError(format!(
+                                "bitwise op between '{}' and '{}'",
+                                op1.typesystem_name(),
+                                op2.typesystem_name()
+                            )))
+                        }
+                    };
+                    self.stack.set_target(instr, res);
+                    LoopState::Continue
+                }
+                Opcode::BWNOT => {
+                    let res = match &*self.stack.get_arg1(instr) {
+                        Object::Integer(i) => Object::Integer(!i),
+                        other => {
+                            return Err(Error::RuntimeError(format!(
+                                "attempt to perform a bitwise op on a {}",
+                                other.typesystem_name()
+                            )))
+                        }
+                    };
+                    self.stack.set_target(instr, res);
                     LoopState::Continue
                 }
                 Opcode::LOADROOT => {
@@ -605,6 +1509,32 @@ impl Executor {
                     let outkey = instr.arg2 as types::Integer;
                     let outvalue = instr.arg2 as types::Integer + 1;
                     let index_pos = instr.arg2 as types::Integer + 2;
+                    let exitpos = instr.arg1 as types::Integer;
+
+                    // instances keep the iterator returned by _nexti in the index slot
+                    if let Object::Instance(_) = &container {
+                        let itr = self.stack.value(index_pos).clone();
+                        let itr = self
+                            .call_metamethod(&container, "_nexti", vec![itr])?
+                            .ok_or_else(|| Error::RuntimeError("_nexti failed".to_string()))?;
+                        let jump = if let Object::Null = itr {
+                            exitpos
+                        } else {
+                            let value = self.get(&container, &itr, false).map_err(|_| {
+                                Error::RuntimeError("_nexti returned an invalid idx".to_string())
+                            })?;
+                            *self.stack.value_mut(outkey) = itr.clone();
+                            *self.stack.value_mut(outvalue) = value;
+                            *self.stack.value_mut(index_pos) = itr;
+                            1
+                        };
+                        self.callstack
+                            .last_mut()
+                            .ok_or_else(|| Error::RuntimeError("callstack empty".to_string()))?
+                            .ip += jump;
+                        continue;
+                    }
+
                     let index = match *self.stack.value(index_pos) {
                         Object::Null => 0,
                         Object::Integer(i) => i as usize,
@@ -615,44 +1545,112 @@ impl Executor {
                             )))
                         }
                     };
-                    let exitpos = instr.arg1 as types::Integer;
 
-                    match &container {
-                        Object::Array(array) => {
-                            if index < array.borrow().array.len() {
-                                let out = array.borrow().array[index].clone(); // end borrowing array so we can modify the stack
+                    if let Object::Generator(generator) = &container {
+                        let state = generator.borrow().state;
+                        match state {
+                            object::GeneratorState::Dead => {
+                                ci.ip += exitpos;
+                                LoopState::Continue
+                            }
+                            object::GeneratorState::Suspended => {
+                                let index = match *self.stack.value(index_pos) {
+                                    Object::Null => 0,
+                                    _ => index as types::Integer + 1,
+                                };
+                                *self.stack.value_mut(outkey) = Object::Integer(index);
+                                *self.stack.value_mut(index_pos) = Object::Integer(index);
+                                LoopState::Resume {
+                                    generator: container.clone(),
+                                    target: outvalue,
+                                }
+                            }
+                            object::GeneratorState::Running => {
+                                return Err(Error::RuntimeError(
+                                    "cannot iterate generator".to_string(),
+                                ))
+                            }
+                        }
+                    } else {
+                        // the slots of tables and classes are stored in vectors, the index is
+                        // the position of the next slot
+                        let next = match &container {
+                            Object::Array(array) => array.borrow().array.get(index).map(|value| {
+                                (
+                                    index + 1,
+                                    Object::Integer(index as types::Integer),
+                                    value.clone(),
+                                )
+                            }),
+                            // deleted table slots are skipped
+                            Object::Table(table) => table
+                                .borrow()
+                                .map
+                                .next_slot(index)
+                                .map(|(next, key, value)| (next, key.clone(), value.clone())),
+                            Object::Class(class) => {
+                                let class = class.borrow();
+                                class.members.get_index(index).map(|(key, _)| {
+                                    (
+                                        index + 1,
+                                        key.clone(),
+                                        class.get(key).unwrap_or(Object::Null),
+                                    )
+                                })
+                            }
+                            Object::String(s) => s.as_bytes().get(index).map(|c| {
+                                (
+                                    index + 1,
+                                    Object::Integer(index as types::Integer),
+                                    Object::Integer(*c as types::Integer),
+                                )
+                            }),
+                            _ => {
+                                return Err(Error::RuntimeError(format!(
+                                    "cannot iterate {}",
+                                    container.typesystem_name()
+                                )))
+                            }
+                        };
 
-                                *self.stack.value_mut(outkey) =
-                                    Object::Integer(index as types::Integer);
-                                *self.stack.value_mut(outvalue) = out;
+                        match next {
+                            Some((next, key, value)) => {
+                                *self.stack.value_mut(outkey) = key;
+                                *self.stack.value_mut(outvalue) = real_value(value);
                                 *self.stack.value_mut(index_pos) =
-                                    Object::Integer(index as types::Integer + 1);
+                                    Object::Integer(next as types::Integer);
                                 ci.ip += 1;
-                            } else {
-                                ci.ip += exitpos; // exit loop
                             }
+                            None => ci.ip += exitpos, // exit loop
                         }
-                        _ => {
-                            return Err(Error::RuntimeError(format!(
-                                "cannot iterate over {:?}",
-                                container
-                            )))
-                        }
+                        LoopState::Continue
                     }
-
-                    // STK(arg0),STK(arg2),STK(arg2+1),STK(arg2+2),arg2,sarg1,tojump
-
-                    LoopState::Continue
                 }
                 Opcode::GETK => {
                     let key = &func.literals[instr.arg1 as usize];
-                    let v = get(&*self.stack.get_arg2(instr), key)?;
+                    let obj = self.stack.get_arg2(instr).clone();
+                    let v = self.get(&obj, key, instr.arg2 == 0)?;
                     self.stack.set_target(instr, v);
                     LoopState::Continue
                     // Get(STK(arg2), ci->_literals[arg1], temp_reg, 0,arg2)
                 }
+                Opcode::GET => {
+                    let obj = self.stack.get_arg1(instr).clone();
+                    let key = self.stack.get_arg2(instr).clone();
+                    let v = self.get(&obj, &key, instr.arg1 == 0)?;
+                    self.stack.set_target(instr, v);
+                    LoopState::Continue
+                }
+                Opcode::DELETE => {
+                    let obj = self.stack.get_arg1(instr).clone();
+                    let key = self.stack.get_arg2(instr).clone();
+                    let res = self.delete_slot(&obj, &key)?;
+                    self.stack.set_target(instr, res);
+                    LoopState::Continue
+                }
                 Opcode::CLONE => {
                     let obj = self.stack.get_arg1(instr).clone_object()?;
+                    let obj = self.account(obj)?;
                     // println!("clone: {:?}", obj);
                     self.stack.set_target(instr, obj);
                     LoopState::Continue
@@ -665,19 +1663,6 @@ impl Executor {
 
                     LoopState::Continue
                 }
-                // Opcode::SET => {
-                //     let obj = self.stack.get_arg1(instr);
-
-                //     LoopState::Continue
-                //     //  if (!Set(STK(arg1), STK(arg2), STK(arg3),arg1)) { SQ_THROW(); }
-                //     // if (arg0 != 0xFF) TARGET = STK(arg3);
-                // }
-                _ => {
-                    return Err(Error::RuntimeError(format!(
-                        "unhandled opcode: {:?}",
-                        instr
-                    )))
-                }
             };
 
             match state {
@@ -702,24 +1687,89 @@ impl Executor {
                     }
                     let new_base = self.stack.frame.base + stack_inc;
 
+                    // calling a class creates an instance and runs its constructor on it
+                    let (closure, target, constructed) = match closure {
+                        Object::Class(_) => {
+                            let instance = self.account(gc::track(Object::Instance(Rc::new(
+                                RefCell::new(object::Instance::new(closure.clone())?),
+                            ))))?;
+                            if let Some(target) = target {
+                                *self.stack.value_mut(target) = instance.clone();
+                            }
+                            *self.stack.value_mut(stack_inc) = instance;
+                            let constructor = closure.class()?.constructor();
+                            match constructor {
+                                Some(constructor) => (constructor, None, target),
+                                None => continue,
+                            }
+                        }
+                        _ => (closure, target, None),
+                    };
+
+                    // calling a generator function only creates the generator
+                    if let Object::Closure(c) = &closure {
+                        let proto = c.func_proto.func_proto_ref()?;
+                        if proto.bgenerator {
+                            let num_params = proto.parameters.len() as types::Integer;
+                            // the parameters can extend beyond the current frame
+                            self.stack.reserve(
+                                (self.stack.frame.base + stack_inc + num_params) as usize,
+                            )?;
+                            self.setup_params(c, num_args, stack_inc)?;
+                            let num_args = num_params;
+                            let stack = &self.stack;
+                            let args = (0..num_args)
+                                .map(|i| stack.value(stack_inc + i).clone())
+                                .collect();
+                            let generator =
+                                object::Generator::new(closure.clone(), args, proto.stacksize);
+                            let generator = self.account(gc::track(Object::Generator(Rc::new(
+                                RefCell::new(generator),
+                            ))))?;
+                            if let Some(target) = target {
+                                *self.stack.value_mut(target) = generator;
+                            }
+                            continue;
+                        }
+                    }
+
                     match closure {
                         Object::Closure(_) => {
                             self.start_call(closure, target, num_args, new_base)?;
-                            ci = self.callstack.last_mut().ok_or_else(|| {
-                                Error::RuntimeError("callstack empty".to_string())
-                            })?;
-                            func = ci.closure.closure_ref()?.func_proto.func_proto()?;
+                            if let Some(target) = constructed {
+                                // the instance in the target can share a slot with the frame,
+                                // which is cleared when the constructor returns
+                                let ci = self.callstack.last_mut().unwrap();
+                                ci.constructor = true;
+                                ci.target = Some(target);
+                            }
+                            func = self.current_func()?;
                         }
                         Object::NativeClosure(native_closure) => {
-                            let new_top = new_base + native_closure.nargs;
-                            let last_frame = self.stack.get_frame();
-                            self.stack.set_frame(StackFrame {
-                                base: new_base,
-                                top: new_top,
-                            });
-
-                            (native_closure.func)(&mut self.stack);
-                            self.stack.set_frame(last_frame);
+                            let retval = self.call_native(&native_closure, new_base, num_args)?;
+                            if let Some(target) = target {
+                                *self.stack.value_mut(target) = retval;
+                            }
+                            if let Some(value) = self.stack.suspend_request.take() {
+                                self.suspended = Some(Suspension { target });
+                                return Ok(ExecutionState::Suspended(value));
+                            }
+                        }
+                        Object::Table(_) | Object::Instance(_) | Object::UserData(_) => {
+                            let mm = get_metamethod(&closure, "_call")?.ok_or_else(|| {
+                                Error::RuntimeError(format!(
+                                    "attempt to call '{}'",
+                                    closure.typesystem_name()
+                                ))
+                            })?;
+                            let mut args = vec![closure];
+                            args.extend(
+                                (0..num_args).map(|i| self.stack.value(stack_inc + i).clone()),
+                            );
+                            let retval = self.call_function(&mm, args)?;
+                            if let Some(target) = target {
+                                *self.stack.value_mut(target) = retval;
+                            }
                         }
                         _ => {
                             return Err(Error::RuntimeError(format!(
@@ -742,6 +1792,7 @@ impl Executor {
                         self.stack.print_compact("before tailcall");
                     }
 
+                    self.stack.close_outers(0);
                     for i in 0..num_args {
                         // println!(
                         //     "{} <- {} {}",
@@ -753,35 +1804,107 @@ impl Executor {
                         // self.stack.stack.swap(i as usize, (arg_offset + i) as usize);
                         self.stack.swap(i, arg_offset + i);
                     }
-
+                    let base = self.stack.frame.base;
+                    self.stack.enter_frame(StackFrame {
+                        base,
+                        top: base
+                            + closure
+                                .closure_ref()?
+                                .func_proto
+                                .func_proto_ref()?
+                                .stacksize,
+                    })?;
+                    self.setup_params(closure.closure_ref()?, num_args, 0)?;
+
+                    let ci = self
+                        .callstack
+                        .last_mut()
+                        .ok_or_else(|| Error::RuntimeError("callstack empty".to_string()))?;
                     ci.closure = closure;
                     ci.ip = 0;
-                    func = ci.closure.closure_ref()?.func_proto.func_proto()?;
+                    func = self.current_func()?;
                     if self.trace_call_return {
                         self.stack.print_compact("after tailcall");
                     }
                 }
+                LoopState::Resume { generator, target } => {
+                    let generator = match generator {
+                        Object::Generator(generator) => generator,
+                        _ => {
+                            return Err(Error::RuntimeError(format!(
+                                "trying to resume a '{}',only genenerator can be resumed",
+                                generator.type_name()
+                            )))
+                        }
+                    };
+                    let mut gen = generator.borrow_mut();
+                    match gen.state {
+                        object::GeneratorState::Dead => {
+                            return Err(Error::RuntimeError("resuming dead generator".to_string()))
+                        }
+                        object::GeneratorState::Running => {
+                            return Err(Error::RuntimeError(
+                                "resuming active generator".to_string(),
+                            ))
+                        }
+                        object::GeneratorState::Suspended => (),
+                    }
+                    if self.callstack.len() >= self.max_call_depth {
+                        return Err(Error::StackOverflow);
+                    }
+
+                    // the generator frame is placed right above the current one
+                    let prevframe = self.stack.get_frame();
+                    let base = prevframe.top;
+                    self.stack.enter_frame(StackFrame {
+                        base,
+                        top: base + gen.stack.len() as types::Integer,
+                    })?;
+                    gen.state = object::GeneratorState::Running;
+                    for (i, value) in gen.stack.drain(..).enumerate() {
+                        *self.stack.value_mut(i as types::Integer) = value;
+                    }
+                    self.callstack.push(CallInfo {
+                        prevframe,
+                        closure: gen.closure.clone(),
+                        ip: gen.ip,
+                        root: false,
+                        traps: std::mem::take(&mut gen.traps),
+                        generator: Some(generator.clone()),
+                        constructor: false,
+                        target: Some(target),
+                    });
+                    drop(gen);
+                    func = self.current_func()?;
+                }
                 LoopState::LeaveFrame(retval) => {
+                    let ci = self
+                        .callstack
+                        .last_mut()
+                        .ok_or_else(|| Error::RuntimeError("callstack empty".to_string()))?;
                     if self.trace_call_return {
                         match ci.target {
                             Some(target) => println!("LeaveFrame {:?} -> {}", retval, target),
                             None => println!("LeaveFrame noreturn"),
                         }
                     }
+                    self.stack.close_outers(0);
                     let root = ci.root;
                     if !root {
                         let target = ci.target;
+                        let retval = if ci.constructor {
+                            self.stack.value(0).clone()
+                        } else {
+                            retval
+                        };
 
                         if self.trace_call_return {
                             self.stack.print_compact("before return");
                         }
+                        self.stack.clear_frame();
                         self.stack.set_frame(ci.prevframe);
 
                         self.callstack.pop();
-                        ci = self
-                            .callstack
-                            .last_mut()
-                            .ok_or_else(|| Error::RuntimeError("callstack empty".to_string()))?;
 
                         if let Some(target) = target {
                             *self.stack.value_mut(target) = retval;
@@ -790,21 +1913,161 @@ impl Executor {
                             self.stack.print_compact("after return");
                         }
 
-                        func = ci.closure.closure_ref()?.func_proto.func_proto()?;
+                        func = self.current_func()?;
                     } else {
-                        return Ok(retval);
+                        self.stack.set_frame(ci.prevframe);
+                        self.callstack.pop();
+                        return Ok(ExecutionState::Returned(retval));
                     }
                 }
 
                 _ => (),
             }
         }
-
-        self.profiling.print();
     }
 
-    pub fn push_roottable(&mut self) {
-        self.stack.push(self.roottable.clone());
+    // releases the objects that are only referenced from reference cycles that can no longer
+    // be reached from the stack, the root table or the host. Returns the number of released
+    // objects.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.stack.clear_unused();
+        self.gc.collect_garbage()
+    }
+    // returns the objects collect_garbage would release, keeping them alive
+    pub fn resurrect_unreachable(&mut self) -> Vec<Object> {
+        self.stack.clear_unused();
+        self.gc.unreachable()
+    }
+    // charges the memory of a new object. Objects that are already charged are left alone.
+    fn account(&self, mut obj: Object) -> Result<Object> {
+        let size = memory::object_size(&obj) as isize;
+        let charge = |allocation: &mut memory::Allocation| {
+            if allocation.is_charged() {
+                Ok(())
+            } else {
+                self.memory.charge(allocation, size)
+            }
+        };
+        match &mut obj {
+            Object::Table(table) => charge(&mut table.borrow_mut().allocation)?,
+            Object::Array(array) => charge(&mut array.borrow_mut().allocation)?,
+            Object::Class(class) => charge(&mut class.borrow_mut().allocation)?,
+            Object::Instance(instance) => charge(&mut instance.borrow_mut().allocation)?,
+            Object::Generator(generator) => charge(&mut generator.borrow_mut().allocation)?,
+            // a string is shared by its copies and charged only once
+            Object::String(s) => {
+                if let Some(s) = Rc::get_mut(s) {
+                    if !s.allocation.is_charged() {
+                        self.memory.charge(&mut s.allocation, size)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(obj)
+    }
+    // charges a value made by the host, like the return value of a native function, together
+    // with the new tables, arrays and strings it holds
+    fn account_new(&self, obj: Object) -> Result<Object> {
+        let fresh = match &obj {
+            Object::Table(table) => !table.borrow().allocation.is_charged(),
+            Object::Array(array) => !array.borrow().allocation.is_charged(),
+            _ => false,
+        };
+        // charged before its values, so a value referring back to it stops there
+        let obj = self.account(obj)?;
+        if fresh {
+            match &obj {
+                Object::Table(table) => {
+                    let slots = std::mem::take(&mut table.borrow_mut().map);
+                    let mut map = object::TableMap::new();
+                    for (key, value) in slots {
+                        map.insert(self.account_new(key)?, self.account_new(value)?);
+                    }
+                    table.borrow_mut().map = map;
+                }
+                Object::Array(array) => {
+                    let values = std::mem::take(&mut array.borrow_mut().array);
+                    let values = values
+                        .into_iter()
+                        .map(|value| self.account_new(value))
+                        .collect::<Result<_>>()?;
+                    array.borrow_mut().array = values;
+                }
+                _ => {}
+            }
+        }
+        Ok(obj)
+    }
+    // sets a slot of the root table to a value given by the host
+    fn set_root_slot(&self, name: &str, value: Object) -> Result<()> {
+        let key = self.account(Object::new_string(name))?;
+        let value = self.account_new(value)?;
+        match &self.roottable {
+            Object::Table(roottable) => self.table_insert(roottable, key, value),
+            other => Err(Error::RuntimeError(format!(
+                "expected table. found {}",
+                other.type_name()
+            ))),
+        }
+    }
+    // sets a table slot, charging the memory it takes
+    pub(crate) fn table_insert(
+        &self,
+        table: &Rc<RefCell<object::Table>>,
+        key: Object,
+        value: Object,
+    ) -> Result<()> {
+        let mut table = table.borrow_mut();
+        let bytes = match table.map.get(&key) {
+            Some(old) => memory::value_size(&value) as isize - memory::value_size(old) as isize,
+            None => memory::slot_size(&key, &value) as isize,
+        };
+        self.memory.charge(&mut table.allocation, bytes)?;
+        table.map.insert(key, value);
+        Ok(())
+    }
+    pub(crate) fn table_remove(
+        &self,
+        table: &Rc<RefCell<object::Table>>,
+        key: &Object,
+    ) -> Option<Object> {
+        let mut table = table.borrow_mut();
+        let (key, value) = table.map.remove_entry(key)?;
+        let bytes = memory::slot_size(&key, &value) as isize;
+        self.memory
+            .charge(&mut table.allocation, -bytes)
+            .expect("releasing memory cannot fail");
+        Some(value)
+    }
+    pub(crate) fn array_push(
+        &self,
+        array: &Rc<RefCell<object::Array>>,
+        value: Object,
+    ) -> Result<()> {
+        let mut array = array.borrow_mut();
+        self.memory
+            .charge(&mut array.allocation, memory::value_size(&value) as isize)?;
+        array.array.push(value);
+        Ok(())
+    }
+    pub(crate) fn array_pop(&self, array: &Rc<RefCell<object::Array>>) -> Option<Object> {
+        let mut array = array.borrow_mut();
+        let value = array.array.pop()?;
+        let bytes = memory::value_size(&value) as isize;
+        self.memory
+            .charge(&mut array.allocation, -bytes)
+            .expect("releasing memory cannot fail");
+        Some(value)
+    }
+    // registers the class bound by T in the root table
+    pub fn register_class<T: binding::ClassBinding>(&mut self) -> Result<()> {
+        let _active = self.gc.activate();
+        let class = binding::class_object::<T>()?;
+        self.set_root_slot(T::NAME, class)
+    }
+    pub fn push_roottable(&mut self) -> Result<()> {
+        self.stack.push(self.roottable.clone())
     }
     pub fn print_state(&self) -> Result<()> {
         let ci = self
@@ -820,66 +2083,569 @@ impl Executor {
         Ok(())
     }
 
-    pub fn add_native_func(&mut self, name: &str, closure: Object) -> Result<()> {
-        self.roottable
-            .table_mut()?
-            .map
-            .insert(Object::String(name.into()), closure);
-        Ok(())
+    pub fn add_native_func<Args>(
+        &mut self,
+        name: &str,
+        func: impl convert::IntoNativeFunc<Args>,
+    ) -> Result<()> {
+        self.set_root_slot(name, func.into_native_func())
     }
-}
 
-fn get(obj: &Object, key: &Object) -> Result<Object> {
-    match obj {
-        Object::Table(table) => table
-            .borrow()
-            .map
-            .get(key)
-            .cloned()
-            .ok_or_else(|| Error::RuntimeError(format!("key {:?} not found in table", key))),
-
-        Object::Array(array) => match key {
-            Object::Integer(i) => array
-                .borrow()
-                .array
-                .get(*i as usize)
-                .cloned()
-                .ok_or_else(|| Error::RuntimeError(format!("array access error {:?}", key))),
-
-            Object::String(s) => match &**s {
-                // hack array default delegation
-                "len" => Ok(Object::Integer(array.borrow().array.len() as types::Integer)),
+    fn call_native(
+        &mut self,
+        native_closure: &object::NativeClosure,
+        base: types::Integer,
+        num_args: types::Integer,
+    ) -> Result<Object> {
+        let nargs = native_closure.nargs;
+        if (nargs > 0 && num_args != nargs) || (nargs < 0 && num_args < -nargs) {
+            return Err(Error::RuntimeError(
+                "wrong number of parameters".to_string(),
+            ));
+        }
+        let offset = base - self.stack.frame.base;
+        let args: Vec<Object> = (offset..offset + num_args)
+            .map(|i| self.stack.value(i).clone())
+            .collect();
+        for (n, (mask, arg)) in native_closure.typemask.iter().zip(&args).enumerate() {
+            if mask & arg.raw_type() == 0 {
+                return Err(arg_type_error(n, arg, &typemask_names(*mask)));
+            }
+        }
+        let retval = match &native_closure.func {
+            object::NativeFunction::Native(func) => func(&mut CallContext {
+                executor: self,
+                args,
+            }),
+            object::NativeFunction::Builtin(func) => func(self, args),
+        }?;
+        // values built by native functions are charged like the ones built by scripts
+        self.account_new(retval)
+    }
+
+    // calls a closure or native closure from native code. args start with `this`.
+    pub(crate) fn call_function(&mut self, func: &Object, args: Vec<Object>) -> Result<Object> {
+        let num_args = args.len() as types::Integer;
+        let depth = self.callstack.len();
+        self.stack
+            .push_all(std::iter::once(func.clone()).chain(args))?;
+        let res = match func {
+            Object::NativeClosure(native_closure) => {
+                let base = self.stack.frame.top - num_args;
+                let res = self.call_native(native_closure, base, num_args);
+                self.stack.pop(num_args);
+                match self.stack.suspend_request.take() {
+                    Some(value) => res.map(|_| ExecutionState::Suspended(value)),
+                    None => res.map(ExecutionState::Returned),
+                }
+            }
+            Object::Closure(_) => self
+                .call(num_args, true)
+                .and_then(|_| self.execute_resumable()),
+            _ => {
+                self.stack.pop(num_args);
+                Err(Error::RuntimeError(format!(
+                    "attempt to call '{}'",
+                    func.typesystem_name()
+                )))
+            }
+        };
+        let res = match res {
+            Ok(ExecutionState::Returned(value)) => Ok(value),
+            Ok(ExecutionState::Suspended(_)) => {
+                self.suspended = None;
+                Err(Error::RuntimeError(
+                    "cannot suspend through a native call".to_string(),
+                ))
+            }
+            // the interrupted call can't be continued through the native call
+            Err(Error::BudgetExhausted) => Err(Error::Terminated(
+                "instruction budget exhausted".to_string(),
+            )),
+            Err(err) => Err(err),
+        };
+        while self.callstack.len() > depth {
+            self.pop_frame();
+        }
+        self.stack.pop(1);
+        res
+    }
+
+    fn call_metamethod(
+        &mut self,
+        obj: &Object,
+        name: &str,
+        args: Vec<Object>,
+    ) -> Result<Option<Object>> {
+        match get_metamethod(obj, name)? {
+            Some(mm) => {
+                let mut mm_args = vec![obj.clone()];
+                mm_args.extend(args);
+                self.call_function(&mm, mm_args).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    // like call_metamethod, but an exception thrown with null means the key was not found
+    fn call_fallback(
+        &mut self,
+        obj: &Object,
+        name: &str,
+        args: Vec<Object>,
+    ) -> Result<Option<Object>> {
+        match self.call_metamethod(obj, name, args) {
+            Err(Error::Exception(Object::Null)) => Ok(None),
+            res => res,
+        }
+    }
+
+    fn raw_get(&self, obj: &Object, key: &Object) -> Result<Option<Object>> {
+        let value = match obj {
+            Object::Table(table) => Ok(table.borrow().map.get(key).cloned()),
+            Object::Instance(instance) => instance.borrow().get(key),
+            Object::Class(class) => Ok(class.borrow().get(key)),
+            Object::Array(array) => match key {
+                Object::Integer(i) => Ok(array.borrow().array.get(*i as usize).cloned()),
+                Object::String(_) => Ok(None),
                 _ => Err(Error::RuntimeError(format!(
-                    "array delegate failed {:?}",
+                    "unsupported array key {:?}",
                     key
                 ))),
             },
+            _ => Ok(None),
+        }?;
+        Ok(value.map(real_value))
+    }
 
-            _ => Err(Error::RuntimeError(format!(
-                "unsupported array key {:?}",
-                key
-            ))),
-        },
-        _ => Err(Error::RuntimeError(format!(
-            "key {:?} not found in table",
-            key
-        ))),
+    // looks up a key along the delegate chain. Only the `_get` of the direct delegate of obj
+    // is called if the key is missing, with obj as `this`.
+    fn get_delegated(&mut self, obj: &Object, key: &Object) -> Result<Option<Object>> {
+        let mut next = obj.clone();
+        loop {
+            if let Some(value) = self.raw_get(&next, key)? {
+                return Ok(Some(value));
+            }
+            let delegate = match &next {
+                Object::Table(table) => table.borrow().delegate.clone(),
+                Object::UserData(userdata) => userdata.borrow().delegate.clone(),
+                _ => None,
+            };
+            match delegate {
+                Some(delegate) => next = delegate,
+                None => break,
+            }
+        }
+        if let Object::Table(_) | Object::Instance(_) | Object::UserData(_) = obj {
+            return self.call_fallback(obj, "_get", vec![key.clone()]);
+        }
+        Ok(None)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    // use super::read_closure;
-    use super::*;
-    use crate::io::*;
-    use std::io::Seek;
+    fn default_delegate(&self, obj: &Object, key: &Object) -> Result<Option<Object>> {
+        let delegate = match obj {
+            Object::Table(_) => &self.delegates.table,
+            Object::Array(_) => &self.delegates.array,
+            Object::Closure(_) | Object::NativeClosure(_) => &self.delegates.closure,
+            Object::Class(_) => &self.delegates.class,
+            Object::Instance(_) => &self.delegates.instance,
+            Object::Generator(_) => &self.delegates.generator,
+            Object::Thread(_) => &self.delegates.thread,
+            Object::WeakRef(_) => &self.delegates.weakref,
+            _ => return Ok(None),
+        };
+        Ok(delegate.table()?.map.get(key).cloned())
+    }
 
-    #[test]
-    fn load_closure() {
-        let mut bc = &include_bytes!("out.cnut")[..];
-        let closure = read_closure(&mut bc).unwrap();
+    // root_fallback is set when obj is `this` of the current function, in which case
+    // missing keys are looked up in the root table
+    fn get(&mut self, obj: &Object, key: &Object, root_fallback: bool) -> Result<Object> {
+        if let Some(value) = self.get_delegated(obj, key)? {
+            return Ok(value);
+        }
+        if let Some(value) = self.default_delegate(obj, key)? {
+            return Ok(value);
+        }
+        if root_fallback {
+            let roottable = self.roottable.clone();
+            if let Some(value) = self.get_delegated(&roottable, key)? {
+                return Ok(value);
+            }
+        }
+        Err(index_error(key))
+    }
 
-        closure
+    // sets an existing slot of obj or one of its delegates
+    fn set_delegated(&mut self, obj: &Object, key: &Object, value: &Object) -> Result<bool> {
+        match obj {
+            Object::Table(table) => {
+                if table.borrow().map.contains_key(key) {
+                    self.table_insert(table, key.clone(), value.clone())?;
+                    return Ok(true);
+                }
+                let delegate = table.borrow().delegate.clone();
+                match delegate {
+                    Some(delegate) => {
+                        if self.set_delegated(&delegate, key, value)? {
+                            return Ok(true);
+                        }
+                    }
+                    None => return Ok(false),
+                }
+            }
+            Object::Instance(instance) => {
+                if instance.borrow_mut().set(key, value.clone())? {
+                    return Ok(true);
+                }
+            }
+            // userdata has no slots of its own
+            Object::UserData(_) => {}
+            Object::Array(array) => {
+                let mut array = array.borrow_mut();
+                return match key {
+                    Object::Integer(i) if *i >= 0 && (*i as usize) < array.array.len() => {
+                        let slot = *i as usize;
+                        let bytes = memory::value_size(value) as isize
+                            - memory::value_size(&array.array[slot]) as isize;
+                        self.memory.charge(&mut array.allocation, bytes)?;
+                        array.array[slot] = value.clone();
+                        Ok(true)
+                    }
+                    Object::Integer(_) => Err(index_error(key)),
+                    _ => Err(Error::RuntimeError(format!(
+                        "indexing {} with {}",
+                        obj.type_name(),
+                        key.type_name()
+                    ))),
+                };
+            }
+            _ => {
+                return Err(Error::RuntimeError(format!(
+                    "trying to set '{}'",
+                    obj.type_name()
+                )))
+            }
+        }
+        Ok(self
+            .call_fallback(obj, "_set", vec![key.clone(), value.clone()])?
+            .is_some())
+    }
+
+    fn set(
+        &mut self,
+        obj: &Object,
+        key: &Object,
+        value: Object,
+        root_fallback: bool,
+    ) -> Result<()> {
+        if self.set_delegated(obj, key, &value)? {
+            return Ok(());
+        }
+        if root_fallback {
+            if let Object::Table(roottable) = &self.roottable {
+                if roottable.borrow().map.contains_key(key) {
+                    return self.table_insert(roottable, key.clone(), value);
+                }
+            }
+        }
+        Err(index_error(key))
+    }
+
+    fn new_slot(
+        &mut self,
+        obj: &Object,
+        key: Object,
+        value: Object,
+        is_static: bool,
+    ) -> Result<()> {
+        if let Object::Null = key {
+            return Err(Error::RuntimeError(
+                "null cannot be used as index".to_string(),
+            ));
+        }
+        match obj {
+            Object::Table(table) => {
+                if !table.borrow().map.contains_key(&key)
+                    && self
+                        .call_metamethod(obj, "_newslot", vec![key.clone(), value.clone()])?
+                        .is_some()
+                {
+                    return Ok(());
+                }
+                self.table_insert(table, key, value)
+            }
+            Object::Class(class) => {
+                let mut class = class.borrow_mut();
+                let bytes = if class.members.contains_key(&key) {
+                    0
+                } else {
+                    memory::class_slot_size()
+                };
+                self.memory.check(bytes)?;
+                class.new_slot(key, value, is_static)?;
+                self.memory.charge(&mut class.allocation, bytes as isize)
+            }
+            Object::Instance(_) => match self.call_metamethod(obj, "_newslot", vec![key, value])? {
+                Some(_) => Ok(()),
+                None => Err(Error::RuntimeError(
+                    "class instances do not support the new slot operator".to_string(),
+                )),
+            },
+            _ => Err(Error::RuntimeError(format!(
+                "indexing {} with {}",
+                obj.type_name(),
+                key.type_name()
+            ))),
+        }
+    }
+
+    fn delete_slot(&mut self, obj: &Object, key: &Object) -> Result<Object> {
+        match obj {
+            Object::Table(_) | Object::Instance(_) | Object::UserData(_) => {
+                if let Some(res) = self.call_metamethod(obj, "_delslot", vec![key.clone()])? {
+                    return Ok(res);
+                }
+                match obj {
+                    Object::Table(table) => self
+                        .table_remove(table, key)
+                        .ok_or_else(|| index_error(key)),
+                    _ => Err(Error::RuntimeError(format!(
+                        "cannot delete a slot from {}",
+                        obj.type_name()
+                    ))),
+                }
+            }
+            _ => Err(Error::RuntimeError(format!(
+                "attempt to delete a slot from a {}",
+                obj.type_name()
+            ))),
+        }
+    }
+
+    fn arith(&mut self, op: char, op1: &Object, op2: &Object) -> Result<Object> {
+        match (op1, op2) {
+            (Object::Integer(i1), Object::Integer(i2)) => {
+                let (i1, i2) = (*i1, *i2);
+                Ok(Object::Integer(match op {
+                    '+' => i1.wrapping_add(i2),
+                    '-' => i1.wrapping_sub(i2),
+                    '*' => i1.wrapping_mul(i2),
+                    '/' | '%' if i2 == 0 => {
+                        return Err(Error::RuntimeError(
+                            if op == '/' {
+                                "division by zero"
+                            } else {
+                                "modulo by zero"
+                            }
+                            .to_string(),
+                        ))
+                    }
+                    '/' => i1.wrapping_div(i2),
+                    _ => i1.wrapping_rem(i2),
+                }))
+            }
+            (Object::Integer(_) | Object::Float(_), Object::Integer(_) | Object::Float(_)) => {
+                let (f1, f2) = (to_float(op1), to_float(op2));
+                Ok(Object::Float(match op {
+                    '+' => f1 + f2,
+                    '-' => f1 - f2,
+                    '*' => f1 * f2,
+                    '/' => f1 / f2,
+                    _ => f1 % f2,
+                }))
+            }
+            (Object::String(_), _) | (_, Object::String(_)) if op == '+' => {
+                let s = format!("{}{}", op1, op2);
+                self.account(Object::String(Rc::new(object::Str::new(s))))
+            }
+            _ => {
+                let name = match op {
+                    '+' => "_add",
+                    '-' => "_sub",
+                    '*' => "_mul",
+                    '/' => "_div",
+                    _ => "_modulo",
+                };
+                self.arith_metamethod(op, name, op1, op2)
+            }
+        }
+    }
+
+    // applies op to the slot key of obj and returns the new or, if postfix is set, the old value
+    fn deref_inc(
+        &mut self,
+        op: char,
+        obj: &Object,
+        key: &Object,
+        incr: &Object,
+        postfix: bool,
+        root_fallback: bool,
+    ) -> Result<Object> {
+        let old = self.get(obj, key, root_fallback)?;
+        let new = self.arith(op, &old, incr)?;
+        self.set(obj, key, new.clone(), root_fallback)?;
+        Ok(if postfix { old } else { new })
+    }
+
+    fn arith_metamethod(
+        &mut self,
+        op: char,
+        name: &str,
+        op1: &Object,
+        op2: &Object,
+    ) -> Result<Object> {
+        self.call_metamethod(op1, name, vec![op2.clone()])?
+            .ok_or_else(|| {
+                Error::RuntimeError(format!(
+                    "arith op {} on between '{}' and '{}'",
+                    op,
+                    op1.typesystem_name(),
+                    op2.typesystem_name()
+                ))
+            })
+    }
+
+    fn compare(&mut self, op1: &Object, op2: &Object) -> Result<types::Integer> {
+        let ordering = match (op1, op2) {
+            (Object::Integer(int1), Object::Integer(int2)) => int1.cmp(int2),
+            (Object::String(str1), Object::String(str2)) => str1.cmp(str2),
+            (Object::Integer(_) | Object::Float(_), Object::Integer(_) | Object::Float(_)) => {
+                let (f1, f2) = (to_float(op1), to_float(op2));
+                return Ok(if f1 == f2 {
+                    0
+                } else if f1 < f2 {
+                    -1
+                } else {
+                    1
+                });
+            }
+            _ => {
+                return match self.call_metamethod(op1, "_cmp", vec![op2.clone()])? {
+                    Some(Object::Integer(r)) => Ok(r),
+                    Some(_) => Err(Error::RuntimeError(
+                        "_cmp must return an integer".to_string(),
+                    )),
+                    None => Err(Error::RuntimeError(format!(
+                        "comparison between '{}' and '{}'",
+                        op1.typesystem_name(),
+                        op2.typesystem_name()
+                    ))),
+                }
+            }
+        };
+        Ok(ordering as types::Integer)
+    }
+
+    fn cmp_op(&mut self, op: u8, op1: &Object, op2: &Object) -> Result<Object> {
+        let r = self.compare(op1, op2)?;
+        match <CompOp as FromPrimitive>::from_u8(op) {
+            Some(CompOp::G) => Ok(Object::Bool(r > 0)),
+            Some(CompOp::GE) => Ok(Object::Bool(r >= 0)),
+            Some(CompOp::L) => Ok(Object::Bool(r < 0)),
+            Some(CompOp::LE) => Ok(Object::Bool(r <= 0)),
+            Some(CompOp::_3W) => Ok(Object::Integer(r)),
+            _ => Err(Error::RuntimeError(format!(
+                "unhandled comparison op {:?}",
+                op as isize,
+            ))),
+        }
+    }
+
+    fn type_of(&mut self, obj: &Object) -> Result<Object> {
+        match self.call_metamethod(obj, "_typeof", Vec::new())? {
+            Some(name) => Ok(name),
+            None => Ok(Object::new_string(obj.typesystem_name())),
+        }
+    }
+}
+
+// looks up a metamethod in the delegate of a table or the class of an instance
+fn get_metamethod(obj: &Object, name: &str) -> Result<Option<Object>> {
+    let key = Object::new_string(name);
+    match obj {
+        Object::Table(table) => match &table.borrow().delegate {
+            Some(delegate) => Ok(delegate.table()?.map.get(&key).cloned()),
+            None => Ok(None),
+        },
+        Object::Instance(instance) => Ok(instance.borrow().class.class()?.get(&key)),
+        Object::UserData(userdata) => match &userdata.borrow().delegate {
+            Some(delegate) => Ok(delegate.table()?.map.get(&key).cloned()),
+            None => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+// the target of a weak reference read from a container
+fn real_value(obj: Object) -> Object {
+    match obj {
+        Object::WeakRef(weakref) => weakref.get(),
+        _ => obj,
+    }
+}
+
+fn is_false(obj: &Object) -> bool {
+    match obj {
+        Object::Bool(b) => !b,
+        Object::Integer(i) => *i == 0,
+        Object::Float(f) => *f == 0.0,
+        Object::Null => true,
+        _ => false,
+    }
+}
+
+fn to_float(obj: &Object) -> types::Float {
+    match obj {
+        Object::Integer(i) => *i as types::Float,
+        Object::Float(f) => *f,
+        _ => 0.0,
+    }
+}
+
+// raw equality as in the EQ and NE opcodes. Numbers of different types are compared by value
+// and reference types by identity.
+fn is_equal(op1: &Object, op2: &Object) -> bool {
+    match (op1, op2) {
+        (Object::Integer(i1), Object::Integer(i2)) => i1 == i2,
+        (Object::Integer(_) | Object::Float(_), Object::Integer(_) | Object::Float(_)) => {
+            to_float(op1) == to_float(op2)
+        }
+        (Object::Bool(b1), Object::Bool(b2)) => b1 == b2,
+        (Object::String(s1), Object::String(s2)) => s1 == s2,
+        (Object::Null, Object::Null) => true,
+        (Object::FuncProto(r1), Object::FuncProto(r2)) => Rc::ptr_eq(r1, r2),
+        (Object::Closure(r1), Object::Closure(r2)) => Rc::ptr_eq(r1, r2),
+        (Object::NativeClosure(r1), Object::NativeClosure(r2)) => Rc::ptr_eq(r1, r2),
+        (Object::Table(r1), Object::Table(r2)) => Rc::ptr_eq(r1, r2),
+        (Object::Array(r1), Object::Array(r2)) => Rc::ptr_eq(r1, r2),
+        (Object::Outer(r1), Object::Outer(r2)) => Rc::ptr_eq(r1, r2),
+        (Object::Class(r1), Object::Class(r2)) => Rc::ptr_eq(r1, r2),
+        (Object::Instance(r1), Object::Instance(r2)) => Rc::ptr_eq(r1, r2),
+        (Object::Generator(r1), Object::Generator(r2)) => Rc::ptr_eq(r1, r2),
+        (Object::Thread(r1), Object::Thread(r2)) => Rc::ptr_eq(r1, r2),
+        (Object::UserData(r1), Object::UserData(r2)) => Rc::ptr_eq(r1, r2),
+        (Object::UserPointer(p1), Object::UserPointer(p2)) => p1 == p2,
+        (Object::WeakRef(r1), Object::WeakRef(r2)) => Rc::ptr_eq(r1, r2),
+        _ => false,
+    }
+}
+
+fn index_error(key: &Object) -> Error {
+    Error::RuntimeError(format!("the index '{}' does not exist", key))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    // use super::read_closure;
+    use super::*;
+    use crate::io::*;
+
+    #[test]
+    fn load_closure() {
+        let mut bc = &include_bytes!("out.cnut")[..];
+        let closure = read_closure(&mut bc).unwrap();
+
+        closure
             .closure()
             .unwrap()
             .func_proto
@@ -895,9 +2661,9 @@ mod tests {
             exec.instr_profiling = true;
         }
 
-        exec.stack.push(closure);
-        exec.push_roottable();
-        let mut num_args = 1;
+        exec.stack.push(closure).unwrap();
+        exec.push_roottable().unwrap();
+        let num_args = 1;
 
         exec.stack.print_compact("initial");
 
@@ -906,6 +2672,1092 @@ mod tests {
         //let ret = exec.stack.pop();
         println!("{:?}", retval);
         exec.profiling.print();
-        assert_eq!(retval.integer().unwrap(), 4091140000);
+        assert_eq!(retval.integer().unwrap(), 4091140000_i64 as types::Integer);
+    }
+
+    fn run_source(source: &str) -> Object {
+        run(&mut Executor::new(), source)
+    }
+
+    fn run_closure(closure: Object) -> Object {
+        let mut exec = Executor::new();
+        start_closure(&mut exec, closure);
+        exec.execute().unwrap()
+    }
+
+    // compiles source and runs it on exec
+    pub(crate) fn run(exec: &mut Executor, source: &str) -> Object {
+        start(exec, source);
+        exec.execute().unwrap()
+    }
+
+    // compiles source and calls it, leaving it to be executed
+    pub(crate) fn start(exec: &mut Executor, source: &str) {
+        start_closure(
+            exec,
+            crate::compiler::compile_str(source, "test.nut").unwrap(),
+        );
+    }
+
+    pub(crate) fn start_closure(exec: &mut Executor, closure: Object) {
+        exec.stack.push(closure).unwrap();
+        exec.push_roottable().unwrap();
+        exec.call(1, false).unwrap();
+    }
+
+    // the elements of an array as strings
+    pub(crate) fn strings(mut array: Object) -> Vec<String> {
+        let array = array.array().unwrap();
+        array.array.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn outers() {
+        let retval = run_source(
+            "
+            function make_counter() {
+                local n = 0;
+                return function() { n += 1; return n; }
+            }
+            local a = make_counter();
+            local b = make_counter();
+            a(); a(); b();
+            local fns = {};
+            for (local i = 0; i < 3; i += 1) {
+                local j = i * 10;
+                fns[i] <- function() { return j; };
+            }
+            local shared = 1;
+            local get = function() { return shared; };
+            local set = function(v) { shared = v; };
+            set(5);
+            return a() * 1000 + b() * 100 + fns[0]() + fns[1]() + fns[2]() + get() + shared;
+        ",
+        );
+        assert_eq!(retval.integer().unwrap(), 3240);
+    }
+
+    #[test]
+    fn exceptions() {
+        let retval = run_source(
+            "
+            function fail() { return missing; }
+            function thrower(v) { throw v; }
+            local log = \"\";
+            try {
+                fail();
+            } catch (e) {
+                log += \"vm:\" + (typeof e) + \";\";
+            }
+            try {
+                try {
+                    thrower({ code = 42 });
+                } catch (e) {
+                    log += \"inner:\" + (typeof e) + \";\";
+                    throw \"rethrown\";
+                }
+            } catch (e) {
+                log += \"outer:\" + e + \";\";
+            }
+            local f = function() {
+                try { return \"ok\"; } catch (e) { return \"bad\"; }
+            }
+            log += f();
+            return log;
+        ",
+        );
+        assert_eq!(
+            retval.string().unwrap(),
+            "vm:string;inner:table;outer:rethrown;ok"
+        );
+
+        let mut exec = Executor::new();
+        start(&mut exec, "throw 1;");
+        match exec.execute() {
+            Err(Error::Exception(Object::Integer(1))) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(exec.callstack.is_empty());
+    }
+
+    #[test]
+    fn classes() {
+        let retval = run_source(
+            "
+            local Animal = class {
+                name = null;
+                legs = 4;
+                constructor(n) {}
+                function describe() { return \"animal\"; }
+            }
+            local Bird = class extends Animal {
+                constructor(n) { base.constructor(n); }
+                function describe() { return \"bird \" + base.describe(); }
+            };
+            local a = Animal(\"cat\");
+            local b = Bird(\"owl\");
+            local res = a.describe() + \",\" + b.describe();
+            res += \",\" + (b instanceof Animal) + (a instanceof Bird) + typeof b;
+            return res;
+        ",
+        );
+        assert_eq!(
+            retval.string().unwrap(),
+            "animal,bird animal,truefalseinstance"
+        );
+
+        let retval = run_source(
+            "
+            class Foo </ version = 3 /> {
+                </ hidden = 1 />
+                value = 1;
+            }
+            return Foo;
+        ",
+        );
+        let class = retval.class().unwrap();
+        assert_eq!(class.attributes.table().unwrap().map.len(), 1);
+        let attrs = class.get_attributes(&Object::new_string("value")).unwrap();
+        assert!(attrs.table().unwrap().map[&Object::new_string("hidden")] == Object::Integer(1));
+    }
+
+    #[test]
+    fn constructor_arguments() {
+        let retval = run_source(
+            "
+            class P {
+                x = 0;
+                constructor(x) { this.x = x; }
+            }
+            local p = P(3);
+            return p.x;
+        ",
+        );
+        assert!(retval == Object::Integer(3));
+    }
+
+    #[test]
+    fn table_keys() {
+        let res = run_source(
+            "
+            class C {}
+            local t = { a = 1 }, k = {}, arr = [], inst = C();
+            t[1.5] <- \"float\";
+            t[k] <- \"table\";
+            t[arr] <- \"array\";
+            t[inst] <- \"instance\";
+            t[C] <- \"class\";
+            t[1] <- \"integer\";
+            local w = k.weakref();
+            t[w] <- \"weakref\";
+            return [t[1.5], t[k], t[arr], t[inst], t[C], t[1], t[w], t.rawin({}), t.rawin(1.0),
+                t.len()];
+        ",
+        );
+        assert_eq!(
+            strings(res),
+            [
+                "float", "table", "array", "instance", "class", "integer", "weakref", "false",
+                "false", "8"
+            ]
+        );
+        let mut table = object::Table::new();
+        table
+            .map
+            .insert(Object::UserPointer(0x10), Object::Integer(1));
+        assert!(table.map.contains_key(&Object::UserPointer(0x10)));
+        assert!(!table.map.contains_key(&Object::UserPointer(0x20)));
+    }
+
+    #[test]
+    fn generators() {
+        let retval = run_source(
+            "
+            function count(from, to) {
+                for (local i = from; i <= to; i += 1) {
+                    yield i;
+                }
+                return 100;
+            }
+            local res = \"\";
+            foreach (idx, v in count(1, 3)) {
+                res += \"\" + idx + \"=\" + v + \";\";
+            }
+            local g = count(5, 6);
+            res += resume g;
+            res += resume g;
+            res += resume g;
+            try {
+                resume g;
+            } catch (e) {
+                res += \";\" + e;
+            }
+            res += \";\" + typeof g;
+            return res;
+        ",
+        );
+        assert_eq!(
+            retval.string().unwrap(),
+            "0=1;1=2;2=3;56100;resuming dead generator;generator"
+        );
+
+        // returning a call to a generator function is not a tail call
+        let retval = run_source(
+            "
+            function g(n) { yield n; yield n + 1; }
+            function f(n) { return g(n); }
+            local gen = f(1);
+            return (resume gen) + (resume gen) * 10;
+        ",
+        );
+        assert_eq!(retval.integer().unwrap(), 21);
+    }
+
+    #[test]
+    fn threads() {
+        let retval = run_source(
+            "
+            shared <- 0;
+            function behavior(start) {
+                local total = start;
+                while (1) {
+                    local got = suspend(total);
+                    if (got == 0) return \"done\";
+                    total += got;
+                    ::shared <- total;
+                }
+            }
+            function getshared() { return shared; }
+            local t = newthread(behavior);
+            local res = t.getstatus();
+            res += \",\" + t.call(10);
+            res += \",\" + t.getstatus();
+            res += \",\" + t.wakeup(5);
+            res += \",\" + t.wakeup(2);
+            res += \",\" + getshared();
+            res += \",\" + t.wakeup(0);
+            res += \",\" + t.getstatus();
+            try {
+                t.wakeup(1);
+            } catch (e) {
+                res += \",\" + e;
+            }
+            return res;
+        ",
+        );
+        assert_eq!(
+            retval.string().unwrap(),
+            "idle,10,suspended,15,17,17,done,idle,cannot wakeup a idle thread"
+        );
+    }
+
+    #[test]
+    fn thread_outers() {
+        let mut retval = run_source(
+            "
+            local shared = 41;
+            local t = newthread(function() {
+                shared += 1;
+                local got = suspend(shared);
+                shared += got;
+                return shared;
+            });
+            local res = [t.call()];
+            shared += 100;
+            res.append(t.wakeup(1000));
+            res.append(shared);
+            local counter = newthread(function() {
+                local n = 0;
+                suspend(function() { return ++n; });
+                return n;
+            });
+            local next = counter.call();
+            next();
+            res.append(next());
+            res.append(counter.wakeup());
+            return res;
+        ",
+        );
+        let res: Vec<_> = retval
+            .array()
+            .unwrap()
+            .array
+            .iter()
+            .map(|v| v.integer().unwrap())
+            .collect();
+        assert_eq!(res, [42, 1142, 1142, 2, 2]);
+    }
+
+    #[test]
+    fn host_suspend() {
+        let source = "
+            local result = wait_for_event(\"x\");
+            local other = ::suspend(result + 1);
+            return result * 100 + other;
+        ";
+        let mut exec = Executor::new();
+        exec.add_native_func(
+            "wait_for_event",
+            crate::native_closure(
+                Box::new(|ctx| {
+                    let event = ctx.arg(0)?.clone();
+                    ctx.executor().stack().suspend(event);
+                    Ok(Object::Null)
+                }),
+                2,
+            ),
+        )
+        .unwrap();
+        start(&mut exec, source);
+
+        match exec.execute_resumable().unwrap() {
+            ExecutionState::Suspended(Object::String(event)) => assert_eq!(&event[..], "x"),
+            other => panic!("unexpected state {:?}", other),
+        }
+        match exec.resume(Object::Integer(4)).unwrap() {
+            ExecutionState::Suspended(Object::Integer(5)) => (),
+            other => panic!("unexpected state {:?}", other),
+        }
+        match exec.resume(Object::Integer(7)).unwrap() {
+            ExecutionState::Returned(Object::Integer(407)) => (),
+            other => panic!("unexpected state {:?}", other),
+        }
+        assert!(exec.resume(Object::Null).is_err());
+
+        // execute can't suspend and leaves the executor ready for the next call
+        start(&mut exec, "return wait_for_event(\"y\");");
+        assert!(exec.execute().is_err());
+        assert!(exec.callstack.is_empty());
+        assert!(exec.resume(Object::Null).is_err());
+        assert_eq!(run(&mut exec, "return 3;").integer().unwrap(), 3);
+    }
+
+    #[test]
+    fn instruction_budget() {
+        let mut exec = Executor::new();
+        start(
+            &mut exec,
+            "local n = 0; for (local i = 0; i < 1000; i += 1) { n += i; } return n;",
+        );
+        exec.set_instruction_budget(Some(100));
+        let mut interrupts = 0;
+        let retval = loop {
+            match exec.execute_resumable() {
+                Err(Error::BudgetExhausted) => {
+                    interrupts += 1;
+                    exec.set_instruction_budget(Some(100));
+                }
+                Ok(ExecutionState::Returned(retval)) => break retval,
+                other => panic!("unexpected result {:?}", other),
+            }
+        };
+        assert_eq!(retval.integer().unwrap(), 499500);
+        assert!(interrupts > 10);
+
+        exec.set_instruction_budget(None);
+        exec.set_deadline(
+            Some(Instant::now() + std::time::Duration::from_millis(10)),
+            64,
+        );
+        start(&mut exec, "while (1) {}");
+        assert!(matches!(exec.execute(), Err(Error::Terminated(_))));
+        assert!(exec.callstack.is_empty());
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let source = "
+            function recurse(n) { return 1 + recurse(n + 1); }
+            try {
+                recurse(0);
+            } catch (e) {
+                return e;
+            }
+        ";
+        assert_eq!(run_source(source).string().unwrap(), "stack overflow");
+
+        let mut exec = Executor::new();
+        exec.set_stack_limit(4096);
+        start(&mut exec, "function f(n) { return 1 + f(n); } return f(0);");
+        assert!(matches!(exec.execute(), Err(Error::StackOverflow)));
+        assert!(exec.callstack.is_empty());
+        assert!(exec.stack.stack.len() <= 4096);
+
+        let mut exec = Executor::new();
+        exec.set_stack_limit(16);
+        let pushed = (0..32)
+            .take_while(|_| exec.stack.push(Object::Null).is_ok())
+            .count();
+        assert_eq!(pushed, 14);
+        assert!(matches!(exec.push_roottable(), Err(Error::StackOverflow)));
+    }
+
+    #[test]
+    fn deep_calls_with_default_params() {
+        // the defaults and vargv of the callees are written beyond the frame of the deepest f
+        let source = "
+            function g(a, b = 1, c = 2, d = 3, e = 4) { return a + e; }
+            function h(a, ...) { return a + vargv.len(); }
+            function f(n) {
+                if (n == 0) return 0;
+                g(n);
+                h(n);
+                return f(n - 1) + 1;
+            }
+            return f(3000);
+        ";
+        assert_eq!(run_source(source).integer().unwrap(), 3000);
+
+        let source = "
+            function g(a, b = 1, c = 2, d = 3, e = 4) { return a + e; }
+            function f(n) {
+                if (n == 0) return g(0);
+                return f(n - 1) + 1;
+            }
+            return f(3000);
+        ";
+        assert_eq!(run_source(source).integer().unwrap(), 3004);
+
+        let source = "
+            function g(a, b = 1, c = 2, d = 3, e = 4) { yield a + e; }
+            function f(n) {
+                if (n == 0) return resume g(0);
+                return f(n - 1) + 1;
+            }
+            return f(3000);
+        ";
+        assert_eq!(run_source(source).integer().unwrap(), 3004);
+    }
+
+    #[test]
+    fn delegation() {
+        let output = Rc::new(RefCell::new(String::new()));
+        let print_output = output.clone();
+        let mut exec = Executor::new();
+        exec.add_native_func(
+            "print",
+            crate::native_closure(
+                Box::new(move |ctx| {
+                    print_output.borrow_mut().push_str(&ctx.arg(0)?.to_string());
+                    Ok(Object::Null)
+                }),
+                2,
+            ),
+        )
+        .unwrap();
+        run(&mut exec, include_str!("../examples/delegation.nut"));
+        assert_eq!(
+            *output.borrow(),
+            "PLAYER NAMEgodzilla\nENTITY TYPEtable\nx=10 y=20 z=30\nx=123 y=20 z=30\n"
+        );
+
+        // delegates further up the chain are only searched, their _get is not called
+        let retval = run_source(
+            "
+            local outer = { _get = function(key) { return \"outer\"; } };
+            local inner = { name = \"inner\", _get = function(key) { return name + key; } };
+            inner.setdelegate(outer);
+            local obj = { name = \"obj\" }.setdelegate(inner);
+            local res = obj.x;
+            local plain = {}.setdelegate({}.setdelegate(outer));
+            try { plain.y; } catch (e) { res += \";\" + e; }
+            return res;
+        ",
+        );
+        assert_eq!(
+            retval.string().unwrap(),
+            "objx;the index 'y' does not exist"
+        );
+    }
+
+    #[test]
+    fn metamethods() {
+        let source = "
+            local log = \"\";
+            local store = {};
+            local proxy = {}.setdelegate({
+                _get = function(key) { if (store.rawin(key)) return store.rawget(key); throw null; }
+                _newslot = function(key, value) { log += \"new\" + key; store[key] <- value; }
+                _delslot = function(key) { log += \"del\" + key; delete store[key]; }
+                _call = function(original_this, a, b) { return a * b; }
+                _typeof = function() { return \"proxy\"; }
+            });
+            proxy.x <- 2;
+            log += proxy.x;
+            delete proxy.x;
+            local missing;
+            try { proxy.y; } catch (e) { missing = e; }
+            return log + typeof proxy + proxy(6, 7) + missing;
+        ";
+        assert_eq!(
+            run_source(source).string().unwrap(),
+            "newx2delxproxy42the index 'y' does not exist"
+        );
+    }
+
+    #[test]
+    fn operator_metamethods() {
+        let source = "
+            local ops = {};
+            function Num(v) { return { v = v }.setdelegate(ops); }
+            ops._add <- function(o) { return Num(this.v + o.v); }
+            ops._sub <- function(o) { return Num(this.v - o.v); }
+            ops._mul <- function(o) { return Num(this.v * o.v); }
+            ops._div <- function(o) { return Num(this.v / o.v); }
+            ops._modulo <- function(o) { return Num(this.v % o.v); }
+            ops._unm <- function() { return Num(-this.v); }
+            ops._cmp <- function(o) { return this.v - o.v; }
+            local a = Num(7);
+            local b = Num(3);
+            local gt = 0;
+            local lt = 0;
+            if (a > b) gt = 1;
+            if (b < a) lt = 2;
+            local t = {}.setdelegate({ _add = function(o) { return 40 + o; } });
+            return [(a + b).v, (a - b).v, (a * b).v, (a / b).v, (a % b).v, (-a).v, a <=> b, gt, lt,
+                t + 2];
+        ";
+        let mut res = run_source(source);
+        let res: Vec<_> = res
+            .array()
+            .unwrap()
+            .array
+            .iter()
+            .map(|v| v.integer().unwrap())
+            .collect();
+        assert_eq!(res, [10, 4, 21, 2, 1, -7, 4, 1, 2, 42]);
+    }
+
+    #[test]
+    fn numeric_semantics() {
+        let source = "
+            local max = 0x7fffffff;
+            while (max * 2 + 1 > 0) max = max * 2 + 1;
+            local div, mod;
+            try { 1 / 0; } catch (e) { div = e; }
+            try { 1 % 0; } catch (e) { mod = e; }
+            return [1 + 2.5, 7 / 2, 7.0 / 2, 7 % 2.5, max + 1 < 0, 2 < 2.5, 1 == 1.0, 1 != 2,
+                \"a\" == 1, div, mod];
+        ";
+        let res = strings(run_source(source));
+        assert_eq!(
+            res,
+            [
+                "3.5",
+                "3",
+                "3.5",
+                "2",
+                "true",
+                "true",
+                "true",
+                "true",
+                "false",
+                "division by zero",
+                "modulo by zero"
+            ]
+        );
+    }
+
+    #[test]
+    fn get_set() {
+        let source = "
+            local log = \"\";
+            function fail(t) { return t.missing; }
+            try { fail({}); } catch (e) { log += e + \";\"; }
+            try { throw { code = 42 }; } catch (e) { log += e.code + \";\"; }
+            class Animal {
+                name = null;
+                legs = 4;
+                constructor(n) { name = n; }
+                function describe() { return name + \":\" + legs; }
+            }
+            class Bird extends Animal {
+                constructor(n) { base.constructor(n); this.legs = 2; }
+                function describe() { return \"bird \" + base.describe(); }
+            }
+            log += Animal(\"cat\").describe() + \",\" + Bird(\"owl\").describe() + \";\";
+            ::shared <- 0;
+            function setshared(v) { ::shared = v; }
+            setshared(5);
+            log += shared + \";\";
+            local store = {};
+            local proxy = {}.setdelegate({
+                _get = function(key) { if (store.rawin(key)) return store[key]; throw null; }
+                _set = function(key, value) { log += \"set\" + key; store[key] = value; }
+                _newslot = function(key, value) { log += \"new\" + key; store[key] <- value; }
+            });
+            proxy.x <- 2;
+            proxy.x = proxy.x + 1;
+            log += proxy.x + \";\";
+            class Num {
+                v = 0;
+                constructor(v) { this.v = v; }
+                function _add(o) { return Num(v + o.v); }
+            }
+            log += (Num(7) + Num(3)).v;
+            return log;
+        ";
+        assert_eq!(
+            run_source(source).string().unwrap(),
+            "the index 'missing' does not exist;42;cat:4,bird owl:2;5;newxsetx3;10"
+        );
+    }
+
+    #[test]
+    fn opcodes() {
+        macro_rules! opcode {
+            ($name:expr, $opcode:expr, $expected:expr) => {
+                let mut bc = &include_bytes!(concat!("../examples/opcodes/", $name, ".cnut"))[..];
+                let closure = read_closure(&mut bc).unwrap();
+                assert!(
+                    closure
+                        .closure()
+                        .unwrap()
+                        .func_proto
+                        .func_proto()
+                        .unwrap()
+                        .instructions
+                        .iter()
+                        .any(|instr| instr.opcode == $opcode as u8),
+                    "{} does not use {:?}",
+                    $name,
+                    $opcode
+                );
+                assert_eq!(run_closure(closure).to_string(), $expected, "{}", $name);
+            };
+        }
+        opcode!("set", Opcode::SET, "2");
+        opcode!("get", Opcode::GET, "3");
+        opcode!("delete", Opcode::DELETE, "11");
+        opcode!("ne", Opcode::NE, "true");
+        opcode!("cmp", Opcode::CMP, "true");
+        opcode!("exists", Opcode::EXISTS, "truefalse");
+        opcode!("and", Opcode::AND, "07");
+        opcode!("or", Opcode::OR, "55");
+        opcode!("neg", Opcode::NEG, "-5");
+        opcode!("not", Opcode::NOT, "truefalse");
+        opcode!("bwnot", Opcode::BWNOT, "-1");
+        opcode!("bitw", Opcode::BITW, "255 1023 510 1022 -4 true");
+        opcode!("loadbool", Opcode::LOADBOOL, "truefalse");
+        opcode!("loadfloat", Opcode::LOADFLOAT, "1.5");
+    }
+
+    #[test]
+    fn compound_assignment() {
+        let source = "
+            local t = {count = 0};
+            t.count += 5;
+            t.count -= 1;
+            local a = [1, 2];
+            a[1] *= 10;
+            local pre = ++t.count;
+            local post = t.count++;
+            local i = 0;
+            local j = i++;
+            ++i;
+            class C { n = 1; }
+            local c = C();
+            c.n += 2;
+            c.n++;
+            local w = {x = {}.setdelegate({ _add = function(o) { return \"added\" + o; } })};
+            w.x += 1;
+            local f = 1.5;
+            f++;
+            return [t.count, a[1], pre, post, j, i, c.n, w.x, f];
+        ";
+        let res = strings(run_source(source));
+        assert_eq!(res, ["6", "20", "5", "5", "0", "2", "4", "added1", "2.5"]);
+    }
+
+    #[test]
+    fn foreach() {
+        let source = "
+            local sum = 0;
+            foreach (k, v in {a = 1, b = 2, c = 3}) {
+                sum += v;
+                if (k == \"b\") sum += 100;
+            }
+            local chars = 0;
+            foreach (i, c in \"ab\") chars = chars * 1000 + c + i;
+            class C {
+                x = 1;
+                function f() {}
+                function _nexti(prev) {
+                    if (prev == null) return \"x\";
+                    return null;
+                }
+            }
+            local members = 0;
+            foreach (k, v in C) members += 1;
+            local fields = \"\";
+            foreach (k, v in C()) fields += k + v;
+            local t = {c = 1, a = 2, b = 3};
+            local order = \"\";
+            foreach (k, v in t) {
+                order += k;
+                if (k == \"a\") t.d <- 4;
+            }
+            local u = {a = 1, b = 2, c = 3};
+            local visited = \"\";
+            foreach (k, v in u) {
+                visited += k;
+                if (k == \"a\") delete u.a;
+            }
+            local all = {a = 1, b = 2, c = 3, d = 4, e = 5};
+            local deleted = 0;
+            foreach (k, v in all) {
+                deleted += 1;
+                delete all[k];
+            }
+            return [sum, chars, members, fields, order, visited, deleted, all.len()];
+        ";
+        let res = strings(run_source(source));
+        assert_eq!(res, ["106", "97099", "3", "x1", "cabd", "abc", "5", "0"]);
+
+        // the holes of deleted slots are dropped once they outnumber the slots
+        let mut map = object::TableMap::new();
+        for i in 0..4 {
+            map.insert(Object::Integer(i), Object::Integer(i * 10));
+        }
+        for i in 0..3 {
+            map.remove_entry(&Object::Integer(i));
+        }
+        assert_eq!(map.next_slot(0).unwrap().0, 4);
+        map.insert(Object::Integer(4), Object::Integer(40));
+        assert_eq!(map.next_slot(0).unwrap().0, 1);
+        let keys: Vec<_> = map.iter().map(|(key, _)| key.to_string()).collect();
+        assert_eq!(keys, ["3", "4"]);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn default_params_and_varargs() {
+        let source = "
+            function add(a, b = 10, c = 100) { return a + b + c; }
+            function count(first, ...) {
+                local sum = first;
+                foreach (v in vargv) sum += v;
+                return sum * 100 + vargv.len();
+            }
+            function tail(x) { return add(x); }
+            local gen = function(a, b = 5) { yield a + b; };
+            local err = null;
+            try { add(); } catch (e) { err = e; }
+            return [add(1), add(1, 2), add(1, 2, 3), count(1), count(1, 2, 3), tail(7), resume gen(1), err];
+        ";
+        let res = strings(run_source(source));
+        assert_eq!(
+            res,
+            [
+                "111",
+                "103",
+                "6",
+                "100",
+                "602",
+                "117",
+                "6",
+                "wrong number of parameters"
+            ]
+        );
+    }
+
+    #[test]
+    fn native_functions() {
+        let source = "
+            local t = {scale = 3, mul = mul};
+            local errors = [];
+            try { mul(\"x\", 1); } catch (e) { errors.append(e); }
+            try { mul(1); } catch (e) { errors.append(e); }
+            try { fail(); } catch (e) { errors.append(e); }
+            return [mul(2, 1.5), t.mul(2, 2), errors[0], errors[1], errors[2]];
+        ";
+        let mut exec = Executor::new();
+        exec.add_native_func(
+            "mul",
+            crate::native_closure(
+                Box::new(|ctx| {
+                    // called as a global function `this` is the root table
+                    let scale = match ctx.this() {
+                        Object::Table(t) => t
+                            .borrow()
+                            .map
+                            .get(&Object::new_string("scale"))
+                            .cloned()
+                            .unwrap_or(Object::Integer(1)),
+                        _ => Object::Integer(1),
+                    };
+                    let product = ctx.integer_arg(0)? as types::Float
+                        * ctx.float_arg(1)?
+                        * scale.integer()? as types::Float;
+                    Ok(Object::Float(product))
+                }),
+                3,
+            ),
+        )
+        .unwrap();
+        exec.add_native_func(
+            "fail",
+            crate::native_closure(
+                Box::new(|_| Err(Error::RuntimeError("native failure".to_string()))),
+                -1,
+            ),
+        )
+        .unwrap();
+        let res = strings(run(&mut exec, source));
+        assert_eq!(
+            res,
+            [
+                "3",
+                "12",
+                "parameter 1 has an invalid type 'string' ; expected: 'integer'",
+                "wrong number of parameters",
+                "native failure"
+            ]
+        );
+    }
+
+    #[test]
+    fn userdata() {
+        struct Counter {
+            count: types::Integer,
+        }
+        const COUNTER_TAG: usize = 1;
+
+        let source = "
+            counter.increment();
+            counter.increment();
+            counter.count += 10;
+            local err = null;
+            try { counter.missing; } catch (e) { err = e; }
+            return [counter.count, typeof counter, typeof handle, handle == get_handle(), err];
+        ";
+        let mut delegate = object::Table::new();
+        delegate.map.insert(
+            Object::new_string("increment"),
+            crate::native_closure(
+                Box::new(|ctx| {
+                    let mut userdata = ctx.this().userdata_mut()?;
+                    userdata.check_type_tag(COUNTER_TAG)?;
+                    userdata.get_mut::<Counter>()?.count += 1;
+                    Ok(Object::Null)
+                }),
+                1,
+            ),
+        );
+        delegate.map.insert(
+            Object::new_string("_get"),
+            crate::native_closure(
+                Box::new(|ctx| match ctx.string_arg(0)? {
+                    "count" => Ok(Object::Integer(
+                        ctx.this().userdata()?.get::<Counter>()?.count,
+                    )),
+                    _ => Err(Error::Exception(Object::Null)),
+                }),
+                2,
+            ),
+        );
+        delegate.map.insert(
+            Object::new_string("_set"),
+            crate::native_closure(
+                Box::new(|ctx| {
+                    let count = ctx.integer_arg(1)?;
+                    ctx.this().userdata_mut()?.get_mut::<Counter>()?.count = count;
+                    Ok(Object::Null)
+                }),
+                3,
+            ),
+        );
+        let released = Rc::new(RefCell::new(None));
+        let release_result = released.clone();
+        let mut counter = object::UserData::new(Counter { count: 0 });
+        counter.delegate = Some(Object::Table(Rc::new(RefCell::new(delegate))));
+        counter.type_tag = COUNTER_TAG;
+        counter.release_hook = Some(Box::new(move |value| {
+            *release_result.borrow_mut() = value.downcast_ref::<Counter>().map(|c| c.count);
+        }));
+
+        let mut exec = Executor::new();
+        exec.add_native_func("counter", Object::new_userdata(counter))
+            .unwrap();
+        exec.add_native_func("handle", Object::UserPointer(0x1234))
+            .unwrap();
+        exec.add_native_func("get_handle", || Ok(Object::UserPointer(0x1234)))
+            .unwrap();
+        let res = strings(run(&mut exec, source));
+        assert_eq!(
+            res,
+            [
+                "12",
+                "userdata",
+                "userpointer",
+                "true",
+                "the index 'missing' does not exist"
+            ]
+        );
+        assert_eq!(*released.borrow(), None);
+        drop(exec);
+        assert_eq!(*released.borrow(), Some(12));
+    }
+
+    #[test]
+    fn type_delegates() {
+        const POINT_TAG: usize = 2;
+        let source = "
+            local err = null;
+            try { other.name(); } catch (e) { err = e; }
+            return [p.name(), q.name(), err];
+        ";
+        let mut delegate = object::Table::new();
+        delegate.map.insert(
+            Object::new_string("name"),
+            crate::native_closure(Box::new(|_| Ok(Object::new_string("point"))), 1),
+        );
+        let mut exec = Executor::new();
+        exec.set_type_delegate(POINT_TAG, Object::Table(Rc::new(RefCell::new(delegate))))
+            .unwrap();
+        assert!(exec.set_type_delegate(0, Object::new_table()).is_err());
+        assert!(exec.set_type_delegate(3, Object::Null).is_err());
+        let point = |tag| {
+            let mut userdata = object::UserData::new(());
+            userdata.type_tag = tag;
+            userdata
+        };
+        let p = exec.new_userdata(point(POINT_TAG));
+        let q = exec.new_userdata(point(POINT_TAG));
+        let other = exec.new_userdata(point(3));
+        // the registry belongs to the executor
+        assert!(Executor::new()
+            .new_userdata(point(POINT_TAG))
+            .userdata()
+            .unwrap()
+            .delegate
+            .is_none());
+        exec.add_native_func("p", p).unwrap();
+        exec.add_native_func("q", q).unwrap();
+        exec.add_native_func("other", other).unwrap();
+        let res = strings(run(&mut exec, source));
+        assert_eq!(res, ["point", "point", "the index 'name' does not exist"]);
+    }
+
+    #[test]
+    fn weak_references() {
+        let source = "
+            local listeners = [];
+            local counter = {n = 0};
+            function listen(listener) { listeners.append(listener.weakref()); }
+            function notify() {
+                foreach (listener in listeners) {
+                    if (listener) listener();
+                }
+            }
+            local first = function() { counter.n += 1; };
+            local second = function() { counter.n += 10; };
+            listen(first);
+            listen(second);
+            notify();
+            second = null;
+            notify();
+            local t = {};
+            local w = t.weakref();
+            local holder = {w = w};
+            local results = [counter.n, w.ref() == t, holder.w == t, typeof w];
+            t = null;
+            results.append(w.ref());
+            results.append(holder.w);
+            return results;
+        ";
+        let res = strings(run_source(source));
+        assert_eq!(res, ["12", "true", "true", "weakref", "null", "null"]);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let source = "
+            local filled = 0, error = null;
+            function fill() {
+                local big = [];
+                try {
+                    while (true) big.append(\"item\" + big.len());
+                } catch (e) {
+                    filled = big.len();
+                    return e;
+                }
+            }
+            local error1 = fill();
+            local s = \"x\";
+            try {
+                while (true) s += s;
+            } catch (e) { error = e; }
+            s = null;
+            local t = {};
+            for (local i = 0; i < 100; i++) t[i] <- i;
+            return [error1, error, filled > 100, t.len()];
+        ";
+        let mut exec = Executor::new();
+        exec.set_memory_limit(Some(64 * 1024));
+        assert_eq!(
+            strings(run(&mut exec, source)),
+            ["out of memory", "out of memory", "true", "100"]
+        );
+        let stats = exec.memory_stats();
+        assert_eq!(stats.limit, Some(64 * 1024));
+        assert!(stats.peak <= 64 * 1024);
+        assert!(stats.allocated > 0 && stats.allocated < stats.peak / 2);
+        exec.stack.clear_unused();
+        assert!(exec.memory_stats().allocated < stats.allocated);
+    }
+
+    #[test]
+    fn memory_limit_objects() {
+        let run_path = |setup: &str, step: &str| {
+            let source = format!(
+                "{} try {{ for (local i = 0; i < 100000; i++) {{ {} }} }} catch (e) {{ return e; }}",
+                setup, step
+            );
+            let mut exec = Executor::new();
+            exec.set_memory_limit(Some(64 * 1024));
+            let allocated = exec.memory_stats().allocated;
+            exec.add_native_func("chain", |prev: Object| {
+                Ok(vec![prev, Object::new_string(&"x".repeat(100))])
+            })
+            .unwrap();
+            // the name and the root table slot are charged
+            let slot = memory::slot_size(&Object::Null, &Object::Null);
+            let name = memory::object_size(&Object::new_string("chain"));
+            assert!(exec.memory_stats().allocated >= allocated + slot + name);
+            let res = run(&mut exec, &source).to_string();
+            assert!(exec.memory_stats().peak <= 64 * 1024);
+            res
+        };
+        // every path keeps what it allocates reachable without growing a table or array
+        let paths = vec![
+            // strings held by locals, each of them below the limit
+            (
+                "local a = \"x\"; for (local j = 0; j < 13; j++) a += a;",
+                "local b = a + a, c = a + a, d = a + a, e = a + a; break;",
+            ),
+            // a chain of instances
+            (
+                "local Node = class { next = null; }, head = null;",
+                "local node = Node(); node.next = head; head = node;",
+            ),
+            // a chain of classes
+            ("local c = class {};", "c = class extends c { x = 1; };"),
+            // a chain of generators holding each other
+            (
+                "function gen(prev) { yield prev; } local g = null;",
+                "g = gen(g);",
+            ),
+            // a chain of vargv arrays
+            (
+                "function f(...) { return vargv; } local args = null;",
+                "args = f(args, 1, 2, 3);",
+            ),
+            // a chain of arrays built by a native function
+            ("local values = null;", "values = chain(values);"),
+        ];
+        for (setup, step) in paths {
+            assert_eq!(run_path(setup, step), "out of memory", "{}", step);
+        }
     }
 }